@@ -8,6 +8,8 @@ layout(location = 0) out vec4 out_fragment_color;
 
 layout(set = 1, binding = 0) uniform texture3D perlin3d_texture;
 layout(set = 1, binding = 1) uniform sampler perlin3d_sampler;
+layout(set = 1, binding = 3) uniform texture3D flow3d_texture;
+layout(set = 1, binding = 4) uniform sampler flow3d_sampler;
 
 layout(set = 1, binding = 2) uniform SkyMaterial {
     float step_size;
@@ -18,12 +20,26 @@ layout(set = 1, binding = 2) uniform SkyMaterial {
     float noise_thresh;
     uint step_count;
     vec3 camera_pos;
+    float time;
+    float flow_strength;
+    float flow_speed;
 };
 
+// Density texture dragged along the curl noise flow. Two copies, half a cycle apart, are each advected
+// for one cycle and then reset, and the blend hides each reset while the other copy is fully weighted.
+float advected_noise(vec3 pos) {
+    vec3 flow = texture(sampler3D(flow3d_texture, flow3d_sampler), pos).xyz * flow_strength;
+    float phase0 = fract(time * flow_speed);
+    float phase1 = fract(time * flow_speed + 0.5);
+    float noise0 = texture(sampler3D(perlin3d_texture, perlin3d_sampler), pos - flow * phase0).r;
+    float noise1 = texture(sampler3D(perlin3d_texture, perlin3d_sampler), pos - flow * phase1).r;
+    return mix(noise0, noise1, abs(1.0 - 2.0 * phase0));
+}
+
 // Args and return are in range 0 to 1
 float density(vec3 pos) {
     pos = pos * noise_size;
-    float noise = texture(sampler3D(perlin3d_texture, perlin3d_sampler), pos).r;
+    float noise = advected_noise(pos);
     // float a = texture(sampler3D(perlin3d_texture, perlin3d_sampler), pos / 2.0).r * 1.0;
     // float b = texture(sampler3D(perlin3d_texture, perlin3d_sampler), pos / 16.0).r * 4.0;
     // float c = texture(sampler3D(perlin3d_texture, perlin3d_sampler), pos / 64.0).r * 16.0;
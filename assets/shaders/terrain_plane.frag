@@ -2,15 +2,18 @@
 
 layout(location = 1) in vec3 fragment_normal;
 layout(location = 2) in vec3 fragment_position_world;
+layout(location = 3) in vec4 fragment_biome_weights_0;
+layout(location = 4) in vec4 fragment_biome_weights_1;
 
 layout(location = 0) out vec4 out_fragment_color;
 
+// One color of each kind per biome
 layout(set = 1, binding = 0) uniform TerrainPlaneColoring {
-    vec4 peak_color;
-    vec4 flat_color;
-    vec4 steep_color;
-    vec4 cliff_color;
-    vec4 sea_color;
+    vec4 peak_colors[8];
+    vec4 flat_colors[8];
+    vec4 steep_colors[8];
+    vec4 cliff_colors[8];
+    vec4 sea_colors[8];
     float peak_thresh;
     float cliff_thresh;
     float steep_thresh;
@@ -28,6 +31,24 @@ layout(set = 1, binding = 1) uniform TerrainPlaneLighting {
 };
 
 void main() {
+    // Palette of the biomes blended here
+    float weights[8] = float[8](
+        fragment_biome_weights_0.x, fragment_biome_weights_0.y, fragment_biome_weights_0.z, fragment_biome_weights_0.w,
+        fragment_biome_weights_1.x, fragment_biome_weights_1.y, fragment_biome_weights_1.z, fragment_biome_weights_1.w
+    );
+    vec4 peak_color = vec4(0.0);
+    vec4 flat_color = vec4(0.0);
+    vec4 steep_color = vec4(0.0);
+    vec4 cliff_color = vec4(0.0);
+    vec4 sea_color = vec4(0.0);
+    for (int i = 0; i < 8; i++) {
+        peak_color += peak_colors[i] * weights[i];
+        flat_color += flat_colors[i] * weights[i];
+        steep_color += steep_colors[i] * weights[i];
+        cliff_color += cliff_colors[i] * weights[i];
+        sea_color += sea_colors[i] * weights[i];
+    }
+
     // Coloring
     vec4 color = peak_color;
     if (fragment_position_world.y < sea_thresh) {
@@ -2,9 +2,13 @@
 
 layout(location = 0) in vec3 vertex_position;
 layout(location = 1) in vec3 vertex_normal;
+layout(location = 2) in vec4 vertex_biome_weights_0;
+layout(location = 3) in vec4 vertex_biome_weights_1;
 
 layout(location = 1) out vec3 out_vertex_normal;
 layout(location = 2) out vec3 out_vertex_position_world;
+layout(location = 3) out vec4 out_vertex_biome_weights_0;
+layout(location = 4) out vec4 out_vertex_biome_weights_1;
 
 layout(set = 0, binding = 0) uniform CameraViewProj {
     mat4 ViewProj;
@@ -27,4 +31,6 @@ void main() {
     gl_Position = mvp * vec4(vertex_position, 1.0);
     out_vertex_normal = vertex_normal;
     out_vertex_position_world = (Model * vec4(vertex_position, 1.0)).xyz;
+    out_vertex_biome_weights_0 = vertex_biome_weights_0;
+    out_vertex_biome_weights_1 = vertex_biome_weights_1;
 }
@@ -0,0 +1,90 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput, BenchmarkId, black_box};
+use bevy::math::{Vec2, Vec3};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::Deserialize;
+
+use astral::clouds::{self, CLOUD_DETAIL};
+use astral::noise::{NoiseSource2D, NoiseSource3D, NoiseKind, Gradient2D, Gradient3D};
+use astral::terrain_recipe::TerrainRecipe;
+
+// Samples per row and rows per grid, matching a terrain plane's side
+const WIDTH: usize = 1001;
+// Side of the cloud volume
+const VOLUME: usize = 64;
+
+fn rng() -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(0)
+}
+
+// The part of the game's world recipe benchmarked here, the rest is skipped
+#[derive(Deserialize)]
+struct WorldRecipe {
+    terrain: TerrainRecipe
+}
+
+// Terrain heightmap of the default world, erosion and all
+fn default_terrain(kind: NoiseKind) -> impl NoiseSource2D {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/worlds/default.terrain.ron");
+    let recipe: WorldRecipe = ron::de::from_bytes(&std::fs::read(path).expect("Couldn't read the default world")).expect("Invalid default world");
+    recipe.terrain.build_2d(kind, &mut rng()).expect("Couldn't build the default terrain")
+}
+
+fn sources_2d(c: &mut Criterion) {
+    let mut group = c.benchmark_group("2d");
+    group.throughput(Throughput::Elements((WIDTH * WIDTH) as u64));
+    let mut out = vec![0.; WIDTH * WIDTH];
+    for kind in [NoiseKind::Perlin, NoiseKind::OpenSimplex2] {
+        let noise = Gradient2D::new(kind, &mut rng());
+        group.bench_function(BenchmarkId::new("point", kind), |b| b.iter(|| {
+            for (i, value) in out.iter_mut().enumerate() {
+                *value = noise.sample_2d((i % WIDTH) as f32 * 0.37, (i / WIDTH) as f32 * 0.37);
+            }
+            black_box(&out);
+        }));
+        group.bench_function(BenchmarkId::new("grid", kind), |b| b.iter(|| {
+            noise.sample_grid_2d(Vec2::ZERO, Vec2::splat(0.37), WIDTH, &mut out);
+            black_box(&out);
+        }));
+
+        let terrain = default_terrain(kind);
+        group.bench_function(BenchmarkId::new("terrain_grid", kind), |b| b.iter(|| {
+            terrain.sample_grid_2d(Vec2::splat(-500.), Vec2::ONE, WIDTH, &mut out);
+            black_box(&out);
+        }));
+    }
+    group.finish();
+}
+
+fn sources_3d(c: &mut Criterion) {
+    let mut group = c.benchmark_group("3d");
+    group.throughput(Throughput::Elements((VOLUME * VOLUME * VOLUME) as u64));
+    let mut out = vec![0.; VOLUME * VOLUME * VOLUME];
+    let step = Vec3::splat(2.);
+    for kind in [NoiseKind::Perlin, NoiseKind::OpenSimplex2] {
+        let noise = Gradient3D::new(kind, &mut rng());
+        group.bench_function(BenchmarkId::new("point", kind), |b| b.iter(|| {
+            for (i, value) in out.iter_mut().enumerate() {
+                let (x, y, z) = (i % VOLUME, i / VOLUME % VOLUME, i / (VOLUME * VOLUME));
+                *value = noise.sample_3d(x as f32 * 2., y as f32 * 2., z as f32 * 2.);
+            }
+            black_box(&out);
+        }));
+        group.bench_function(BenchmarkId::new("grid", kind), |b| b.iter(|| {
+            noise.sample_grid_3d(Vec3::ZERO, step, VOLUME, VOLUME, &mut out);
+            black_box(&out);
+        }));
+
+        // The cloud density the sky's texture is baked from, which only works with noise that tiles
+        if let Ok(clouds) = clouds::density(&mut rng(), kind) {
+            group.bench_function(BenchmarkId::new("clouds_grid", kind), |b| b.iter(|| {
+                clouds.sample_grid_3d(Vec3::ZERO, Vec3::splat(CLOUD_DETAIL), VOLUME, VOLUME, &mut out);
+                black_box(&out);
+            }));
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sources_2d, sources_3d);
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::{prelude::*, tasks::AsyncComputeTaskPool, render::primitives::Aabb};
+
+#[derive(Default)]
+pub struct BackgroundPlugin {}
+
+impl Plugin for BackgroundPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, finish_pending_meshes);
+    }
+}
+
+/// Work running on the `AsyncComputeTaskPool`, checked on from systems until it's done. On the web the
+/// pool runs tasks on the browser's event loop between frames and hands back no `Task`, so the result is
+/// passed back through a shared slot on every platform.
+pub struct Background<T> {
+    result: Arc<Mutex<Option<T>>>
+}
+
+impl<T: Send + 'static> Background<T> {
+    pub fn spawn(work: impl FnOnce() -> T + Send + 'static) -> Background<T> {
+        let result = Arc::new(Mutex::new(None));
+        let slot = result.clone();
+        AsyncComputeTaskPool::get().spawn(async move {
+            *slot.lock().unwrap() = Some(work());
+        }).detach();
+        Background { result }
+    }
+
+    /// Takes the result if the work has finished
+    pub fn poll(&self) -> Option<T> {
+        self.result.lock().unwrap().take()
+    }
+}
+
+// Calls `f` with the index and contents of each `chunk_len` long chunk of `data`, spread over the async
+// compute threads. The web has a single thread, so there the chunks run one after another.
+pub fn par_chunks_mut<T: Send>(data: &mut [T], chunk_len: usize, f: impl Fn(usize, &mut [T]) + Send + Sync) {
+    let f = &f;
+    AsyncComputeTaskPool::get().scope(|scope| {
+        for (i, chunk) in data.chunks_mut(chunk_len.max(1)).enumerate() {
+            scope.spawn(async move { f(i, chunk) });
+        }
+    });
+}
+
+/// Mesh being generated for this entity, which is drawn with a placeholder mesh until it's ready
+#[derive(Component)]
+pub struct PendingMesh(pub Background<Mesh>);
+
+fn finish_pending_meshes(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, pending: Query<(Entity, &PendingMesh)>) {
+    for (entity, PendingMesh(mesh)) in pending.iter() {
+        if let Some(mesh) = mesh.poll() {
+            // Dropping the bounds makes Bevy compute them again from the new mesh
+            commands.entity(entity).insert(meshes.add(mesh)).remove::<(PendingMesh, Aabb)>();
+        }
+    }
+}
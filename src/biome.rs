@@ -0,0 +1,259 @@
+// Climate worked out from temperature and moisture fields, and the Whittaker-style biomes it gives
+
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+use crate::noise::{BoxedNoise2D, NoiseKind, NoiseNode, NoiseSource2D};
+
+pub const BIOME_COUNT: usize = 8;
+
+// Temperature range, in °C, that counts as far apart as the whole moisture range when classifying
+const TEMPERATURE_SPAN: f32 = 40.;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Biome {
+    Tundra,
+    BorealForest,
+    // Steppe and cold desert
+    Grassland,
+    TemperateForest,
+    TemperateRainforest,
+    Desert,
+    Savanna,
+    TropicalRainforest
+}
+
+// How much each biome applies, indexed like `Biome::ALL` and adding up to 1
+pub type BiomeWeights = [f32; BIOME_COUNT];
+
+impl Biome {
+    pub const ALL: [Biome; BIOME_COUNT] = [
+        Biome::Tundra, Biome::BorealForest, Biome::Grassland, Biome::TemperateForest,
+        Biome::TemperateRainforest, Biome::Desert, Biome::Savanna, Biome::TropicalRainforest
+    ];
+
+    // The biome's place on the Whittaker diagram
+    pub fn climate(self) -> Climate {
+        let (temperature, moisture) = match self {
+            Biome::Tundra => (-10., 0.4),
+            Biome::BorealForest => (0., 0.7),
+            Biome::Grassland => (10., 0.15),
+            Biome::TemperateForest => (10., 0.55),
+            Biome::TemperateRainforest => (12., 0.9),
+            Biome::Desert => (25., 0.1),
+            Biome::Savanna => (25., 0.45),
+            Biome::TropicalRainforest => (25., 0.85)
+        };
+        Climate { temperature, moisture }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Biome::Tundra => "tundra",
+            Biome::BorealForest => "boreal forest",
+            Biome::Grassland => "grassland",
+            Biome::TemperateForest => "temperate forest",
+            Biome::TemperateRainforest => "temperate rainforest",
+            Biome::Desert => "desert",
+            Biome::Savanna => "savanna",
+            Biome::TropicalRainforest => "tropical rainforest"
+        }
+    }
+}
+
+// Mean temperature in °C, and moisture from 0 (arid) to 1 (wet)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Climate {
+    pub temperature: f32,
+    pub moisture: f32
+}
+
+impl Climate {
+    // Biome whose climate is closest
+    pub fn biome(&self) -> Biome {
+        let weights = self.biome_weights(1.);
+        let nearest = (0..BIOME_COUNT).max_by(|&a, &b| weights[a].total_cmp(&weights[b])).unwrap();
+        Biome::ALL[nearest]
+    }
+
+    // Every biome weighted by how close its climate is, so biomes fade into each other over about `blend`
+    // of the diagram instead of meeting at a hard edge. Far from any border the closest biome has all the
+    // weight.
+    pub fn biome_weights(&self, blend: f32) -> BiomeWeights {
+        let distances = Biome::ALL.map(|biome| {
+            let center = biome.climate();
+            let dt = (self.temperature - center.temperature) / TEMPERATURE_SPAN;
+            let dm = self.moisture - center.moisture;
+            dt * dt + dm * dm
+        });
+        // Counted from the closest one, which can't underflow
+        let nearest = distances.iter().copied().fold(f32::MAX, f32::min);
+        let blend = blend.max(f32::EPSILON);
+        let weights = distances.map(|distance| (-(distance - nearest) / (blend * blend)).exp());
+        let total: f32 = weights.iter().sum();
+        weights.map(|weight| weight / total)
+    }
+}
+
+// How the climate fields are turned into a climate
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClimateSettings {
+    // Added to the temperature field, at sea level on the equator
+    pub mean_temperature: f32,
+    // Added to the moisture field before it's clamped to 0 to 1
+    pub mean_moisture: f32,
+    // Cooling per unit of height above zero, °C
+    pub lapse_rate: f32,
+    // Lines of constant latitude run along x. The equator is at z = `equator`, and the poles `pole_distance`
+    // away from it on either side, where it's `polar_cooling` colder.
+    pub equator: f32,
+    pub pole_distance: f32,
+    pub polar_cooling: f32,
+    // How far across the Whittaker diagram biomes blend, see `Climate::biome_weights`
+    pub blend: f32
+}
+
+impl Default for ClimateSettings {
+    fn default() -> ClimateSettings {
+        ClimateSettings {
+            mean_temperature: 16.,
+            mean_moisture: 0.5,
+            lapse_rate: 0.25,
+            equator: 0.,
+            pole_distance: 16384.,
+            polar_cooling: 40.,
+            blend: 0.1
+        }
+    }
+}
+
+// Temperature (°C) and moisture noise, for `ClimateMap`. Either can be left out for no variation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClimateRecipe {
+    #[serde(default = "no_variation")]
+    pub temperature: NoiseNode,
+    #[serde(default = "no_variation")]
+    pub moisture: NoiseNode,
+    #[serde(default)]
+    pub settings: ClimateSettings
+}
+
+fn no_variation() -> NoiseNode {
+    NoiseNode::Constant(0.)
+}
+
+impl Default for ClimateRecipe {
+    fn default() -> ClimateRecipe {
+        ClimateRecipe { temperature: no_variation(), moisture: no_variation(), settings: ClimateSettings::default() }
+    }
+}
+
+impl ClimateRecipe {
+    pub fn build<R: Rng>(&self, default_kind: NoiseKind, rng: &mut R) -> Result<ClimateMap, String> {
+        Ok(ClimateMap {
+            temperature: self.temperature.build_2d(default_kind, rng)?,
+            moisture: self.moisture.build_2d(default_kind, rng)?,
+            settings: self.settings
+        })
+    }
+}
+
+// Climate anywhere in the world, which cools towards the poles and with height
+pub struct ClimateMap {
+    pub temperature: BoxedNoise2D,
+    pub moisture: BoxedNoise2D,
+    pub settings: ClimateSettings
+}
+
+impl ClimateMap {
+    pub fn climate_at(&self, x: f32, z: f32, height: f32) -> Climate {
+        let settings = &self.settings;
+        let latitude = ((z - settings.equator).abs() / settings.pole_distance).min(1.);
+        let temperature = settings.mean_temperature + self.temperature.sample_2d(x, z)
+            - settings.polar_cooling * latitude
+            - settings.lapse_rate * height.max(0.);
+        let moisture = (settings.mean_moisture + self.moisture.sample_2d(x, z)).clamp(0., 1.);
+        Climate { temperature, moisture }
+    }
+
+    pub fn biome_weights_at(&self, x: f32, z: f32, height: f32) -> BiomeWeights {
+        self.climate_at(x, z, height).biome_weights(self.settings.blend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::Constant;
+
+    fn index(biome: Biome) -> usize {
+        Biome::ALL.iter().position(|&other| other == biome).unwrap()
+    }
+
+    fn assert_normalized(weights: &BiomeWeights) {
+        assert!(weights.iter().all(|weight| weight.is_finite() && *weight >= 0.), "{:?}", weights);
+        assert!((weights.iter().sum::<f32>() - 1.).abs() < 1e-5, "{:?}", weights);
+    }
+
+    #[test]
+    fn biome_centers_classify_as_themselves() {
+        for biome in Biome::ALL {
+            assert_eq!(biome.climate().biome(), biome);
+        }
+    }
+
+    #[test]
+    fn weights_add_up_to_one() {
+        for temperature in (-30..=40).step_by(5) {
+            for moisture in 0..=10 {
+                let climate = Climate { temperature: temperature as f32, moisture: moisture as f32 / 10. };
+                for blend in [0.02, 0.1, 0.5, 5.] {
+                    assert_normalized(&climate.biome_weights(blend));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn weights_are_one_hot_far_from_borders() {
+        for (i, biome) in Biome::ALL.into_iter().enumerate() {
+            let weights = biome.climate().biome_weights(0.05);
+            assert!(weights[i] > 1. - 1e-6, "{:?}: {:?}", biome, weights);
+        }
+        // Halfway between two biomes they share the weight
+        let weights = Climate { temperature: 25., moisture: 0.275 }.biome_weights(0.05);
+        assert!((weights[index(Biome::Desert)] - 0.5).abs() < 1e-3 && (weights[index(Biome::Savanna)] - 0.5).abs() < 1e-3, "{:?}", weights);
+    }
+
+    #[test]
+    fn tiny_blends_stay_finite() {
+        // Including exactly on a border, where two biomes tie
+        for climate in [Biome::Desert.climate(), Climate { temperature: 25., moisture: 0.275 }, Climate { temperature: 100., moisture: -3. }] {
+            for blend in [0., 1e-30, f32::MIN_POSITIVE, f32::EPSILON, 1e-6] {
+                assert_normalized(&climate.biome_weights(blend));
+            }
+        }
+        assert_eq!(Biome::Savanna.climate().biome_weights(0.)[index(Biome::Savanna)], 1.);
+    }
+
+    #[test]
+    fn height_and_latitude_cool_the_climate() {
+        let settings = ClimateSettings { mean_moisture: 0.8, ..ClimateSettings::default() };
+        let map = ClimateMap { temperature: Box::new(Constant(2.)), moisture: Box::new(Constant(0.5)), settings };
+        let temperature = |z: f32, height: f32| map.climate_at(3., z, height).temperature;
+        // Sea level on the equator
+        assert_eq!(temperature(0., 0.), 18.);
+        // lapse_rate per unit of height, and nothing extra below sea level
+        assert_eq!(temperature(0., 100.), 18. - 25.);
+        assert_eq!(temperature(0., -50.), 18.);
+        // polar_cooling at the poles, in proportion on the way there, and no colder past them
+        assert_eq!(temperature(8192., 0.), 18. - 20.);
+        assert_eq!(temperature(-8192., 0.), 18. - 20.);
+        assert_eq!(temperature(16384., 0.), 18. - 40.);
+        assert_eq!(temperature(-40000., 0.), 18. - 40.);
+        assert_eq!(temperature(8192., 40.), 18. - 20. - 10.);
+        // Moisture is clamped
+        assert_eq!(map.climate_at(0., 0., 0.).moisture, 1.);
+    }
+}
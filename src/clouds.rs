@@ -0,0 +1,51 @@
+// Cloud density for the sky, here rather than with the sky's material so previews and benchmarks sample
+// the clouds that ship
+
+use rand::Rng;
+
+use crate::noise::{Gradient3D, Fractal, Add, FromFn, NoiseSource3D, NoiseKind, Worley, PerlinWorley, ScaleBias, DomainWarp};
+
+// Texels along each side of the cloud density texture
+pub const CLOUD_VOLUME_SIZE: usize = 256;
+// Noise units between neighbouring texels of the density texture
+pub const CLOUD_DETAIL: f32 = 2.;
+
+// Cloud density, with CLOUD_DETAIL noise units between texels. The density texture wraps, so `noise` has to
+// tile (see `NoiseKind::tiles`).
+pub fn density(rng: &mut impl Rng, noise: NoiseKind) -> Result<impl NoiseSource3D + Send + Sync + 'static, String> {
+    let perlin_size = CLOUD_VOLUME_SIZE;
+    let perlin_detail = CLOUD_DETAIL;
+    // Tiles with the texture (3 lattice cells across the sampled range at the first octave, and a whole
+    // number at every following octave since the lacunarity is an integer)
+    let perlin_period = 3;
+    let perlin = Gradient3D::new(noise, rng).period(perlin_period)?;
+    let layered_perlin = Fractal::fbm(perlin)
+        .octaves(4)
+        .frequency(perlin_period as f32 / (perlin_size as f32 * perlin_detail))
+        .lacunarity(4.)
+        .gain(0.25)
+        .amplitude(0.75);
+    // Warping drags the billows out into streaks. The warp tiles the same way as the Perlin noise.
+    let warp_period = 2;
+    let warp = Fractal::fbm(Gradient3D::new(noise, rng).period(warp_period)?)
+        .octaves(2)
+        .frequency(warp_period as f32 / (perlin_size as f32 * perlin_detail))
+        .lacunarity(2.);
+    let warped_perlin = DomainWarp::new(layered_perlin, warp, 40.);
+    // Inverted Worley fbm that tiles with the texture (16 cells across the sampled range at the first octave)
+    let worley_period = 16;
+    let worley = ScaleBias::new(
+        Fractal::fbm(Worley::new(rng).period(worley_period))
+            .octaves(3)
+            .frequency(worley_period as f32 / (perlin_size as f32 * perlin_detail))
+            .lacunarity(2.)
+            .gain(0.5)
+            .amplitude(0.6),
+        -1., 1.
+    );
+    Ok(Add(
+        ScaleBias::new(PerlinWorley::new(warped_perlin, worley), 64., -32.),
+        // Fade out towards the top and bottom of the cloud layer
+        FromFn(|_x: f32, y: f32, _z: f32| -(y - 128.0) * (y - 128.0) / 16384.0)
+    ))
+}
@@ -0,0 +1,427 @@
+// Erosion simulated on a grid of heights, to carve the valleys and fans fractal noise doesn't have
+
+use std::f32::consts::SQRT_2;
+
+use bevy::prelude::*;
+use rand::{Rng, RngCore};
+use serde::{Serialize, Deserialize};
+
+use crate::{heightmap::Heightmap, noise::NoiseSource2D, raster::rasterize_2d};
+
+// Heights on a square-celled grid, row by row along x, with neighbouring samples one unit apart
+#[derive(Clone, Debug)]
+pub struct HeightGrid {
+    pub width: usize,
+    pub height: usize,
+    pub heights: Vec<f32>
+}
+
+impl HeightGrid {
+    pub fn new(width: usize, height: usize, heights: Vec<f32>) -> HeightGrid {
+        assert_eq!(heights.len(), width * height, "Heights don't match the grid");
+        HeightGrid { width, height, heights }
+    }
+
+    pub fn index(&self, x: usize, y: usize) -> usize {
+        y * self.width + x
+    }
+
+    // Bilinear height and gradient at `p`, which must lie inside the grid with room for the next sample
+    // along each axis
+    pub fn height_and_gradient(&self, p: Vec2) -> (f32, Vec2) {
+        let (x, y) = (p.x as usize, p.y as usize);
+        let t = p - Vec2::new(x as f32, y as f32);
+        let i = self.index(x, y);
+        let (a, b, c, d) = (self.heights[i], self.heights[i + 1], self.heights[i + self.width], self.heights[i + self.width + 1]);
+        let top = a + (b - a) * t.x;
+        let bottom = c + (d - c) * t.x;
+        (top + (bottom - top) * t.y, Vec2::new((b - a) * (1. - t.y) + (d - c) * t.y, bottom - top))
+    }
+
+    // Whether `p` is far enough from the far edges to be sampled
+    fn contains(&self, p: Vec2) -> bool {
+        p.x >= 0. && p.y >= 0. && p.x < (self.width - 1) as f32 && p.y < (self.height - 1) as f32
+    }
+}
+
+// A simulation run over a grid. Randomness only comes from `rng`, so the same seed always erodes the same way.
+pub trait ErosionPass {
+    fn apply(&self, grid: &mut HeightGrid, rng: &mut dyn RngCore);
+}
+
+// Droplets dropped at random, which run downhill picking up sediment while they speed up and dropping it
+// where they slow down or fill a pit, after Hans Theobald Beyer's "Implementation of a method for
+// hydraulic erosion"
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HydraulicErosion {
+    // Droplets simulated
+    pub iterations: u32,
+    // Steps each droplet lives for at most
+    pub lifetime: u32,
+    // How much of its direction a droplet keeps, from 0 (straight down the slope) to 1 (never turns)
+    pub inertia: f32,
+    // Sediment a droplet can carry per unit of slope, speed and water
+    pub capacity: f32,
+    // Floor on the capacity, so droplets still erode on flat ground
+    pub min_capacity: f32,
+    // Fraction of the missing capacity picked up each step
+    pub erosion_rate: f32,
+    // Fraction of the excess sediment dropped each step
+    pub deposition_rate: f32,
+    // Fraction of its water a droplet loses each step
+    pub evaporation: f32,
+    pub gravity: f32,
+    // Cells out from the droplet that are worn away, which keeps single droplets from digging pits
+    pub radius: u32
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        HydraulicErosion {
+            iterations: 100_000,
+            lifetime: 30,
+            inertia: 0.05,
+            capacity: 4.,
+            min_capacity: 0.01,
+            erosion_rate: 0.3,
+            deposition_rate: 0.3,
+            evaporation: 0.01,
+            gravity: 4.,
+            radius: 3
+        }
+    }
+}
+
+impl HydraulicErosion {
+    // Offsets and weights of the cells worn away around a droplet, heaviest in the middle
+    fn brush(&self) -> Vec<(IVec2, f32)> {
+        let radius = self.radius as i32;
+        let mut brush: Vec<(IVec2, f32)> = (-radius..=radius)
+            .flat_map(|y| (-radius..=radius).map(move |x| IVec2::new(x, y)))
+            .map(|offset| (offset, self.radius as f32 + 1. - offset.as_vec2().length()))
+            .filter(|&(_, weight)| weight > 0.)
+            .collect();
+        let total: f32 = brush.iter().map(|&(_, weight)| weight).sum();
+        brush.iter_mut().for_each(|(_, weight)| *weight /= total);
+        brush
+    }
+}
+
+impl ErosionPass for HydraulicErosion {
+    fn apply(&self, grid: &mut HeightGrid, rng: &mut dyn RngCore) {
+        if grid.width < 2 || grid.height < 2 {
+            return;
+        }
+        let brush = self.brush();
+        let size = IVec2::new(grid.width as i32, grid.height as i32);
+        for _ in 0..self.iterations {
+            let mut position = Vec2::new(rng.gen_range(0. ..(grid.width - 1) as f32), rng.gen_range(0. ..(grid.height - 1) as f32));
+            let mut direction = Vec2::ZERO;
+            let (mut speed, mut water, mut sediment) = (1., 1., 0.);
+            for _ in 0..self.lifetime {
+                let cell = position.floor();
+                let (height, gradient) = grid.height_and_gradient(position);
+                direction = (direction * self.inertia - gradient * (1. - self.inertia)).normalize_or_zero();
+                let previous = position;
+                position += direction;
+                if direction == Vec2::ZERO || !grid.contains(position) {
+                    break;
+                }
+
+                let height_change = grid.height_and_gradient(position).0 - height;
+                let capacity = (-height_change * speed * water * self.capacity).max(self.min_capacity);
+                if sediment > capacity || height_change > 0. {
+                    // Uphill the droplet fills the pit behind it, otherwise it drops part of its excess
+                    let deposit = if height_change > 0. {
+                        height_change.min(sediment)
+                    } else {
+                        (sediment - capacity) * self.deposition_rate
+                    };
+                    sediment -= deposit;
+                    // Spread over the corners of the cell it left, nearest first
+                    let t = previous - cell;
+                    let i = grid.index(cell.x as usize, cell.y as usize);
+                    grid.heights[i] += deposit * (1. - t.x) * (1. - t.y);
+                    grid.heights[i + 1] += deposit * t.x * (1. - t.y);
+                    grid.heights[i + grid.width] += deposit * (1. - t.x) * t.y;
+                    grid.heights[i + grid.width + 1] += deposit * t.x * t.y;
+                } else {
+                    // Never dig deeper than the drop, so droplets don't leave holes behind them
+                    let erosion = ((capacity - sediment) * self.erosion_rate).min(-height_change);
+                    for &(offset, weight) in brush.iter() {
+                        let target = cell.as_ivec2() + offset;
+                        if target.cmpge(IVec2::ZERO).all() && target.cmplt(size).all() {
+                            let i = grid.index(target.x as usize, target.y as usize);
+                            grid.heights[i] -= erosion * weight;
+                            sediment += erosion * weight;
+                        }
+                    }
+                }
+
+                speed = (speed * speed - height_change * self.gravity).max(0.).sqrt();
+                water *= 1. - self.evaporation;
+            }
+        }
+    }
+}
+
+// Neighbours material can slide to, and how far away they are
+const NEIGHBOURS: [(IVec2, f32); 8] = [
+    (IVec2::new(-1, -1), SQRT_2), (IVec2::new(0, -1), 1.), (IVec2::new(1, -1), SQRT_2),
+    (IVec2::new(-1, 0), 1.), (IVec2::new(1, 0), 1.),
+    (IVec2::new(-1, 1), SQRT_2), (IVec2::new(0, 1), 1.), (IVec2::new(1, 1), SQRT_2)
+];
+
+// Material sliding off slopes steeper than the talus angle onto the neighbours below, which knocks down
+// spikes and sharp ridges until the slopes settle, after Musgrave et al. "The synthesis and rendering of
+// eroded fractal terrains"
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThermalErosion {
+    // Most times material is moved
+    pub iterations: u32,
+    // Steepest slope that holds, in degrees
+    pub talus_angle: f32,
+    // Fraction of the excess over the talus slope moved each iteration. Past 0.5 slopes start to overshoot.
+    pub rate: f32,
+    // Stops early once an iteration moves less material than this in total
+    pub tolerance: f32
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        ThermalErosion { iterations: 50, talus_angle: 40., rate: 0.5, tolerance: 0. }
+    }
+}
+
+impl ThermalErosion {
+    // Relaxes `grid` and returns how much material moved in each iteration that ran, which shrinks towards
+    // zero as the slopes settle
+    pub fn relax(&self, grid: &mut HeightGrid) -> Vec<f32> {
+        let mut moved = Vec::new();
+        for _ in 0..self.iterations {
+            let step = self.step(grid);
+            moved.push(step);
+            if step <= self.tolerance {
+                break;
+            }
+        }
+        moved
+    }
+
+    // Moves material once and returns how much. Every cell reads the heights from before the step, so the
+    // result doesn't depend on the order cells are visited in.
+    pub fn step(&self, grid: &mut HeightGrid) -> f32 {
+        let talus = self.talus_angle.to_radians().tan();
+        let size = IVec2::new(grid.width as i32, grid.height as i32);
+        let mut changes = vec![0.; grid.heights.len()];
+        for y in 0..grid.height {
+            for x in 0..grid.width {
+                let i = grid.index(x, y);
+                // Height above the talus slope towards each neighbour
+                let mut excess = [0.; NEIGHBOURS.len()];
+                for (n, &(offset, distance)) in NEIGHBOURS.iter().enumerate() {
+                    let target = IVec2::new(x as i32, y as i32) + offset;
+                    if target.cmpge(IVec2::ZERO).all() && target.cmplt(size).all() {
+                        let drop = grid.heights[i] - grid.heights[grid.index(target.x as usize, target.y as usize)];
+                        excess[n] = (drop - talus * distance).max(0.);
+                    }
+                }
+                let (largest, total) = excess.iter().fold((0f32, 0.), |(largest, total), &e| (largest.max(e), total + e));
+                if total <= 0. {
+                    continue;
+                }
+                // Half the largest excess levels the steepest pair, shared out by how far past the talus each one is
+                let amount = self.rate * largest / 2.;
+                changes[i] -= amount;
+                for (n, &(offset, _)) in NEIGHBOURS.iter().enumerate().filter(|&(n, _)| excess[n] > 0.) {
+                    let target = IVec2::new(x as i32, y as i32) + offset;
+                    changes[grid.index(target.x as usize, target.y as usize)] += amount * excess[n] / total;
+                }
+            }
+        }
+        // Counted from the heights as they end up, since once slopes are within rounding of the talus the
+        // changes are too small to register, and the terrain has settled
+        let mut moved = 0.;
+        for (height, change) in grid.heights.iter_mut().zip(changes) {
+            let before = *height;
+            *height += change;
+            moved += (*height - before).abs() / 2.;
+        }
+        moved
+    }
+}
+
+impl ErosionPass for ThermalErosion {
+    fn apply(&self, grid: &mut HeightGrid, _rng: &mut dyn RngCore) {
+        let moved = self.relax(grid);
+        if let (Some(first), Some(last)) = (moved.first(), moved.last()) {
+            debug!("Thermal erosion ran {} iterations, moving {} at first and {} at the end", moved.len(), first, last);
+        }
+    }
+}
+
+// Every pass a recipe can run, in the order they're listed
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum ErosionStep {
+    Hydraulic(HydraulicErosion),
+    Thermal(ThermalErosion)
+}
+
+impl ErosionPass for ErosionStep {
+    fn apply(&self, grid: &mut HeightGrid, rng: &mut dyn RngCore) {
+        match self {
+            ErosionStep::Hydraulic(erosion) => erosion.apply(grid, rng),
+            ErosionStep::Thermal(erosion) => erosion.apply(grid, rng)
+        }
+    }
+}
+
+// Square of `size` cells, `spacing` apart and centered on the origin, where a heightmap is eroded. The
+// simulation only covers a finite area, so what it changed is faded out over `margin` cells towards the
+// edges, and the terrain carries on unchanged beyond them.
+#[derive(Clone, Copy, Debug)]
+pub struct ErosionRegion {
+    pub size: u32,
+    pub spacing: f32,
+    pub margin: u32
+}
+
+impl ErosionRegion {
+    // Runs `passes` in order over `source` sampled on the region, and returns how far each point moved, to
+    // be added back onto the source. The simulation works in cells, so heights are divided by the spacing
+    // to keep slopes the same at any resolution.
+    pub fn erode(&self, source: &impl NoiseSource2D, passes: &[impl ErosionPass], rng: &mut dyn RngCore) -> Heightmap {
+        let side = self.size as usize + 1;
+        let origin = Vec2::splat(-(self.size as f32) * self.spacing / 2.);
+        let original: Vec<f32> = rasterize_2d(source, origin, Vec2::splat(self.spacing), side, side)
+            .into_iter()
+            .map(|height| height / self.spacing)
+            .collect();
+        let mut grid = HeightGrid::new(side, side, original.clone());
+        for pass in passes {
+            pass.apply(&mut grid, rng);
+        }
+
+        // Zero on the outermost samples, so the clamped edges of the heightmap leave the source as it is
+        let fade = |i: usize| {
+            let t = (i.min(side - 1 - i) as f32 / self.margin.max(1) as f32).min(1.);
+            t * t * (3. - 2. * t)
+        };
+        let delta = grid.heights.iter().zip(original).enumerate()
+            .map(|(i, (eroded, original))| (eroded - original) * self.spacing * fade(i % side) * fade(i / side))
+            .collect();
+        Heightmap::new(side, side, delta).spacing(self.spacing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    fn settle(mut grid: HeightGrid) {
+        let erosion = ThermalErosion { iterations: 5000, tolerance: 1e-4, ..default() };
+        let volume: f32 = grid.heights.iter().sum();
+        let moved = erosion.relax(&mut grid);
+        let last = *moved.last().unwrap();
+        assert!(last <= erosion.tolerance, "Still moving {} after {} iterations", last, moved.len());
+        assert!(moved.len() < erosion.iterations as usize);
+        assert!(moved[0] > 100. * last);
+        // Material only moves between cells
+        let settled: f32 = grid.heights.iter().sum();
+        assert!((settled - volume).abs() < volume.abs() * 1e-4);
+    }
+
+    #[test]
+    fn thermal_erosion_settles_a_spike() {
+        let mut heights = vec![0.; 33 * 33];
+        heights[16 * 33 + 16] = 50.;
+        settle(HeightGrid::new(33, 33, heights));
+    }
+
+    #[test]
+    fn thermal_erosion_settles_a_step() {
+        let heights = (0..32 * 32).map(|i| if i % 32 < 16 { 20. } else { 0. }).collect();
+        settle(HeightGrid::new(32, 32, heights));
+    }
+
+    fn hydraulic(iterations: u32) -> HydraulicErosion {
+        HydraulicErosion { iterations, ..default() }
+    }
+
+    fn erode(erosion: &impl ErosionPass, mut grid: HeightGrid, seed: u64) -> HeightGrid {
+        erosion.apply(&mut grid, &mut ChaCha8Rng::seed_from_u64(seed));
+        grid
+    }
+
+    // Rolling hills with no flat spots, so every droplet has somewhere to go
+    fn hills(size: usize) -> HeightGrid {
+        let heights = (0..size * size).map(|i| {
+            let (x, y) = ((i % size) as f32, (i / size) as f32);
+            (x * 0.3).sin() * 4. + (y * 0.2).cos() * 3. + (x + y) * 0.1
+        }).collect();
+        HeightGrid::new(size, size, heights)
+    }
+
+    #[test]
+    fn hydraulic_erosion_is_reproducible() {
+        let erosion = hydraulic(2000);
+        let first = erode(&erosion, hills(48), 7);
+        let again = erode(&erosion, hills(48), 7);
+        let other = erode(&erosion, hills(48), 8);
+        let bits = |grid: &HeightGrid| grid.heights.iter().map(|h| h.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&first), bits(&again));
+        assert_ne!(bits(&first), bits(&other));
+        assert_ne!(bits(&first), bits(&hills(48)));
+    }
+
+    #[test]
+    fn hydraulic_erosion_moves_material_downhill() {
+        // A valley whose sides run down into a flat floor along the middle
+        let size = 64;
+        let center = (size - 1) as f32 / 2.;
+        let side = |x: usize| ((x as f32 - center).abs() - 8.).max(0.) * 0.5;
+        let before = HeightGrid::new(size, size, (0..size * size).map(|i| side(i % size)).collect());
+        let after = erode(&hydraulic(200), before.clone(), 3);
+        let change = |columns: &dyn Fn(usize) -> bool| -> f32 {
+            (0..size * size).filter(|i| columns(i % size)).map(|i| after.heights[i] - before.heights[i]).sum()
+        };
+        let slopes = change(&|x| (x as f32 - center).abs() > 8.);
+        let floor = change(&|x| (x as f32 - center).abs() <= 8.);
+        assert!(slopes < 0., "Slopes changed by {}", slopes);
+        // Most of what came off the slopes ends up on the floor
+        assert!(floor > -slopes / 2., "Floor gained {} of {}", floor, -slopes);
+    }
+
+    #[test]
+    fn hydraulic_erosion_roughly_conserves_material() {
+        // A bowl nothing can run out of, where droplets only lose what they still carry when they dry up
+        let size = 48;
+        let center = (size - 1) as f32 / 2.;
+        let heights = (0..size * size).map(|i| Vec2::new((i % size) as f32 - center, (i / size) as f32 - center).length_squared() * 0.01).collect();
+        let before = HeightGrid::new(size, size, heights);
+        let after = erode(&HydraulicErosion { lifetime: 60, ..hydraulic(200) }, before.clone(), 5);
+        let total = |grid: &HeightGrid| grid.heights.iter().sum::<f32>();
+        let moved: f32 = after.heights.iter().zip(&before.heights).map(|(a, b)| (a - b).abs()).sum();
+        let lost = total(&before) - total(&after);
+        assert!(moved > 0.);
+        assert!(lost > -1e-3 * moved && lost < 0.1 * moved, "Lost {} while moving {}", lost, moved);
+    }
+
+    #[test]
+    fn hydraulic_erosion_stays_inside_the_grid() {
+        // Tilted towards the far corner, so droplets run off the far edges, with a brush that reaches past them
+        let size = 40;
+        let heights = (0..size * size).map(|i| (2 * size - i % size - i / size) as f32 * 0.5 + (i % 7) as f32 * 0.05).collect();
+        let before = HeightGrid::new(size, size, heights);
+        let after = erode(&HydraulicErosion { radius: 5, ..hydraulic(500) }, before.clone(), 11);
+        assert!(after.heights.iter().all(|h| h.is_finite()));
+        assert_ne!(after.heights, before.heights);
+        // Droplets leaving take their sediment with them, but never add any
+        let total = |grid: &HeightGrid| grid.heights.iter().sum::<f32>();
+        assert!(total(&after) <= total(&before));
+    }
+}
@@ -0,0 +1,134 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use bevy::{prelude::*, asset::FileAssetIo, tasks::{AsyncComputeTaskPool, TaskPool}, render::mesh::VertexAttributeValues};
+
+use astral::biome::ClimateMap;
+use astral::mesh_file::{MeshData, save_mesh};
+use astral::noise::NoiseSource2D;
+
+use crate::{background::Background, preview::save_heightmap, recipe::WorldRecipe, terrain_chunks::TerrainChunks};
+use crate::terrain_plane::{TerrainPlane, TerrainPlaneDescriptor, TerrainPlaneMaterial};
+use crate::world::{WorldSeed, NoiseSettings, WorldRecipePath, arg_value};
+
+// Exports the terrain from inside the app
+const EXPORT_KEY: KeyCode = KeyCode::F12;
+// Where the key binding writes its files, relative to the working directory
+const EXPORT_DIRECTORY: &str = "exports";
+
+#[derive(Default)]
+pub struct ExportPlugin {}
+
+impl Plugin for ExportPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ExportGrid(TerrainPlaneDescriptor::from_args("export")));
+        app.add_systems(Update, (start_export, finish_export));
+    }
+}
+
+/// Files to write the terrain to instead of launching the scene. `--export-heightmap <path>` takes a `.png`
+/// or `.raw` (16-bit little-endian) heightmap and `--export-mesh <path>` an `.obj` or `.glb` mesh with
+/// normals and vertex colors.
+#[derive(Clone, Debug)]
+pub struct ExportRequest {
+    pub heightmap: Option<PathBuf>,
+    pub mesh: Option<PathBuf>,
+    /// Grid both are sampled on, set with `--export-size <cells>` and `--export-spacing <units>`
+    pub grid: TerrainPlaneDescriptor
+}
+
+// Grid the key binding exports on
+#[derive(Resource)]
+struct ExportGrid(TerrainPlaneDescriptor);
+
+// Export started with the key binding, running in the background
+#[derive(Resource)]
+struct ExportInProgress(Background<Result<(), String>>);
+
+impl ExportRequest {
+    /// Returns `None` when no export was asked for
+    pub fn from_args() -> Option<ExportRequest> {
+        let path = |kind: &str| arg_value(&format!("export-{}", kind)).map(PathBuf::from);
+        let request = ExportRequest { heightmap: path("heightmap"), mesh: path("mesh"), grid: TerrainPlaneDescriptor::from_args("export") };
+        (request.heightmap.is_some() || request.mesh.is_some()).then_some(request)
+    }
+
+    // PNG heightmap and glTF mesh named after the seed, in `EXPORT_DIRECTORY`
+    fn in_directory(seed: WorldSeed, grid: TerrainPlaneDescriptor) -> ExportRequest {
+        let path = |extension: &str| Path::new(EXPORT_DIRECTORY).join(format!("terrain_{}.{}", seed.0, extension));
+        ExportRequest { heightmap: Some(path("png")), mesh: Some(path("glb")), grid }
+    }
+
+    /// Generates the terrain with the same seed the scene would use and writes it out
+    pub fn run(&self, seed: WorldSeed, noise: NoiseSettings, recipe_path: &WorldRecipePath) -> Result<(), String> {
+        // Generation is spread over the same pool as in the app, which isn't running to set it up
+        AsyncComputeTaskPool::init(TaskPool::default);
+        let recipe = WorldRecipe::read(&FileAssetIo::get_base_path().join("assets").join(&recipe_path.0))?;
+        let heightmap = recipe.terrain.build_2d(noise.terrain, &mut seed.rng("terrain"))?;
+        let climate = recipe.climate.build(noise.terrain, &mut seed.rng("climate"))?;
+        // The scene starts out with the same coloring, whatever its noise texture
+        self.write(&heightmap, &climate, &TerrainPlaneMaterial::new(Handle::default()))
+    }
+
+    fn write(&self, heightmap: &(impl NoiseSource2D + Sync), climate: &ClimateMap, material: &TerrainPlaneMaterial) -> Result<(), String> {
+        if let Some(path) = &self.heightmap {
+            save_heightmap(path, &self.grid, heightmap)?;
+        }
+        if let Some(path) = &self.mesh {
+            save_terrain_mesh(path, &TerrainPlane::mesh(&self.grid, heightmap), climate, material)?;
+        }
+        Ok(())
+    }
+}
+
+// Writes `mesh` with vertex colors baked from `material`, in the biomes `climate` gives each vertex
+fn save_terrain_mesh(path: &Path, mesh: &Mesh, climate: &ClimateMap, material: &TerrainPlaneMaterial) -> Result<(), String> {
+    let (Some(VertexAttributeValues::Float32x3(positions)), Some(VertexAttributeValues::Float32x3(normals))) =
+        (mesh.attribute(Mesh::ATTRIBUTE_POSITION), mesh.attribute(Mesh::ATTRIBUTE_NORMAL)) else {
+        return Err("Terrain mesh has no positions or normals".to_string());
+    };
+    let indices: Vec<u32> = mesh.indices().map(|indices| indices.iter().map(|i| i as u32).collect()).unwrap_or_default();
+    let colors: Vec<[f32; 4]> = positions.iter().zip(normals)
+        .map(|(&[x, y, z], &normal)| material.color_at(y, Vec3::from(normal), &climate.biome_weights_at(x, z, y)).as_linear_rgba_f32())
+        .collect();
+    let data = MeshData { positions, normals, colors: Some(&colors), indices: &indices };
+    save_mesh(path, &data).map_err(|err| format!("Couldn't write {}: {}", path.display(), err))?;
+    println!("Wrote {} ({} vertices, {} triangles)", path.display(), positions.len(), indices.len() / 3);
+    Ok(())
+}
+
+fn start_export(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    seed: Res<WorldSeed>,
+    grid: Res<ExportGrid>,
+    chunks: Option<Res<TerrainChunks>>,
+    materials: Res<Assets<TerrainPlaneMaterial>>,
+    running: Option<Res<ExportInProgress>>
+) {
+    if !keys.just_pressed(EXPORT_KEY) || running.is_some() {
+        return;
+    }
+    let Some(chunks) = chunks else {
+        println!("Nothing to export until the terrain recipe has loaded");
+        return;
+    };
+    // Colored with the material as it's currently tweaked
+    let material = materials.get(chunks.material()).cloned().unwrap_or_else(|| TerrainPlaneMaterial::new(Handle::default()));
+    let (heightmap, climate) = (chunks.sculpted(), chunks.climate().clone());
+    let request = ExportRequest::in_directory(*seed, grid.0);
+    println!("Exporting terrain to {}/", EXPORT_DIRECTORY);
+    commands.insert_resource(ExportInProgress(Background::spawn(move || {
+        fs::create_dir_all(EXPORT_DIRECTORY).map_err(|err| format!("Couldn't create {}: {}", EXPORT_DIRECTORY, err))?;
+        request.write(&heightmap, &climate, &material)
+    })));
+}
+
+fn finish_export(mut commands: Commands, running: Option<Res<ExportInProgress>>) {
+    let Some(result) = running.and_then(|running| running.0.poll()) else {
+        return;
+    };
+    if let Err(err) = result {
+        eprintln!("Export failed: {}", err);
+    }
+    commands.remove_resource::<ExportInProgress>();
+}
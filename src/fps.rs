@@ -1,5 +1,7 @@
 use bevy::prelude::*;
 
+use crate::terrain_query::TerrainQuery;
+
 #[derive(Default)]
 pub struct FpsPlugin {}
 
@@ -28,7 +30,17 @@ fn startup(mut commands: Commands, asset_server: Res<AssetServer>) {
     }));
 }
 
-fn update(mut query: Query<&mut Text, With<FPSTextBox>>, time: Res<Time>) {
+// Frame rate, then the camera's height over the terrain, the slope beneath it and the climate there
+fn update(mut query: Query<&mut Text, With<FPSTextBox>>, time: Res<Time>, camera: Query<&Transform, With<Camera3d>>, terrain: TerrainQuery) {
     let mut text = query.single_mut();
-    text.sections[0].value = ((1.0 / time.delta_seconds()).round() as i32).to_string()
+    let mut value = ((1.0 / time.delta_seconds()).round() as i32).to_string();
+    if let Ok(eye) = camera.get_single().map(|transform| transform.translation) {
+        if let (Some(height), Some(normal)) = (terrain.height_at(eye.x, eye.z), terrain.normal_at(eye.x, eye.z)) {
+            value += &format!("\n{:.1} above terrain, {:.0}° slope", eye.y - height, normal.angle_between(Vec3::Y).to_degrees());
+        }
+        if let Some(climate) = terrain.climate_at(eye.x, eye.z) {
+            value += &format!("\n{}, {:.0}°C, {:.0}% moisture", climate.biome().name(), climate.temperature, climate.moisture * 100.);
+        }
+    }
+    text.sections[0].value = value;
 }
@@ -0,0 +1,406 @@
+// Elevation data loaded from disk, sampled like any other 2D noise source so it can drive the same terrain
+
+use std::{fs::{self, File}, path::Path};
+
+use bevy::prelude::*;
+use serde::{Serialize, Deserialize};
+use tiff::{ColorType, decoder::{Decoder, DecodingResult}};
+
+use crate::noise::{NoiseSource2D, NoiseGrad2D};
+
+// Marks missing samples in SRTM tiles
+const HGT_VOID: i16 = -32768;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HeightmapFilter {
+    #[default]
+    Bilinear,
+    // Catmull-Rom, which passes through the samples and keeps slopes continuous between cells
+    Bicubic
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ByteOrder {
+    #[default]
+    LittleEndian,
+    BigEndian
+}
+
+// How a heightmap file is stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HeightmapFormat {
+    // Grayscale PNG, 16-bit for full precision
+    Png,
+    // Headerless unsigned 16-bit samples, row by row
+    Raw {
+        width: usize,
+        height: usize,
+        #[serde(default)]
+        byte_order: ByteOrder
+    },
+    // SRTM tile: a square of big-endian signed 16-bit samples in metres
+    Hgt,
+    // Single band TIFF of any integer or float sample type
+    GeoTiff
+}
+
+impl HeightmapFormat {
+    // Guesses the format from the file extension. RAW files can't be recognized, since they also need a size.
+    pub fn from_path(path: &Path) -> Option<HeightmapFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(HeightmapFormat::Png),
+            "hgt" => Some(HeightmapFormat::Hgt),
+            "tif" | "tiff" => Some(HeightmapFormat::GeoTiff),
+            _ => None
+        }
+    }
+}
+
+// Grid of elevation samples. Sample (i, j) lies at x = i * spacing, y = j * spacing from the grid's
+// corner, the grid is centered on `center`, and beyond the edges the outermost samples carry on. Heights
+// come out as (sample - sea_level) * vertical_scale.
+#[derive(Clone, Debug)]
+pub struct Heightmap {
+    width: usize,
+    height: usize,
+    samples: Vec<f32>,
+    pub spacing: f32,
+    pub center: Vec2,
+    pub vertical_scale: f32,
+    pub sea_level: f32,
+    pub filter: HeightmapFilter
+}
+
+impl Heightmap {
+    pub fn new(width: usize, height: usize, samples: Vec<f32>) -> Heightmap {
+        assert!(width > 0 && height > 0, "Heightmap can't be empty");
+        assert_eq!(samples.len(), width * height, "Heightmap samples don't match its size");
+        Heightmap {
+            width,
+            height,
+            samples,
+            spacing: 1.,
+            center: Vec2::ZERO,
+            vertical_scale: 1.,
+            sea_level: 0.,
+            filter: HeightmapFilter::default()
+        }
+    }
+
+    // Loads a file in the format given by its extension
+    pub fn load(path: &Path) -> Result<Heightmap, String> {
+        let format = HeightmapFormat::from_path(path)
+            .ok_or_else(|| format!("Unknown heightmap format for {}", path.display()))?;
+        Self::load_as(path, format)
+    }
+
+    pub fn load_as(path: &Path, format: HeightmapFormat) -> Result<Heightmap, String> {
+        let error = |err: &dyn std::fmt::Display| format!("Couldn't load heightmap {}: {}", path.display(), err);
+        match format {
+            HeightmapFormat::Png => {
+                let image = image::open(path).map_err(|err| error(&err))?.into_luma16();
+                let (width, height) = (image.width() as usize, image.height() as usize);
+                Ok(Heightmap::new(width, height, image.into_raw().into_iter().map(f32::from).collect()))
+            },
+            HeightmapFormat::Raw { width, height, byte_order } => {
+                let bytes = fs::read(path).map_err(|err| error(&err))?;
+                if bytes.len() != width * height * 2 {
+                    return Err(error(&format!("expected {}x{} 16-bit samples, found {} bytes", width, height, bytes.len())));
+                }
+                let samples = bytes.chunks_exact(2).map(|b| match byte_order {
+                    ByteOrder::LittleEndian => u16::from_le_bytes([b[0], b[1]]),
+                    ByteOrder::BigEndian => u16::from_be_bytes([b[0], b[1]])
+                } as f32).collect();
+                Ok(Heightmap::new(width, height, samples))
+            },
+            HeightmapFormat::Hgt => {
+                let bytes = fs::read(path).map_err(|err| error(&err))?;
+                // 1201 samples across for 3 arc-second tiles, 3601 for 1 arc-second ones
+                let side = ((bytes.len() / 2) as f64).sqrt() as usize;
+                if side == 0 || side * side * 2 != bytes.len() {
+                    return Err(error(&format!("{} bytes isn't a square tile of 16-bit samples", bytes.len())));
+                }
+                // Voids are left at sea level
+                let samples = bytes.chunks_exact(2)
+                    .map(|b| i16::from_be_bytes([b[0], b[1]]))
+                    .map(|sample| if sample == HGT_VOID { 0. } else { sample as f32 })
+                    .collect();
+                Ok(Heightmap::new(side, side, samples))
+            },
+            HeightmapFormat::GeoTiff => {
+                let file = File::open(path).map_err(|err| error(&err))?;
+                let mut decoder = Decoder::new(file).map_err(|err| error(&err))?;
+                if !matches!(decoder.colortype().map_err(|err| error(&err))?, ColorType::Gray(_)) {
+                    return Err(error(&"expected a single band"));
+                }
+                let (width, height) = decoder.dimensions().map_err(|err| error(&err))?;
+                let samples = match decoder.read_image().map_err(|err| error(&err))? {
+                    DecodingResult::U8(data) => data.into_iter().map(f32::from).collect(),
+                    DecodingResult::U16(data) => data.into_iter().map(f32::from).collect(),
+                    DecodingResult::U32(data) => data.into_iter().map(|v| v as f32).collect(),
+                    DecodingResult::U64(data) => data.into_iter().map(|v| v as f32).collect(),
+                    DecodingResult::I8(data) => data.into_iter().map(f32::from).collect(),
+                    DecodingResult::I16(data) => data.into_iter().map(f32::from).collect(),
+                    DecodingResult::I32(data) => data.into_iter().map(|v| v as f32).collect(),
+                    DecodingResult::I64(data) => data.into_iter().map(|v| v as f32).collect(),
+                    DecodingResult::F32(data) => data,
+                    DecodingResult::F64(data) => data.into_iter().map(|v| v as f32).collect()
+                };
+                Ok(Heightmap::new(width as usize, height as usize, samples))
+            }
+        }
+    }
+
+    pub fn spacing(mut self, spacing: f32) -> Self {
+        self.spacing = spacing;
+        self
+    }
+
+    pub fn center(mut self, center: Vec2) -> Self {
+        self.center = center;
+        self
+    }
+
+    pub fn vertical_scale(mut self, vertical_scale: f32) -> Self {
+        self.vertical_scale = vertical_scale;
+        self
+    }
+
+    pub fn sea_level(mut self, sea_level: f32) -> Self {
+        self.sea_level = sea_level;
+        self
+    }
+
+    pub fn filter(mut self, filter: HeightmapFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn size(&self) -> UVec2 {
+        UVec2::new(self.width as u32, self.height as u32)
+    }
+
+    fn sample(&self, i: i64, j: i64) -> f32 {
+        let i = i.clamp(0, self.width as i64 - 1) as usize;
+        let j = j.clamp(0, self.height as i64 - 1) as usize;
+        self.samples[j * self.width + i]
+    }
+}
+
+// Catmull-Rom weights of the four samples around t, and their derivatives
+fn catmull_rom(t: f32) -> ([f32; 4], [f32; 4]) {
+    let (t2, t3) = (t * t, t * t * t);
+    (
+        [(-t3 + 2. * t2 - t) / 2., (3. * t3 - 5. * t2 + 2.) / 2., (-3. * t3 + 4. * t2 + t) / 2., (t3 - t2) / 2.],
+        [(-3. * t2 + 4. * t - 1.) / 2., (9. * t2 - 10. * t) / 2., (-9. * t2 + 8. * t + 1.) / 2., (3. * t2 - 2. * t) / 2.]
+    )
+}
+
+impl NoiseSource2D for Heightmap {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        self.sample_grad_2d(x, y).0
+    }
+}
+
+impl NoiseGrad2D for Heightmap {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let half_size = Vec2::new(self.width as f32 - 1., self.height as f32 - 1.) / 2.;
+        let p = (Vec2::new(x, y) - self.center) / self.spacing + half_size;
+        let cell = p.floor();
+        let (t, i, j) = (p - cell, cell.x as i64, cell.y as i64);
+        let (value, gradient) = match self.filter {
+            HeightmapFilter::Bilinear => {
+                let (a, b, c, d) = (self.sample(i, j), self.sample(i + 1, j), self.sample(i, j + 1), self.sample(i + 1, j + 1));
+                let top = a + (b - a) * t.x;
+                let bottom = c + (d - c) * t.x;
+                let gradient = Vec2::new((b - a) * (1. - t.y) + (d - c) * t.y, bottom - top);
+                (top + (bottom - top) * t.y, gradient)
+            },
+            HeightmapFilter::Bicubic => {
+                let ((wx, dwx), (wy, dwy)) = (catmull_rom(t.x), catmull_rom(t.y));
+                let (mut value, mut gradient) = (0., Vec2::ZERO);
+                for (dj, (wy, dwy)) in wy.into_iter().zip(dwy).enumerate() {
+                    let (mut row, mut row_dx) = (0., 0.);
+                    for (di, (wx, dwx)) in wx.into_iter().zip(dwx).enumerate() {
+                        let sample = self.sample(i + di as i64 - 1, j + dj as i64 - 1);
+                        row += wx * sample;
+                        row_dx += dwx * sample;
+                    }
+                    value += wy * row;
+                    gradient += Vec2::new(wy * row_dx, dwy * row);
+                }
+                (value, gradient)
+            }
+        };
+        ((value - self.sea_level) * self.vertical_scale, gradient * self.vertical_scale / self.spacing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Luma};
+    use std::path::PathBuf;
+    use tiff::encoder::{TiffEncoder, colortype};
+
+    // Path in the temp directory that's removed again when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            TempFile(std::env::temp_dir().join(format!("astral-{}-{}", std::process::id(), name)))
+        }
+
+        fn with_bytes(name: &str, bytes: &[u8]) -> TempFile {
+            let file = TempFile::new(name);
+            fs::write(&file.0, bytes).unwrap();
+            file
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn raw_samples_follow_the_byte_order() {
+        let file = TempFile::with_bytes("order.raw", &[0x01, 0x02, 0x03, 0x04, 0xff, 0x00]);
+        let load = |byte_order| Heightmap::load_as(&file.0, HeightmapFormat::Raw { width: 3, height: 1, byte_order }).unwrap().samples;
+        assert_eq!(load(ByteOrder::LittleEndian), [513., 1027., 255.]);
+        assert_eq!(load(ByteOrder::BigEndian), [258., 772., 65280.]);
+        assert!(Heightmap::load_as(&file.0, HeightmapFormat::Raw { width: 2, height: 2, byte_order: ByteOrder::LittleEndian }).is_err());
+        // RAW files have no size, so they can't be loaded by extension
+        assert!(Heightmap::load(&file.0).is_err());
+    }
+
+    #[test]
+    fn hgt_tiles_are_square_with_voids_at_sea_level() {
+        let samples: [i16; 9] = [100, -20, 0, HGT_VOID, 8848, -32767, 1, 2, 3];
+        let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_be_bytes()).collect();
+        let file = TempFile::with_bytes("tile.hgt", &bytes);
+        let heightmap = Heightmap::load(&file.0).unwrap();
+        assert_eq!(heightmap.size(), UVec2::new(3, 3));
+        assert_eq!(heightmap.samples, [100., -20., 0., 0., 8848., -32767., 1., 2., 3.]);
+
+        for length in [0, 6, 7, 16] {
+            let file = TempFile::with_bytes(&format!("tile{}.hgt", length), &bytes[..length]);
+            let loaded = Heightmap::load(&file.0);
+            // 8 bytes are a 2x2 tile, anything else here isn't square
+            assert!(loaded.is_err(), "{} bytes loaded as {:?}", length, loaded.map(|heightmap| heightmap.size()));
+        }
+        let file = TempFile::with_bytes("small.hgt", &bytes[..8]);
+        assert_eq!(Heightmap::load(&file.0).unwrap().samples, [100., -20., 0., 0.]);
+    }
+
+    #[test]
+    fn png_keeps_all_16_bits() {
+        let pixels: Vec<u16> = vec![0, 1, 255, 256, 4097, 32768, 65534, 65535];
+        let file = TempFile::new("round_trip.png");
+        ImageBuffer::<Luma<u16>, _>::from_raw(4, 2, pixels.clone()).unwrap().save(&file.0).unwrap();
+        let heightmap = Heightmap::load(&file.0).unwrap();
+        assert_eq!(heightmap.size(), UVec2::new(4, 2));
+        assert_eq!(heightmap.samples, pixels.iter().map(|&pixel| pixel as f32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn geotiff_reads_any_single_band_sample_type() {
+        fn load<C: colortype::ColorType>(name: &str, data: &[C::Inner]) -> Result<Heightmap, String> where [C::Inner]: tiff::encoder::TiffValue {
+            let file = TempFile::new(name);
+            TiffEncoder::new(File::create(&file.0).unwrap()).unwrap().write_image::<C>(2, 2, data).unwrap();
+            Heightmap::load(&file.0)
+        }
+        let expected = [0., 1., 2., 250.];
+        assert_eq!(load::<colortype::Gray8>("u8.tif", &[0, 1, 2, 250]).unwrap().samples, expected);
+        assert_eq!(load::<colortype::Gray16>("u16.tiff", &[0, 1, 2, 250]).unwrap().samples, expected);
+        assert_eq!(load::<colortype::GrayI16>("i16.tif", &[-400, 0, 2, 8848]).unwrap().samples, [-400., 0., 2., 8848.]);
+        assert_eq!(load::<colortype::GrayI32>("i32.tif", &[-1, 0, 1, 100_000]).unwrap().samples, [-1., 0., 1., 100_000.]);
+        assert_eq!(load::<colortype::Gray32Float>("f32.tif", &[-0.5, 0., 1.25, 3000.5]).unwrap().samples, [-0.5, 0., 1.25, 3000.5]);
+        assert_eq!(load::<colortype::Gray64Float>("f64.tif", &[-0.5, 0., 1.25, 3000.5]).unwrap().samples, [-0.5, 0., 1.25, 3000.5]);
+        assert!(load::<colortype::RGB8>("rgb.tif", &[0; 12]).is_err());
+    }
+
+    // Samples with no pattern to them, on a grid offset, scaled and shifted so every setting is exercised
+    fn bumpy(filter: HeightmapFilter) -> Heightmap {
+        let (width, height) = (7, 6);
+        let samples = (0..width * height).map(|k| ((k % width * 7 + k / width * 13) % 11) as f32 * 3.).collect();
+        Heightmap::new(width, height, samples)
+            .spacing(2.)
+            .center(Vec2::new(1., -3.))
+            .vertical_scale(0.5)
+            .sea_level(4.)
+            .filter(filter)
+    }
+
+    // Where sample (i, j) lies
+    fn lattice(heightmap: &Heightmap, i: f32, j: f32) -> Vec2 {
+        let half_size = Vec2::new(heightmap.width as f32 - 1., heightmap.height as f32 - 1.) / 2.;
+        (Vec2::new(i, j) - half_size) * heightmap.spacing + heightmap.center
+    }
+
+    fn height(heightmap: &Heightmap, i: i64, j: i64) -> f32 {
+        (heightmap.sample(i, j) - heightmap.sea_level) * heightmap.vertical_scale
+    }
+
+    #[test]
+    fn filters_pass_through_the_samples() {
+        for filter in [HeightmapFilter::Bilinear, HeightmapFilter::Bicubic] {
+            let heightmap = bumpy(filter);
+            for j in 0..heightmap.height as i64 {
+                for i in 0..heightmap.width as i64 {
+                    let p = lattice(&heightmap, i as f32, j as f32);
+                    let value = heightmap.sample_2d(p.x, p.y);
+                    assert!((value - height(&heightmap, i, j)).abs() < 1e-4, "{:?} at sample ({}, {}): {}", filter, i, j, value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn filters_interpolate_between_the_samples() {
+        let bilinear = bumpy(HeightmapFilter::Bilinear);
+        let bicubic = bumpy(HeightmapFilter::Bicubic);
+        for (i, j) in [(1, 1), (2, 3), (4, 2), (3, 4)] {
+            // The middle of a cell is the average of its corners
+            let p = lattice(&bilinear, i as f32 + 0.5, j as f32 + 0.5);
+            let corners = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(di, dj)| height(&bilinear, i + di, j + dj));
+            assert!((bilinear.sample_2d(p.x, p.y) - corners.iter().sum::<f32>() / 4.).abs() < 1e-4);
+
+            // Halfway along a row, Catmull-Rom weighs the four samples around it -1/16, 9/16, 9/16, -1/16
+            let p = lattice(&bicubic, i as f32 + 0.5, j as f32);
+            let row = [-1, 0, 1, 2].map(|di| height(&bicubic, i + di, j));
+            let expected = (-row[0] + 9. * row[1] + 9. * row[2] - row[3]) / 16.;
+            assert!((bicubic.sample_2d(p.x, p.y) - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        const H: f32 = 1e-3;
+        // Inside the cells, where both filters are smooth. Bicubic is also smooth across the samples.
+        let cells = [(1.5, 1.5), (2.25, 3.5), (4.5, 2.75), (3.1, 1.9), (2.6, 3.3)];
+        let samples = [(2., 2.), (3., 3.), (4., 1.)];
+        let bicubic_points: Vec<(f32, f32)> = cells.iter().chain(&samples).copied().collect();
+        for (filter, points) in [(HeightmapFilter::Bilinear, cells.to_vec()), (HeightmapFilter::Bicubic, bicubic_points)] {
+            let heightmap = bumpy(filter);
+            for (i, j) in points {
+                let p = lattice(&heightmap, i, j);
+                let (value, gradient) = heightmap.sample_grad_2d(p.x, p.y);
+                assert!((value - heightmap.sample_2d(p.x, p.y)).abs() < 1e-5);
+                let differences = Vec2::new(
+                    heightmap.sample_2d(p.x + H, p.y) - heightmap.sample_2d(p.x - H, p.y),
+                    heightmap.sample_2d(p.x, p.y + H) - heightmap.sample_2d(p.x, p.y - H)
+                ) / (2. * H);
+                assert!((gradient - differences).abs().max_element() < 2e-2 * gradient.abs().max_element().max(1.),
+                    "{:?} at ({}, {}): {} vs {}", filter, i, j, gradient, differences);
+            }
+        }
+        // At a sample Catmull-Rom's slope is half the difference of its neighbours
+        let heightmap = bumpy(HeightmapFilter::Bicubic);
+        let p = lattice(&heightmap, 3., 2.);
+        let slope = (height(&heightmap, 4, 2) - height(&heightmap, 2, 2)) / 2. / heightmap.spacing;
+        assert!((heightmap.sample_grad_2d(p.x, p.y).1.x - slope).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,9 @@
+pub mod noise;
+pub mod raster;
+pub mod heightmap;
+pub mod mesh_file;
+pub mod erosion;
+pub mod sculpt;
+pub mod biome;
+pub mod clouds;
+pub mod terrain_recipe;
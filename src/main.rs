@@ -1,23 +1,71 @@
-use std::f32::consts::PI;
+use std::{f32::consts::{PI, FRAC_1_SQRT_2}, sync::Arc};
 use bevy_framepace::{Limiter, FramepaceSettings, FramepacePlugin};
 use fps::FpsPlugin;
-use rand::random;
 
 use bevy::{prelude::*, input::mouse::MouseMotion, app::AppExit, window::{CursorGrabMode, Cursor}, log::{LogPlugin, Level}};
 use sky_plane::{SkyPlaneMaterial, SkyPlanePlugin};
 use terrain_plane::TerrainPlaneMaterial;
 
-use crate::{terrain_plane::{TerrainPlane, TerrainPlanePlugin}, sky_plane::SkyPlane};
+use astral::{biome::ClimateMap, noise::{BoxedNoise2D, Translate}};
+use rand_chacha::ChaCha8Rng;
+#[cfg(target_arch = "wasm32")]
+use bevy::render::render_resource::IndexFormat;
+use world::{WorldSeed, NoiseSettings, WorldRecipePath};
+use recipe::{WorldRecipe, WorldRecipeHandle, WorldRecipePlugin};
+
+use crate::{background::{Background, BackgroundPlugin}, terrain_plane::{TerrainPlane, TerrainPlaneDescriptor, TerrainPlanePlugin}, terrain_chunks::{TerrainChunks, TerrainChunksPlugin}, sky_plane::SkyPlane};
+use crate::terrain_query::{TerrainPicked, TerrainPickingPlugin, TerrainQuery};
+use crate::sculpting::SculptingPlugin;
 
 mod terrain_plane;
 mod sky_plane;
 mod fps;
+mod world;
+mod recipe;
+mod terrain_chunks;
+mod background;
+mod terrain_query;
+mod sculpting;
+#[cfg(not(target_arch = "wasm32"))]
+mod preview;
+#[cfg(not(target_arch = "wasm32"))]
+mod export;
 
 fn main() {
     #[cfg(target_arch = "wasm32")]
     console_error_panic_hook::set_once();
 
-    App::new()
+    let seed = WorldSeed::from_args();
+    println!("World seed: {}", seed.0);
+    let noise = NoiseSettings::from_args();
+    let recipe_path = WorldRecipePath::from_args();
+
+    // Write the requested previews and exit without opening a window
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(preview) = preview::PreviewRequest::from_args() {
+        if let Err(err) = preview.run(seed, noise, &recipe_path) {
+            eprintln!("Preview failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Write the requested terrain exports and exit, like previews
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(export) = export::ExportRequest::from_args() {
+        if let Err(err) = export.run(seed, noise, &recipe_path) {
+            eprintln!("Export failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut app = App::new();
+    app
+        .insert_resource(seed)
+        .insert_resource(noise)
+        .insert_resource(recipe_path)
+        .insert_resource(CameraCollision::from_args())
         .insert_resource(ClearColor(Color::rgb(0.94, 0.97, 1.0) * 0.8))
         .add_plugins((
             DefaultPlugins
@@ -33,49 +81,34 @@ fn main() {
                 }),
             FramepacePlugin {}
         ))
-        .add_plugins((TerrainPlanePlugin::default(), SkyPlanePlugin::default(), FpsPlugin::default()))
+        .add_plugins((BackgroundPlugin::default(), TerrainPlanePlugin::default(), TerrainChunksPlugin::default(), SkyPlanePlugin::default(), FpsPlugin::default(), WorldRecipePlugin::default(), TerrainPickingPlugin::default(), SculptingPlugin::default()))
         .add_systems(Startup, startup)
-        .add_systems(Update, (update_move, update_look, exit_game, use_mouse))
-        .run();
+        .add_systems(Update, (generate_world, finish_terrain, (update_move, keep_clearance).chain(), update_look, exit_game, use_mouse, toggle_collision, report_picks));
+    // Files can't be written from the browser
+    #[cfg(not(target_arch = "wasm32"))]
+    app.add_plugins(export::ExportPlugin::default());
+    app.run();
 }
 
+#[allow(clippy::too_many_arguments)]
 fn startup(
     mut commands: Commands,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut terrain_materials: ResMut<Assets<TerrainPlaneMaterial>>,
     mut sky_materials: ResMut<Assets<SkyPlaneMaterial>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut images: ResMut<Assets<Image>>,
-    mut frames: ResMut<FramepaceSettings>
+    mut frames: ResMut<FramepaceSettings>,
+    asset_server: Res<AssetServer>,
+    seed: Res<WorldSeed>,
+    noise: Res<NoiseSettings>,
+    recipe_path: Res<WorldRecipePath>
 ) {
     println!("Hello, world!");
 
     frames.limiter = Limiter::from_framerate(60.);
 
-    // Terrain
-    let perlin_1 = perlin_2d(100);
-    let perlin_2 = perlin_2d(100);
-    let perlin_3 = perlin_2d(100);
-    let perlin_4 = perlin_2d(100);
-    let terrain_heightmap = |x: f32, y: f32| {
-        [
-            perlin_1(x / 3., y / 3.),
-            perlin_2(x / 13., y / 13.) * 4.,
-            perlin_3(x / 43., y / 43.) * 16.,
-            perlin_4(x / 197., y / 197.) * 64.
-        ].iter().sum()
-    };
-    let terrain = TerrainPlane::new(&mut meshes, &mut terrain_materials, &mut images, terrain_heightmap);
-    let terrain_handle = terrain.mesh.clone();
-    let terrain_material_handle = terrain.material.clone();
-    commands.spawn((terrain, MaterialMeshBundle {
-        mesh: terrain_handle,
-        material: terrain_material_handle.clone(),
-        ..default()
-    }));
-
     // Sky
-    let sky = SkyPlane::new(&mut meshes, &mut sky_materials, &mut images);
+    let (sky, wind) = SkyPlane::new(&mut meshes, &mut sky_materials, &mut images, &mut seed.rng("sky"), noise.clouds);
+    commands.insert_resource(wind);
     let sky_handle = sky.mesh.clone();
     let sky_material_handle = sky.material.clone();
     commands.spawn((sky, MaterialMeshBundle {
@@ -85,49 +118,8 @@ fn startup(
         ..default()
     }));
 
-    // Water
-    let material_water = materials.add(StandardMaterial {
-        base_color: Color::rgba(0.2, 0.2, 0.9, 0.45),
-        reflectance: 0.4,
-        alpha_mode: AlphaMode::Blend,
-        ..default()
-    });
-    let perlin_1 = perlin_2d(100);
-    let perlin_2 = perlin_2d(100);
-    let heightmap = |x: f32, y: f32| {
-        [
-            perlin_1(x / 1.5, y / 1.5) * 0.3,
-            perlin_2(x / 7., y / 7.) * 0.8,
-        ].iter().sum()
-    };
-    let water = TerrainPlane::new(&mut meshes, &mut terrain_materials, &mut images, heightmap);
-    let water2 = TerrainPlane::new(&mut meshes, &mut terrain_materials, &mut images, |x, y| heightmap(x + 34., y - 12.));
-    let water3 = TerrainPlane::new(&mut meshes, &mut terrain_materials, &mut images, |x, y| heightmap(x + 11., y + 64.));
-    let water4 = TerrainPlane::new(&mut meshes, &mut terrain_materials, &mut images, |x, y| heightmap(x - 22., y - 36.));
-    commands.spawn(MaterialMeshBundle {
-        mesh: water.mesh.clone(),
-        material: material_water.clone(),
-        transform: Transform::from_translation(-16. * Vec3::Y),
-        ..default()
-    });
-    commands.spawn(MaterialMeshBundle {
-        mesh: water2.mesh.clone(),
-        material: material_water.clone(),
-        transform: Transform::from_translation(-17. * Vec3::Y),
-        ..default()
-    });
-    commands.spawn(MaterialMeshBundle {
-        mesh: water3.mesh.clone(),
-        material: material_water.clone(),
-        transform: Transform::from_translation(-18. * Vec3::Y),
-        ..default()
-    });
-    commands.spawn(MaterialMeshBundle {
-        mesh: water4.mesh.clone(),
-        material: material_water.clone(),
-        transform: Transform::from_translation(-19. * Vec3::Y),
-        ..default()
-    });
+    // Terrain and water are generated once their recipe has loaded
+    commands.insert_resource(WorldRecipeHandle(asset_server.load(recipe_path.0.as_str())));
 
     commands.spawn(DirectionalLightBundle {
         directional_light: DirectionalLight {
@@ -141,11 +133,116 @@ fn startup(
     });
 
     commands.spawn(Camera3dBundle {
-        transform: Transform::from_xyz(1024., 1024., 1024.).looking_at(Vec3::new(0., 0., 0.), Vec3::Y),
+        transform: Transform::from_xyz(256., 256., 256.).looking_at(Vec3::new(0., 0., 0.), Vec3::Y),
         ..default()
     });
 }
 
+// Meshes generated from the world recipe, replaced whenever the recipe changes. Terrain chunks are
+// managed by `TerrainChunks` instead.
+#[derive(Component)]
+struct WorldMesh;
+
+#[allow(clippy::too_many_arguments)]
+fn generate_world(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<WorldRecipe>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    recipes: Res<Assets<WorldRecipe>>,
+    recipe_handle: Res<WorldRecipeHandle>,
+    seed: Res<WorldSeed>,
+    noise: Res<NoiseSettings>,
+    generated: Query<Entity, With<WorldMesh>>
+) {
+    let changed = events.iter().any(|event| match event {
+        AssetEvent::Created { handle } | AssetEvent::Modified { handle } => *handle == recipe_handle.0,
+        AssetEvent::Removed { .. } => false
+    });
+    let Some(recipe) = recipes.get(&recipe_handle.0).filter(|_| changed) else {
+        return;
+    };
+    generated.iter().for_each(|entity| commands.entity(entity).despawn());
+
+    // Terrain, built in the background since eroding it takes a while. The chunks already streamed in stay
+    // until it's done, and a newer recipe replaces one that's still being built.
+    let (terrain, climate, kind, seed) = (recipe.terrain.clone(), recipe.climate.clone(), noise.terrain, *seed);
+    commands.insert_resource(PendingTerrain(Background::spawn(move || {
+        let mut rng = seed.rng("terrain");
+        let built = terrain.build_2d(kind, &mut rng)
+            .and_then(|heightmap| Ok((heightmap, climate.build(kind, &mut seed.rng("climate"))?)));
+        // The material's noise texture carries on from the same stream
+        (built, rng)
+    })));
+
+    // Water
+    match recipe.water.heightmap.build_2d(noise.water, &mut seed.rng("water")) {
+        Ok(heightmap) => {
+            let heightmap = Arc::new(heightmap);
+            let material_water = materials.add(StandardMaterial {
+                base_color: Color::rgba(0.2, 0.2, 0.9, 0.45),
+                reflectance: 0.4,
+                alpha_mode: AlphaMode::Blend,
+                ..default()
+            });
+            // Web builds draw the water on a coarser grid that fits 16-bit indices
+            #[cfg(target_arch = "wasm32")]
+            let grid = TerrainPlaneDescriptor {
+                index_format: IndexFormat::Uint16,
+                ..TerrainPlaneDescriptor::centered(UVec2::splat(250), 4.)
+            };
+            #[cfg(not(target_arch = "wasm32"))]
+            let grid = TerrainPlaneDescriptor::default();
+            for layer in recipe.water.layers.iter() {
+                let offset = Vec3::new(layer.offset[0], layer.offset[1], 0.);
+                let (water, generating) = TerrainPlane::new(&mut meshes, &grid, Translate::new(heightmap.clone(), offset));
+                commands.spawn((WorldMesh, generating, MaterialMeshBundle {
+                    mesh: water.mesh.clone(),
+                    material: material_water.clone(),
+                    transform: Transform::from_translation(layer.height * Vec3::Y),
+                    ..default()
+                }, water));
+            }
+            println!("Water ({}) generating in the background", noise.water);
+        },
+        Err(err) => eprintln!("Invalid water recipe: {}", err)
+    }
+}
+
+// Terrain heightmap and climate built from the recipe
+type BuiltTerrain = Result<(BoxedNoise2D, ClimateMap), String>;
+
+// Terrain being built in the background, and the terrain stream to draw the material from
+#[derive(Resource)]
+struct PendingTerrain(Background<(BuiltTerrain, ChaCha8Rng)>);
+
+// Streams the terrain in around the camera once it's built
+fn finish_terrain(
+    mut commands: Commands,
+    mut terrain_materials: ResMut<Assets<TerrainPlaneMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut images: ResMut<Assets<Image>>,
+    noise: Res<NoiseSettings>,
+    pending: Option<Res<PendingTerrain>>,
+    chunks: Option<ResMut<TerrainChunks>>
+) {
+    let Some((built, mut rng)) = pending.and_then(|pending| pending.0.poll()) else {
+        return;
+    };
+    commands.remove_resource::<PendingTerrain>();
+    match built {
+        Ok((terrain_heightmap, climate)) => {
+            let material = TerrainPlane::material(&mut terrain_materials, &mut images, &mut rng);
+            match chunks {
+                Some(mut chunks) => chunks.reset(&mut commands, &mut meshes, terrain_heightmap, climate, material),
+                None => commands.insert_resource(TerrainChunks::new(&mut meshes, terrain_heightmap, climate, material))
+            }
+            println!("Terrain ({}) recipe loaded", noise.terrain);
+        },
+        Err(err) => eprintln!("Invalid terrain recipe: {}", err)
+    }
+}
+
 fn update_move(mut camera: Query<&mut Transform, With<Camera>>, keys: Res<Input<KeyCode>>, time: Res<Time>) {
     let mut camera = camera.single_mut();
     let forward = camera.forward();
@@ -165,6 +262,72 @@ fn update_move(mut camera: Query<&mut Transform, With<Camera>>, keys: Res<Input<
     }
 }
 
+// Keeps the camera `clearance` units above the terrain while `enabled`, toggled with C. Off unless asked
+// for, so the camera can fly anywhere.
+#[derive(Resource, Clone, Copy, Debug)]
+struct CameraCollision {
+    enabled: bool,
+    // Whether water counts as a floor. With it the camera can't go below sea level.
+    over_water: bool,
+    clearance: f32
+}
+
+impl CameraCollision {
+    // Reads `--camera-collision <off|ground|surface>`, off by default, where `ground` lets the camera dive
+    // under water and `surface` keeps it above the water too, and `--camera-clearance <units>`, 2 by default
+    fn from_args() -> CameraCollision {
+        let (enabled, over_water) = match world::arg_value("camera-collision").as_deref().map(str::trim) {
+            None | Some("off") => (false, true),
+            Some("ground") => (true, false),
+            Some("surface") => (true, true),
+            Some(other) => panic!("Invalid camera collision '{}', expected 'off', 'ground' or 'surface'", other)
+        };
+        let clearance = match world::arg_value("camera-clearance").map(|v| v.trim().parse::<f32>()) {
+            Some(Ok(clearance)) => clearance,
+            Some(Err(err)) => panic!("Invalid camera clearance: {}", err),
+            None => 2.
+        };
+        CameraCollision { enabled, over_water, clearance }
+    }
+}
+
+// Directions around the camera the ground is also checked in, so it keeps its distance from steep slopes
+// beside it as well as the ground below
+const CLEARANCE_FOOTPRINT: [Vec2; 9] = [
+    Vec2::new(0., 0.),
+    Vec2::new(1., 0.), Vec2::new(-1., 0.), Vec2::new(0., 1.), Vec2::new(0., -1.),
+    Vec2::new(FRAC_1_SQRT_2, FRAC_1_SQRT_2), Vec2::new(-FRAC_1_SQRT_2, FRAC_1_SQRT_2),
+    Vec2::new(FRAC_1_SQRT_2, -FRAC_1_SQRT_2), Vec2::new(-FRAC_1_SQRT_2, -FRAC_1_SQRT_2)
+];
+
+// Lifts the camera back up after it moves too low. Only the height changes, so moving into a slope slides
+// along it. The floor is the highest surface within `clearance` of the camera, which changes smoothly as the
+// camera moves even over sharp ridges, so the camera doesn't jitter. Where nothing has been generated yet
+// the camera is left alone.
+fn keep_clearance(mut camera: Query<&mut Transform, With<Camera3d>>, collision: Res<CameraCollision>, terrain: TerrainQuery) {
+    let Ok(mut camera) = camera.get_single_mut() else {
+        return;
+    };
+    if !collision.enabled {
+        return;
+    }
+    let eye = camera.translation;
+    let floor = CLEARANCE_FOOTPRINT.iter()
+        .map(|direction| Vec2::new(eye.x, eye.z) + *direction * collision.clearance)
+        .filter_map(|p| if collision.over_water { terrain.height_at(p.x, p.y) } else { terrain.ground_height_at(p.x, p.y) })
+        .reduce(f32::max);
+    if let Some(floor) = floor {
+        camera.translation.y = eye.y.max(floor + collision.clearance);
+    }
+}
+
+fn toggle_collision(keys: Res<Input<KeyCode>>, mut collision: ResMut<CameraCollision>) {
+    if keys.just_pressed(KeyCode::C) {
+        collision.enabled = !collision.enabled;
+        println!("Camera collision {}", if collision.enabled { "on" } else { "off" });
+    }
+}
+
 fn update_look(mut camera: Query<&mut Transform, With<Camera>>, mut mouse: EventReader<MouseMotion>, time: Res<Time>, window: Query<&Window>) {
     let window = window.single();
     if window.cursor.visible {
@@ -186,7 +349,13 @@ fn exit_game(keys: Res<Input<KeyCode>>, mut exit: EventWriter<AppExit>, assets:
     if keys.pressed(KeyCode::Escape) {
         assets.iter().for_each(|(_, mat)| println!("{:#?}", mat));
         assets2.iter().for_each(|(_, mat)| println!("{:#?}", mat));
-        exit.send(AppExit::default());
+        exit.send(AppExit);
+    }
+}
+
+fn report_picks(mut picks: EventReader<TerrainPicked>) {
+    for TerrainPicked(hit) in picks.iter() {
+        println!("Picked terrain at {:.2} ({:.1} away), facing {:.2}", hit.position, hit.distance, hit.normal);
     }
 }
 
@@ -202,73 +371,3 @@ fn use_mouse(keys: Res<Input<KeyCode>>, mut window: Query<&mut Window>) {
         }
     }
 }
-
-fn perlin_2d(size: usize) -> impl Fn(f32, f32) -> f32 {
-    let mut map = vec![vec![Vec2::ZERO; size]; size];
-    for xi in 0..size {
-        for yi in 0..size {
-            map[xi][yi] = Vec2::from_angle(2. * PI * random::<f32>());
-        }
-    }
-    move |x, y| {
-        let (xi, yi) = (x.rem_euclid(size as f32), y.rem_euclid(size as f32));
-        let (xi_f, yi_f) = (xi.floor(), yi.floor());
-        let points = vec![
-            Vec2::new(xi_f, yi_f),
-            Vec2::new(xi_f, yi_f+1.),
-            Vec2::new(xi_f+1., yi_f),
-            Vec2::new(xi_f+1., yi_f+1.),
-        ];
-        let gradients = points.iter().map(|p| map[p.x as usize % size][p.y as usize % size]); // % size in case floating point error
-        let offsets = points.iter().map(|p| Vec2::new(xi, yi) - *p);
-        let dot_offsets = gradients.zip(offsets).map(|(g, o)| (g.dot(o), o)).collect::<Vec<_>>();
-        let smoothstep = |x: f32| 6. * x.powi(5) - 15. * x.powi(4) + 10. * x.powi(3);
-        let interp = |a, b, p| a + (b - a) * smoothstep(p);
-        let interp_x = |(d1, p1): (f32, Vec2), (d2, _)| (interp(d1, d2, p1.x), Vec2::new(0., p1.y));
-        let interp_y = |(d1, p1): (f32, Vec2), (d2, _)| interp(d1, d2, p1.y);
-        interp_y(interp_x(dot_offsets[0b00], dot_offsets[0b10]), interp_x(dot_offsets[0b01], dot_offsets[0b11]))
-    }
-}
-
-fn perlin_3d(size: usize) -> impl Fn(f32, f32, f32) -> f32 {
-    let mut map = vec![vec![vec![Vec3::ZERO; size]; size]; size];
-    for xi in 0..size {
-        for yi in 0..size {
-            for zi in 0..size {
-                let (dx, dy, dz) = random();
-                map[xi][yi][zi] = Vec3::new(dx, dy, dz).normalize();
-            }
-        }
-    }
-    move |x, y, z| {
-        let (xi, yi, zi) = (x.rem_euclid(size as f32), y.rem_euclid(size as f32), z.rem_euclid(size as f32));
-        let (xi_f, yi_f, zi_f) = (xi.floor(), yi.floor(), zi.floor());
-        let mut points = Vec::new();
-        for dx in 0..=1 {
-            for dy in 0..=1 {
-                for dz in 0..=1 {
-                    points.push(Vec3::new(xi_f + dx as f32, yi_f + dy as f32, zi_f + dz as f32));
-                }
-            }
-        }
-        // modulo size again in case floating point error
-        let gradients = points.iter().map(|p| map[p.x as usize % size][p.y as usize % size][p.z as usize % size]);
-        let offsets = points.iter().map(|p| Vec3::new(xi, yi, zi) - *p);
-        let dot_offsets = gradients.zip(offsets).map(|(g, o)| (g.dot(o), o)).collect::<Vec<_>>();
-        let smoothstep = |x: f32| 6. * x.powi(5) - 15. * x.powi(4) + 10. * x.powi(3);
-        let interp = |a, b, p| a + (b - a) * smoothstep(p);
-        let interp_x = |(d1, p1): (f32, Vec3), (d2, _)| (interp(d1, d2, p1.x), Vec3::new(0., p1.y, p1.z));
-        let interp_y = |(d1, p1): (f32, Vec3), (d2, _)| (interp(d1, d2, p1.y), Vec3::new(0., 0., p1.z));
-        let interp_z = |(d1, p1): (f32, Vec3), (d2, _)| interp(d1, d2, p1.z);
-        interp_z(
-            interp_y(
-                interp_x(dot_offsets[0b000], dot_offsets[0b100]),
-                interp_x(dot_offsets[0b010], dot_offsets[0b110]),
-            ),
-            interp_y(
-                interp_x(dot_offsets[0b001], dot_offsets[0b101]),
-                interp_x(dot_offsets[0b011], dot_offsets[0b111])
-            )
-        )
-    }
-}
@@ -0,0 +1,135 @@
+// Writes triangle meshes to files other tools can open: Wavefront OBJ and binary glTF
+
+use std::{fs::File, io::{self, BufWriter, Write}, path::Path};
+
+// glTF constants
+const GLB_MAGIC: u32 = 0x46546C67;
+const GLB_CHUNK_JSON: u32 = 0x4E4F534A;
+const GLB_CHUNK_BIN: u32 = 0x004E4942;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+
+// Indexed triangle list. Colors are linear RGBA, one per vertex like the normals.
+#[derive(Clone, Copy, Debug)]
+pub struct MeshData<'a> {
+    pub positions: &'a [[f32; 3]],
+    pub normals: &'a [[f32; 3]],
+    pub colors: Option<&'a [[f32; 4]]>,
+    pub indices: &'a [u32]
+}
+
+impl MeshData<'_> {
+    fn check(&self) {
+        assert_eq!(self.normals.len(), self.positions.len(), "Mesh needs one normal per vertex");
+        assert!(self.colors.is_none_or(|colors| colors.len() == self.positions.len()), "Mesh needs one color per vertex");
+        assert_eq!(self.indices.len() % 3, 0, "Mesh indices don't make whole triangles");
+    }
+}
+
+// Writes `mesh` in the format given by the path's extension, `.obj` or `.glb`
+pub fn save_mesh(path: &Path, mesh: &MeshData) -> io::Result<()> {
+    let extension = path.extension().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+    match extension.as_str() {
+        "obj" => save_obj(path, mesh),
+        "glb" => save_glb(path, mesh),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported mesh format \"{}\"", extension)))
+    }
+}
+
+// Colors go after each vertex position (`v x y z r g b`), converted to sRGB, which most viewers read
+pub fn save_obj(path: &Path, mesh: &MeshData) -> io::Result<()> {
+    mesh.check();
+    let mut out = BufWriter::new(File::create(path)?);
+    for (i, [x, y, z]) in mesh.positions.iter().enumerate() {
+        match mesh.colors {
+            Some(colors) => {
+                let [r, g, b, _] = colors[i].map(linear_to_srgb);
+                writeln!(out, "v {} {} {} {} {} {}", x, y, z, r, g, b)?;
+            },
+            None => writeln!(out, "v {} {} {}", x, y, z)?
+        }
+    }
+    for [x, y, z] in mesh.normals {
+        writeln!(out, "vn {} {} {}", x, y, z)?;
+    }
+    // OBJ counts from 1
+    for triangle in mesh.indices.chunks(3) {
+        let [a, b, c] = [triangle[0] + 1, triangle[1] + 1, triangle[2] + 1];
+        writeln!(out, "f {}//{} {}//{} {}//{}", a, a, b, b, c, c)?;
+    }
+    out.flush()
+}
+
+// A single glTF mesh with its buffer embedded. Attributes are stored one after another as floats, then the
+// 32-bit indices.
+pub fn save_glb(path: &Path, mesh: &MeshData) -> io::Result<()> {
+    mesh.check();
+    let vertex_count = mesh.positions.len();
+    let (min, max) = mesh.positions.iter().fold(([f32::MAX; 3], [f32::MIN; 3]), |(min, max), p| {
+        ([0, 1, 2].map(|i| min[i].min(p[i])), [0, 1, 2].map(|i| max[i].max(p[i])))
+    });
+
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut views = Vec::new();
+    let mut accessors = Vec::new();
+    let mut attributes = Vec::new();
+    let mut push_view = |buffer: &mut Vec<u8>, bytes: Vec<u8>, target: u32| {
+        views.push(format!(r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{}}}"#, buffer.len(), bytes.len(), target));
+        buffer.extend(bytes);
+        views.len() - 1
+    };
+
+    let floats = |values: &mut dyn Iterator<Item = f32>| values.flat_map(f32::to_le_bytes).collect::<Vec<u8>>();
+    let view = push_view(&mut buffer, floats(&mut mesh.positions.iter().flatten().copied()), ARRAY_BUFFER);
+    accessors.push(format!(
+        r#"{{"bufferView":{},"componentType":{},"count":{},"type":"VEC3","min":[{},{},{}],"max":[{},{},{}]}}"#,
+        view, FLOAT, vertex_count, min[0], min[1], min[2], max[0], max[1], max[2]
+    ));
+    attributes.push(format!(r#""POSITION":{}"#, accessors.len() - 1));
+
+    let view = push_view(&mut buffer, floats(&mut mesh.normals.iter().flatten().copied()), ARRAY_BUFFER);
+    accessors.push(format!(r#"{{"bufferView":{},"componentType":{},"count":{},"type":"VEC3"}}"#, view, FLOAT, vertex_count));
+    attributes.push(format!(r#""NORMAL":{}"#, accessors.len() - 1));
+
+    if let Some(colors) = mesh.colors {
+        let view = push_view(&mut buffer, floats(&mut colors.iter().flatten().copied()), ARRAY_BUFFER);
+        accessors.push(format!(r#"{{"bufferView":{},"componentType":{},"count":{},"type":"VEC4"}}"#, view, FLOAT, vertex_count));
+        attributes.push(format!(r#""COLOR_0":{}"#, accessors.len() - 1));
+    }
+
+    let indices = mesh.indices.iter().flat_map(|i| i.to_le_bytes()).collect();
+    let view = push_view(&mut buffer, indices, ELEMENT_ARRAY_BUFFER);
+    accessors.push(format!(r#"{{"bufferView":{},"componentType":{},"count":{},"type":"SCALAR"}}"#, view, UNSIGNED_INT, mesh.indices.len()));
+
+    let json = format!(
+        concat!(
+            r#"{{"asset":{{"version":"2.0","generator":"astral"}},"scene":0,"scenes":[{{"nodes":[0]}}],"nodes":[{{"mesh":0}}],"#,
+            r#""meshes":[{{"primitives":[{{"attributes":{{{}}},"indices":{}}}]}}],"accessors":[{}],"bufferViews":[{}],"buffers":[{{"byteLength":{}}}]}}"#
+        ),
+        attributes.join(","), accessors.len() - 1, accessors.join(","), views.join(","), buffer.len()
+    );
+
+    // Chunks are padded to 4 bytes, the JSON with spaces
+    let mut json = json.into_bytes();
+    json.resize(json.len().next_multiple_of(4), b' ');
+    buffer.resize(buffer.len().next_multiple_of(4), 0);
+    let total_len = 12 + 8 + json.len() + 8 + buffer.len();
+
+    let mut out = BufWriter::new(File::create(path)?);
+    for word in [GLB_MAGIC, 2, total_len as u32, json.len() as u32, GLB_CHUNK_JSON] {
+        out.write_all(&word.to_le_bytes())?;
+    }
+    out.write_all(&json)?;
+    for word in [buffer.len() as u32, GLB_CHUNK_BIN] {
+        out.write_all(&word.to_le_bytes())?;
+    }
+    out.write_all(&buffer)?;
+    out.flush()
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    let c = c.clamp(0., 1.);
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1. / 2.4) - 0.055 }
+}
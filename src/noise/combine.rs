@@ -0,0 +1,279 @@
+use bevy::prelude::*;
+
+use super::{NoiseSource2D, NoiseSource3D, NoiseGrad2D, NoiseGrad3D, CHUNK};
+
+// Batched `op(a, b)`: `a` samples straight into `out`, `b(start, buffer)` fills a stack buffer with the row's
+// samples from index `start` on, one chunk at a time
+fn combine_row(out: &mut [f32], a: impl FnOnce(&mut [f32]), b: impl Fn(usize, &mut [f32]), op: impl Fn(f32, f32) -> f32) {
+    a(out);
+    let mut scratch = [0.; CHUNK];
+    for (chunk_idx, chunk) in out.chunks_mut(CHUNK).enumerate() {
+        let scratch = &mut scratch[..chunk.len()];
+        b(chunk_idx * CHUNK, scratch);
+        for (value, other) in chunk.iter_mut().zip(scratch.iter()) {
+            *value = op(*value, *other);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Add<A, B>(pub A, pub B);
+
+impl<A: NoiseSource2D, B: NoiseSource2D> NoiseSource2D for Add<A, B> {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        self.0.sample_2d(x, y) + self.1.sample_2d(x, y)
+    }
+
+    fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+        combine_row(
+            out,
+            |out| self.0.sample_row_2d(x, y, step, out),
+            |start, out| self.1.sample_row_2d(x + start as f32 * step, y, step, out),
+            |a, b| a + b
+        )
+    }
+}
+
+impl<A: NoiseSource3D, B: NoiseSource3D> NoiseSource3D for Add<A, B> {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        self.0.sample_3d(x, y, z) + self.1.sample_3d(x, y, z)
+    }
+
+    fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+        combine_row(
+            out,
+            |out| self.0.sample_row_3d(x, y, z, step, out),
+            |start, out| self.1.sample_row_3d(x + start as f32 * step, y, z, step, out),
+            |a, b| a + b
+        )
+    }
+}
+
+impl<A: NoiseGrad2D, B: NoiseGrad2D> NoiseGrad2D for Add<A, B> {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let ((a, da), (b, db)) = (self.0.sample_grad_2d(x, y), self.1.sample_grad_2d(x, y));
+        (a + b, da + db)
+    }
+}
+
+impl<A: NoiseGrad3D, B: NoiseGrad3D> NoiseGrad3D for Add<A, B> {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+        let ((a, da), (b, db)) = (self.0.sample_grad_3d(x, y, z), self.1.sample_grad_3d(x, y, z));
+        (a + b, da + db)
+    }
+}
+
+#[derive(Clone)]
+pub struct Mul<A, B>(pub A, pub B);
+
+impl<A: NoiseSource2D, B: NoiseSource2D> NoiseSource2D for Mul<A, B> {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        self.0.sample_2d(x, y) * self.1.sample_2d(x, y)
+    }
+
+    fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+        combine_row(
+            out,
+            |out| self.0.sample_row_2d(x, y, step, out),
+            |start, out| self.1.sample_row_2d(x + start as f32 * step, y, step, out),
+            |a, b| a * b
+        )
+    }
+}
+
+impl<A: NoiseSource3D, B: NoiseSource3D> NoiseSource3D for Mul<A, B> {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        self.0.sample_3d(x, y, z) * self.1.sample_3d(x, y, z)
+    }
+
+    fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+        combine_row(
+            out,
+            |out| self.0.sample_row_3d(x, y, z, step, out),
+            |start, out| self.1.sample_row_3d(x + start as f32 * step, y, z, step, out),
+            |a, b| a * b
+        )
+    }
+}
+
+impl<A: NoiseGrad2D, B: NoiseGrad2D> NoiseGrad2D for Mul<A, B> {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let ((a, da), (b, db)) = (self.0.sample_grad_2d(x, y), self.1.sample_grad_2d(x, y));
+        (a * b, da * b + db * a)
+    }
+}
+
+impl<A: NoiseGrad3D, B: NoiseGrad3D> NoiseGrad3D for Mul<A, B> {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+        let ((a, da), (b, db)) = (self.0.sample_grad_3d(x, y, z), self.1.sample_grad_3d(x, y, z));
+        (a * b, da * b + db * a)
+    }
+}
+
+// Picks `lower` where `control` is below `threshold` and `upper` above it, blending across a band of
+// width 2 * `falloff` around the threshold
+#[derive(Clone)]
+pub struct Select<C, A, B> {
+    pub control: C,
+    pub lower: A,
+    pub upper: B,
+    pub threshold: f32,
+    pub falloff: f32
+}
+
+impl<C, A, B> Select<C, A, B> {
+    pub fn new(control: C, lower: A, upper: B, threshold: f32, falloff: f32) -> Select<C, A, B> {
+        Select { control, lower, upper, threshold, falloff }
+    }
+
+    // Blend factor towards `upper` for a control value
+    fn blend(&self, control: f32) -> f32 {
+        self.blend_with_slope(control).0
+    }
+
+    // Batched select over one chunk: `out` holds the control values on entry, and `lower`/`upper` only
+    // sample into their buffers when some value in the chunk needs them
+    fn blend_chunk(&self, out: &mut [f32], lower: impl FnOnce(&mut [f32]), upper: impl FnOnce(&mut [f32])) {
+        let (mut lower_buf, mut upper_buf) = ([0.; CHUNK], [0.; CHUNK]);
+        out.iter_mut().for_each(|value| *value = self.blend(*value));
+        if out.iter().any(|&t| t < 1.) {
+            lower(&mut lower_buf[..out.len()]);
+        }
+        if out.iter().any(|&t| t > 0.) {
+            upper(&mut upper_buf[..out.len()]);
+        }
+        for (i, value) in out.iter_mut().enumerate() {
+            *value = match *value {
+                t if t <= 0. => lower_buf[i],
+                t if t >= 1. => upper_buf[i],
+                t => lower_buf[i] * (1. - t) + upper_buf[i] * t
+            };
+        }
+    }
+
+    // Blend factor and its derivative with respect to the control value
+    fn blend_with_slope(&self, control: f32) -> (f32, f32) {
+        if self.falloff <= 0. {
+            return (if control < self.threshold { 0. } else { 1. }, 0.);
+        }
+        let t = (control - self.threshold + self.falloff) / (2. * self.falloff);
+        if !(0. ..=1.).contains(&t) {
+            return (t.clamp(0., 1.), 0.);
+        }
+        (t * t * (3. - 2. * t), 6. * t * (1. - t) / (2. * self.falloff))
+    }
+}
+
+impl<C: NoiseSource2D, A: NoiseSource2D, B: NoiseSource2D> NoiseSource2D for Select<C, A, B> {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        match self.blend(self.control.sample_2d(x, y)) {
+            t if t <= 0. => self.lower.sample_2d(x, y),
+            t if t >= 1. => self.upper.sample_2d(x, y),
+            t => self.lower.sample_2d(x, y) * (1. - t) + self.upper.sample_2d(x, y) * t
+        }
+    }
+
+    fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+        for (chunk_idx, chunk) in out.chunks_mut(CHUNK).enumerate() {
+            let x = x + (chunk_idx * CHUNK) as f32 * step;
+            self.control.sample_row_2d(x, y, step, chunk);
+            self.blend_chunk(chunk, |out| self.lower.sample_row_2d(x, y, step, out), |out| self.upper.sample_row_2d(x, y, step, out));
+        }
+    }
+}
+
+impl<C: NoiseSource3D, A: NoiseSource3D, B: NoiseSource3D> NoiseSource3D for Select<C, A, B> {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        match self.blend(self.control.sample_3d(x, y, z)) {
+            t if t <= 0. => self.lower.sample_3d(x, y, z),
+            t if t >= 1. => self.upper.sample_3d(x, y, z),
+            t => self.lower.sample_3d(x, y, z) * (1. - t) + self.upper.sample_3d(x, y, z) * t
+        }
+    }
+
+    fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+        for (chunk_idx, chunk) in out.chunks_mut(CHUNK).enumerate() {
+            let x = x + (chunk_idx * CHUNK) as f32 * step;
+            self.control.sample_row_3d(x, y, z, step, chunk);
+            self.blend_chunk(chunk, |out| self.lower.sample_row_3d(x, y, z, step, out), |out| self.upper.sample_row_3d(x, y, z, step, out));
+        }
+    }
+}
+
+impl<C: NoiseGrad2D, A: NoiseGrad2D, B: NoiseGrad2D> NoiseGrad2D for Select<C, A, B> {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let (control, control_grad) = self.control.sample_grad_2d(x, y);
+        match self.blend_with_slope(control) {
+            (t, _) if t <= 0. => self.lower.sample_grad_2d(x, y),
+            (t, _) if t >= 1. => self.upper.sample_grad_2d(x, y),
+            (t, slope) => {
+                let ((a, da), (b, db)) = (self.lower.sample_grad_2d(x, y), self.upper.sample_grad_2d(x, y));
+                (a * (1. - t) + b * t, da * (1. - t) + db * t + control_grad * (slope * (b - a)))
+            }
+        }
+    }
+}
+
+impl<C: NoiseGrad3D, A: NoiseGrad3D, B: NoiseGrad3D> NoiseGrad3D for Select<C, A, B> {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+        let (control, control_grad) = self.control.sample_grad_3d(x, y, z);
+        match self.blend_with_slope(control) {
+            (t, _) if t <= 0. => self.lower.sample_grad_3d(x, y, z),
+            (t, _) if t >= 1. => self.upper.sample_grad_3d(x, y, z),
+            (t, slope) => {
+                let ((a, da), (b, db)) = (self.lower.sample_grad_3d(x, y, z), self.upper.sample_grad_3d(x, y, z));
+                (a * (1. - t) + b * t, da * (1. - t) + db * t + control_grad * (slope * (b - a)))
+            }
+        }
+    }
+}
+
+// Billowy cloud density: `perlin` (roughly [-1, 1]) dilated by `worley`, an inverted Worley noise in
+// [0, 1] that is high inside the cells. Returns a density in [0, 1].
+#[derive(Clone)]
+pub struct PerlinWorley<P, W> {
+    pub perlin: P,
+    pub worley: W
+}
+
+impl<P, W> PerlinWorley<P, W> {
+    pub fn new(perlin: P, worley: W) -> PerlinWorley<P, W> {
+        PerlinWorley { perlin, worley }
+    }
+
+    fn blend(perlin: f32, worley: f32) -> f32 {
+        let (perlin, worley) = ((perlin * 0.5 + 0.5).clamp(0., 1.), worley.clamp(0., 1.));
+        // Remap perlin from [0, 1] to [worley, 1]
+        worley + perlin * (1. - worley)
+    }
+}
+
+impl<P: NoiseSource2D, W: NoiseSource2D> NoiseSource2D for PerlinWorley<P, W> {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        Self::blend(self.perlin.sample_2d(x, y), self.worley.sample_2d(x, y))
+    }
+
+    fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+        combine_row(
+            out,
+            |out| self.perlin.sample_row_2d(x, y, step, out),
+            |start, out| self.worley.sample_row_2d(x + start as f32 * step, y, step, out),
+            Self::blend
+        )
+    }
+}
+
+impl<P: NoiseSource3D, W: NoiseSource3D> NoiseSource3D for PerlinWorley<P, W> {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        Self::blend(self.perlin.sample_3d(x, y, z), self.worley.sample_3d(x, y, z))
+    }
+
+    fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+        combine_row(
+            out,
+            |out| self.perlin.sample_row_3d(x, y, z, step, out),
+            |start, out| self.worley.sample_row_3d(x + start as f32 * step, y, z, step, out),
+            Self::blend
+        )
+    }
+}
@@ -0,0 +1,81 @@
+use bevy::prelude::*;
+
+use super::NoiseGrad3D;
+
+// Shifts each component of the vector potential so the three are uncorrelated
+const POTENTIAL_OFFSET: [Vec3; 3] = [Vec3::new(0., 0., 0.), Vec3::new(31.4, 47.2, 12.9), Vec3::new(-27.6, 19.3, 53.1)];
+
+// Divergence-free velocity field: the curl of a vector potential whose components are three shifted copies
+// of `source` (Bridson et al., "Curl-Noise for Procedural Fluid Flow"). Anything moved by it swirls
+// without bunching up or thinning out, so clouds and particles can follow the same wind.
+#[derive(Clone)]
+pub struct Curl3D<S> {
+    pub source: S
+}
+
+impl<S: NoiseGrad3D> Curl3D<S> {
+    pub fn new(source: S) -> Curl3D<S> {
+        Curl3D { source }
+    }
+
+    pub fn sample_curl_3d(&self, x: f32, y: f32, z: f32) -> Vec3 {
+        let [a, b, c] = POTENTIAL_OFFSET.map(|offset| {
+            let p = Vec3::new(x, y, z) + offset;
+            self.source.sample_grad_3d(p.x, p.y, p.z).1
+        });
+        Vec3::new(c.y - b.z, a.z - c.x, b.x - a.y)
+    }
+
+    // Fills `out` in the same order as `NoiseSource3D::sample_grid_3d`
+    pub fn sample_curl_grid_3d(&self, origin: Vec3, step: Vec3, width: usize, height: usize, out: &mut [Vec3]) {
+        for (i, velocity) in out.iter_mut().enumerate() {
+            let (x, y, z) = (i % width, i / width % height, i / (width * height));
+            let p = origin + Vec3::new(x as f32, y as f32, z as f32) * step;
+            *velocity = self.sample_curl_3d(p.x, p.y, p.z);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::{Fractal, Perlin3D};
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    fn check_divergence<S: NoiseGrad3D>(name: &str, curl: &Curl3D<S>, rng: &mut impl Rng) {
+        const H: f32 = 1e-2;
+        for _ in 0..200 {
+            let p = Vec3::new(rng.gen_range(-20. ..20.), rng.gen_range(-20. ..20.), rng.gen_range(-20. ..20.));
+            // ∂v_i/∂x_i for each axis, which cancel out
+            let partials = Vec3::AXES.map(|axis| {
+                let (hi, lo) = (p + axis * H, p - axis * H);
+                (curl.sample_curl_3d(hi.x, hi.y, hi.z) - curl.sample_curl_3d(lo.x, lo.y, lo.z)).dot(axis) / (2. * H)
+            });
+            let divergence: f32 = partials.iter().sum();
+            let scale: f32 = partials.iter().map(|partial| partial.abs()).sum();
+            assert!(divergence.abs() < 1e-2 * scale.max(1.), "{}: divergence {} at {} from {:?}", name, divergence, p, partials);
+        }
+    }
+
+    #[test]
+    fn curl_is_divergence_free() {
+        let mut rng = ChaCha8Rng::seed_from_u64(12);
+        check_divergence("Perlin", &Curl3D::new(Perlin3D::new(&mut rng).period(4)), &mut rng);
+        // As the sky's wind is built
+        check_divergence("fbm", &Curl3D::new(Fractal::fbm(Perlin3D::new(&mut rng).period(4)).octaves(2).lacunarity(2.)), &mut rng);
+        check_divergence("untiled fbm", &Curl3D::new(Fractal::fbm(Perlin3D::new(&mut rng)).octaves(4).frequency(0.3)), &mut rng);
+    }
+
+    #[test]
+    fn grid_matches_single_samples() {
+        let curl = Curl3D::new(Fractal::fbm(Perlin3D::new(&mut ChaCha8Rng::seed_from_u64(13))).octaves(2));
+        let (origin, step, width, height) = (Vec3::new(-3.1, 0.4, 7.7), Vec3::new(0.3, 0.55, 0.7), 5, 3);
+        let mut grid = vec![Vec3::ZERO; width * height * 2];
+        curl.sample_curl_grid_3d(origin, step, width, height, &mut grid);
+        for (i, velocity) in grid.into_iter().enumerate() {
+            let p = origin + Vec3::new((i % width) as f32, (i / width % height) as f32, (i / (width * height)) as f32) * step;
+            assert_eq!(velocity, curl.sample_curl_3d(p.x, p.y, p.z), "Sample {}", i);
+        }
+    }
+}
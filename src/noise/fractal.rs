@@ -0,0 +1,181 @@
+use std::ops::{Add, Mul};
+
+use bevy::prelude::*;
+use serde::{Serialize, Deserialize};
+
+use super::{NoiseSource2D, NoiseSource3D, NoiseSource4D, NoiseGrad2D, NoiseGrad3D, CHUNK};
+
+// Shifts each octave so octaves sharing one source don't line up at the origin
+const OCTAVE_OFFSET: [f32; 4] = [12.9898, 78.233, 37.719, 93.989];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FractalKind {
+    // Plain sum of octaves
+    #[default]
+    Fbm,
+    // Sharp creases where the source crosses zero, each octave weighted by the one before it
+    Ridged,
+    // Rounded puffs, the absolute value of each octave
+    Billow
+}
+
+// Layers `octaves` copies of `source`, starting at `frequency` and `amplitude` and multiplying them
+// by `lacunarity` and `gain` for every following octave
+#[derive(Clone)]
+pub struct Fractal<S> {
+    pub source: S,
+    pub kind: FractalKind,
+    pub octaves: u32,
+    pub frequency: f32,
+    pub lacunarity: f32,
+    pub gain: f32,
+    pub amplitude: f32
+}
+
+impl<S> Fractal<S> {
+    pub fn new(kind: FractalKind, source: S) -> Fractal<S> {
+        Fractal { source, kind, octaves: 4, frequency: 1., lacunarity: 2., gain: 0.5, amplitude: 1. }
+    }
+
+    pub fn fbm(source: S) -> Fractal<S> {
+        Fractal::new(FractalKind::Fbm, source)
+    }
+
+    pub fn ridged(source: S) -> Fractal<S> {
+        Fractal::new(FractalKind::Ridged, source)
+    }
+
+    pub fn billow(source: S) -> Fractal<S> {
+        Fractal::new(FractalKind::Billow, source)
+    }
+
+    pub fn octaves(mut self, octaves: u32) -> Self {
+        self.octaves = octaves;
+        self
+    }
+
+    pub fn frequency(mut self, frequency: f32) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    pub fn lacunarity(mut self, lacunarity: f32) -> Self {
+        self.lacunarity = lacunarity;
+        self
+    }
+
+    pub fn gain(mut self, gain: f32) -> Self {
+        self.gain = gain;
+        self
+    }
+
+    pub fn amplitude(mut self, amplitude: f32) -> Self {
+        self.amplitude = amplitude;
+        self
+    }
+
+    // `octave` samples the source at the given frequency, shifted by the given octave offset, and returns
+    // the value with its gradient (or `0.` when the gradient isn't needed)
+    fn sum<G>(&self, octave: impl Fn(f32, [f32; 4]) -> (f32, G)) -> (f32, G)
+    where G: Copy + Default + Add<Output = G> + Mul<f32, Output = G> {
+        let (mut frequency, mut amplitude) = (self.frequency, self.amplitude);
+        let mut weight = (1., G::default());
+        let (mut sum, mut sum_grad) = (0., G::default());
+        for i in 0..self.octaves {
+            let offset = OCTAVE_OFFSET.map(|o| o * i as f32);
+            let (n, n_grad) = octave(frequency, offset);
+            // The source is sampled at p * frequency
+            let (value, grad) = self.shape(n, n_grad * frequency, &mut weight);
+            sum += amplitude * value;
+            sum_grad = sum_grad + grad * amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.gain;
+        }
+        (sum, sum_grad)
+    }
+
+    // Batched `sum` without gradients: `octave(frequency, offset, start, buffer)` fills a stack buffer with
+    // the octave's samples from index `start` of the row on, one chunk at a time
+    fn sum_row(&self, out: &mut [f32], octave: impl Fn(f32, [f32; 4], usize, &mut [f32])) {
+        let (mut octave_buf, mut weight_buf) = ([0.; CHUNK], [0.; CHUNK]);
+        for (chunk_idx, chunk) in out.chunks_mut(CHUNK).enumerate() {
+            let (n, weights) = (&mut octave_buf[..chunk.len()], &mut weight_buf[..chunk.len()]);
+            chunk.fill(0.);
+            weights.fill(1.);
+            let (mut frequency, mut amplitude) = (self.frequency, self.amplitude);
+            for i in 0..self.octaves {
+                octave(frequency, OCTAVE_OFFSET.map(|o| o * i as f32), chunk_idx * CHUNK, n);
+                for ((sum, &n), weight) in chunk.iter_mut().zip(n.iter()).zip(weights.iter_mut()) {
+                    let mut octave_weight = (*weight, 0.);
+                    *sum += amplitude * self.shape(n, 0., &mut octave_weight).0;
+                    *weight = octave_weight.0;
+                }
+                frequency *= self.lacunarity;
+                amplitude *= self.gain;
+            }
+        }
+    }
+
+    // Applies the fractal kind to one octave's value and gradient. `weight` (with its gradient) carries the
+    // ridged weighting over to the next octave.
+    fn shape<G>(&self, n: f32, n_grad: G, weight: &mut (f32, G)) -> (f32, G)
+    where G: Copy + Default + Add<Output = G> + Mul<f32, Output = G> {
+        match self.kind {
+            FractalKind::Fbm => (n, n_grad),
+            FractalKind::Ridged => {
+                let (ridge, ridge_grad) = (1. - n.abs(), n_grad * -n.signum());
+                let signal = ridge * ridge * weight.0;
+                let signal_grad = ridge_grad * (2. * ridge * weight.0) + weight.1 * (ridge * ridge);
+                *weight = if signal * 2. >= 1. {
+                    (1., G::default())
+                } else {
+                    (signal * 2., signal_grad * 2.)
+                };
+                (signal * 2. - 1., signal_grad * 2.)
+            },
+            FractalKind::Billow => (n.abs() * 2. - 1., n_grad * (2. * n.signum()))
+        }
+    }
+}
+
+impl<S: NoiseSource2D> NoiseSource2D for Fractal<S> {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        self.sum(|f, o| (self.source.sample_2d(x * f + o[0], y * f + o[1]), 0.)).0
+    }
+
+    fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+        self.sum_row(out, |f, o, start, out| {
+            self.source.sample_row_2d((x + start as f32 * step) * f + o[0], y * f + o[1], step * f, out)
+        })
+    }
+}
+
+impl<S: NoiseSource3D> NoiseSource3D for Fractal<S> {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        self.sum(|f, o| (self.source.sample_3d(x * f + o[0], y * f + o[1], z * f + o[2]), 0.)).0
+    }
+
+    fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+        self.sum_row(out, |f, o, start, out| {
+            self.source.sample_row_3d((x + start as f32 * step) * f + o[0], y * f + o[1], z * f + o[2], step * f, out)
+        })
+    }
+}
+
+impl<S: NoiseSource4D> NoiseSource4D for Fractal<S> {
+    fn sample_4d(&self, x: f32, y: f32, z: f32, w: f32) -> f32 {
+        self.sum(|f, o| (self.source.sample_4d(x * f + o[0], y * f + o[1], z * f + o[2], w * f + o[3]), 0.)).0
+    }
+}
+
+impl<S: NoiseGrad2D> NoiseGrad2D for Fractal<S> {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        self.sum(|f, o| self.source.sample_grad_2d(x * f + o[0], y * f + o[1]))
+    }
+}
+
+impl<S: NoiseGrad3D> NoiseGrad3D for Fractal<S> {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+        self.sum(|f, o| self.source.sample_grad_3d(x * f + o[0], y * f + o[1], z * f + o[2]))
+    }
+}
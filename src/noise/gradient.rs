@@ -0,0 +1,218 @@
+use std::{str::FromStr, fmt};
+
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+use super::{NoiseSource2D, NoiseSource3D, NoiseGrad2D, NoiseGrad3D, Perlin2D, Perlin3D, OpenSimplex2};
+
+// Which gradient noise algorithm a layer is built from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoiseKind {
+    #[default]
+    Perlin,
+    OpenSimplex2
+}
+
+impl NoiseKind {
+    // Whether noise of this kind can be made to tile with `Gradient2D::period` and `Gradient3D::period`
+    pub fn tiles(self) -> bool {
+        self == NoiseKind::Perlin
+    }
+}
+
+impl FromStr for NoiseKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "perlin" => Ok(NoiseKind::Perlin),
+            "opensimplex2" | "open-simplex2" | "simplex" => Ok(NoiseKind::OpenSimplex2),
+            _ => Err(format!("Unknown noise kind '{}', expected 'perlin' or 'opensimplex2'", s))
+        }
+    }
+}
+
+impl fmt::Display for NoiseKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoiseKind::Perlin => write!(f, "perlin"),
+            NoiseKind::OpenSimplex2 => write!(f, "opensimplex2")
+        }
+    }
+}
+
+// A 2D gradient noise whose algorithm is picked at runtime
+pub enum Gradient2D {
+    Perlin(Perlin2D),
+    OpenSimplex2(OpenSimplex2)
+}
+
+impl Gradient2D {
+    pub fn new(kind: NoiseKind, rng: &mut impl Rng) -> Gradient2D {
+        match kind {
+            NoiseKind::Perlin => Gradient2D::Perlin(Perlin2D::new(rng)),
+            NoiseKind::OpenSimplex2 => Gradient2D::OpenSimplex2(OpenSimplex2::new(rng))
+        }
+    }
+
+    // Tiles every `period` units. Only Perlin noise can tile: the OpenSimplex2 lattice is skewed, so it
+    // doesn't line up with an axis-aligned period.
+    pub fn period(self, period: u32) -> Result<Self, String> {
+        match self {
+            Gradient2D::Perlin(noise) => Ok(Gradient2D::Perlin(noise.period(period))),
+            Gradient2D::OpenSimplex2(_) => Err(format!("{} noise can't tile, so it can't have a period", NoiseKind::OpenSimplex2))
+        }
+    }
+}
+
+impl NoiseSource2D for Gradient2D {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        match self {
+            Gradient2D::Perlin(noise) => noise.sample_2d(x, y),
+            Gradient2D::OpenSimplex2(noise) => noise.sample_2d(x, y)
+        }
+    }
+
+    fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+        match self {
+            Gradient2D::Perlin(noise) => noise.sample_row_2d(x, y, step, out),
+            Gradient2D::OpenSimplex2(noise) => noise.sample_row_2d(x, y, step, out)
+        }
+    }
+}
+
+impl NoiseGrad2D for Gradient2D {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        match self {
+            Gradient2D::Perlin(noise) => noise.sample_grad_2d(x, y),
+            Gradient2D::OpenSimplex2(noise) => noise.sample_grad_2d(x, y)
+        }
+    }
+}
+
+pub enum Gradient3D {
+    Perlin(Perlin3D),
+    OpenSimplex2(OpenSimplex2)
+}
+
+impl Gradient3D {
+    pub fn new(kind: NoiseKind, rng: &mut impl Rng) -> Gradient3D {
+        match kind {
+            NoiseKind::Perlin => Gradient3D::Perlin(Perlin3D::new(rng)),
+            NoiseKind::OpenSimplex2 => Gradient3D::OpenSimplex2(OpenSimplex2::new(rng))
+        }
+    }
+
+    // Tiles every `period` units. Only Perlin noise can tile: the OpenSimplex2 lattice is skewed, so it
+    // doesn't line up with an axis-aligned period.
+    pub fn period(self, period: u32) -> Result<Self, String> {
+        match self {
+            Gradient3D::Perlin(noise) => Ok(Gradient3D::Perlin(noise.period(period))),
+            Gradient3D::OpenSimplex2(_) => Err(format!("{} noise can't tile, so it can't have a period", NoiseKind::OpenSimplex2))
+        }
+    }
+}
+
+impl NoiseSource3D for Gradient3D {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        match self {
+            Gradient3D::Perlin(noise) => noise.sample_3d(x, y, z),
+            Gradient3D::OpenSimplex2(noise) => noise.sample_3d(x, y, z)
+        }
+    }
+
+    fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+        match self {
+            Gradient3D::Perlin(noise) => noise.sample_row_3d(x, y, z, step, out),
+            Gradient3D::OpenSimplex2(noise) => noise.sample_row_3d(x, y, z, step, out)
+        }
+    }
+}
+
+impl NoiseGrad3D for Gradient3D {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+        match self {
+            Gradient3D::Perlin(noise) => noise.sample_grad_3d(x, y, z),
+            Gradient3D::OpenSimplex2(noise) => noise.sample_grad_3d(x, y, z)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::Fractal;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    const H: f32 = 1e-3;
+
+    // Checks analytic gradients against central differences at points spread over a few lattice cells.
+    // Points where the one-sided differences disagree, at the creases of ridged noise (where an octave
+    // crosses zero) or where the noise bends sharply, are skipped, as long as more than half are checked.
+    fn check<const N: usize>(name: &str, sample: impl Fn([f32; N]) -> f32, sample_grad: impl Fn([f32; N]) -> (f32, [f32; N])) {
+        let mut checked = 0;
+        for i in 0..400 {
+            let p: [f32; N] = std::array::from_fn(|axis| ((i * (axis + 3) * 37) % 401) as f32 * 0.0371 - 7.3);
+            let (value, gradient) = sample_grad(p);
+            assert!((value - sample(p)).abs() < 1e-5, "{}: value at {:?}", name, p);
+            let tolerance = |axis: usize| 2e-2 * gradient[axis].abs().max(1.);
+            let mut smooth = true;
+            let mut differences = [0.; N];
+            for axis in 0..N {
+                let (mut lo, mut hi) = (p, p);
+                lo[axis] -= H;
+                hi[axis] += H;
+                let (lo, hi) = (sample(lo), sample(hi));
+                smooth &= ((hi - value) / H - (value - lo) / H).abs() < tolerance(axis);
+                differences[axis] = (hi - lo) / (2. * H);
+            }
+            if !smooth {
+                continue;
+            }
+            for axis in 0..N {
+                let tolerance = tolerance(axis);
+                assert!((gradient[axis] - differences[axis]).abs() < tolerance, "{}: at {:?}, {:?} vs {:?}", name, p, gradient, differences);
+            }
+            checked += 1;
+        }
+        assert!(checked > 200, "{}: only {} points checked", name, checked);
+    }
+
+    fn check_2d(name: &str, noise: &impl NoiseGrad2D) {
+        check(name, |[x, y]| noise.sample_2d(x, y), |[x, y]| {
+            let (value, gradient) = noise.sample_grad_2d(x, y);
+            (value, gradient.to_array())
+        });
+    }
+
+    fn check_3d(name: &str, noise: &impl NoiseGrad3D) {
+        check(name, |[x, y, z]| noise.sample_3d(x, y, z), |[x, y, z]| {
+            let (value, gradient) = noise.sample_grad_3d(x, y, z);
+            (value, gradient.to_array())
+        });
+    }
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        let mut rng = ChaCha8Rng::seed_from_u64(6);
+        for kind in [NoiseKind::Perlin, NoiseKind::OpenSimplex2] {
+            check_2d(&format!("{} 2D", kind), &Gradient2D::new(kind, &mut rng));
+            check_2d(&format!("{} 2D fbm", kind), &Fractal::fbm(Gradient2D::new(kind, &mut rng)).octaves(4).frequency(0.25));
+            check_2d(&format!("{} 2D ridged", kind), &Fractal::ridged(Gradient2D::new(kind, &mut rng)).octaves(4).frequency(0.25));
+            check_3d(&format!("{} 3D", kind), &Gradient3D::new(kind, &mut rng));
+            check_3d(&format!("{} 3D fbm", kind), &Fractal::fbm(Gradient3D::new(kind, &mut rng)).octaves(4).frequency(0.25));
+            check_3d(&format!("{} 3D ridged", kind), &Fractal::ridged(Gradient3D::new(kind, &mut rng)).octaves(4).frequency(0.25));
+        }
+    }
+
+    #[test]
+    fn only_perlin_noise_can_have_a_period() {
+        let mut rng = ChaCha8Rng::seed_from_u64(4);
+        assert!(Gradient2D::new(NoiseKind::Perlin, &mut rng).period(4).is_ok());
+        assert!(Gradient3D::new(NoiseKind::Perlin, &mut rng).period(4).is_ok());
+        assert!(Gradient2D::new(NoiseKind::OpenSimplex2, &mut rng).period(4).is_err());
+        assert!(Gradient3D::new(NoiseKind::OpenSimplex2, &mut rng).period(4).is_err());
+    }
+}
@@ -0,0 +1,28 @@
+// Integer lattice hashing shared by the hash-based generators. Lattice coordinates are multiplied by
+// large odd primes and mixed with the seed, so no gradient table has to be stored.
+
+pub const PRIME_X: u64 = 0x5205402B9270C86F;
+pub const PRIME_Y: u64 = 0x598CD327003817B5;
+pub const PRIME_Z: u64 = 0x5BCC226E9FA0BACB;
+pub const PRIME_W: u64 = 0x56CC5227E58F554B;
+const HASH_MULTIPLIER: u64 = 0x53A3F72DEEC546F5;
+
+// Coordinates are expected to be pre-multiplied by their primes
+pub fn hash(seed: u64, coords: &[u64]) -> u64 {
+    let h = coords.iter().fold(seed, |h, &c| h ^ c).wrapping_mul(HASH_MULTIPLIER);
+    h ^ (h >> 32)
+}
+
+// Lattice coordinate premultiplied by its prime, wrapped first when the noise tiles every `period` cells
+pub fn lattice(c: i64, prime: u64, period: Option<u32>) -> u64 {
+    let c = match period {
+        Some(period) => c.rem_euclid(period as i64),
+        None => c
+    };
+    (c as u64).wrapping_mul(prime)
+}
+
+// Picks one of `count` entries from a hash
+pub fn index(hash: u64, count: usize) -> usize {
+    ((hash >> 24) % count as u64) as usize
+}
@@ -0,0 +1,274 @@
+use std::{sync::Arc, rc::Rc};
+
+use bevy::prelude::*;
+
+mod hash;
+mod perlin;
+mod simplex;
+mod worley;
+mod gradient;
+mod fractal;
+mod modifier;
+mod combine;
+mod warp;
+mod curl;
+mod recipe;
+
+// Samples per stack buffer when a combinator needs scratch space for batched sampling
+const CHUNK: usize = 64;
+
+pub use perlin::{Perlin2D, Perlin3D};
+pub use simplex::OpenSimplex2;
+pub use worley::{Worley, WorleyReturn};
+pub use gradient::{NoiseKind, Gradient2D, Gradient3D};
+pub use fractal::{Fractal, FractalKind};
+pub use modifier::{ScaleBias, Clamp, Terrace, Translate};
+pub use combine::{Add, Mul, Select, PerlinWorley};
+pub use warp::DomainWarp;
+pub use curl::Curl3D;
+pub use recipe::{NoiseNode, NodeExtension, NoExtension, BoxedNoise2D};
+
+// Batched sampling writes into a caller-provided buffer and never allocates. The defaults fall back to one
+// sample at a time; sources override the row methods when neighbouring samples can share work.
+pub trait NoiseSource2D {
+    fn sample_2d(&self, x: f32, y: f32) -> f32;
+
+    // Fills `out` with the samples at (x + i * step, y)
+    fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+        for (i, value) in out.iter_mut().enumerate() {
+            *value = self.sample_2d(x + i as f32 * step, y);
+        }
+    }
+
+    // Fills `out` row by row with the samples at origin + (i, j) * step, `width` samples per row
+    fn sample_grid_2d(&self, origin: Vec2, step: Vec2, width: usize, out: &mut [f32]) {
+        for (j, row) in out.chunks_mut(width).enumerate() {
+            self.sample_row_2d(origin.x, origin.y + j as f32 * step.y, step.x, row);
+        }
+    }
+}
+
+pub trait NoiseSource3D {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32;
+
+    // Fills `out` with the samples at (x + i * step, y, z)
+    fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+        for (i, value) in out.iter_mut().enumerate() {
+            *value = self.sample_3d(x + i as f32 * step, y, z);
+        }
+    }
+
+    // Fills `out` slice by slice along z, each slice row by row, with the samples at origin + (i, j, k) * step
+    fn sample_grid_3d(&self, origin: Vec3, step: Vec3, width: usize, height: usize, out: &mut [f32]) {
+        for (k, slice) in out.chunks_mut(width * height).enumerate() {
+            for (j, row) in slice.chunks_mut(width).enumerate() {
+                let (y, z) = (origin.y + j as f32 * step.y, origin.z + k as f32 * step.z);
+                self.sample_row_3d(origin.x, y, z, step.x, row);
+            }
+        }
+    }
+}
+
+pub trait NoiseSource4D {
+    fn sample_4d(&self, x: f32, y: f32, z: f32, w: f32) -> f32;
+}
+
+// Sources that can return their analytic gradient along with the value, so callers like terrain normals
+// don't need finite differences or smoothing
+pub trait NoiseGrad2D: NoiseSource2D {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2);
+}
+
+pub trait NoiseGrad3D: NoiseSource3D {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3);
+}
+
+// Lets plain closures (including ones sampling other sources) be used anywhere a source is expected
+#[derive(Clone, Copy)]
+pub struct FromFn<F>(pub F);
+
+impl<F: Fn(f32, f32) -> f32> NoiseSource2D for FromFn<F> {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        (self.0)(x, y)
+    }
+}
+
+impl<F: Fn(f32, f32, f32) -> f32> NoiseSource3D for FromFn<F> {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        (self.0)(x, y, z)
+    }
+}
+
+impl<F: Fn(f32, f32, f32, f32) -> f32> NoiseSource4D for FromFn<F> {
+    fn sample_4d(&self, x: f32, y: f32, z: f32, w: f32) -> f32 {
+        (self.0)(x, y, z, w)
+    }
+}
+
+// Constant value, mostly useful as an operand of Add/Mul/Select
+#[derive(Clone, Copy)]
+pub struct Constant(pub f32);
+
+impl NoiseSource2D for Constant {
+    fn sample_2d(&self, _x: f32, _y: f32) -> f32 {
+        self.0
+    }
+
+    fn sample_row_2d(&self, _x: f32, _y: f32, _step: f32, out: &mut [f32]) {
+        out.fill(self.0);
+    }
+}
+
+impl NoiseSource3D for Constant {
+    fn sample_3d(&self, _x: f32, _y: f32, _z: f32) -> f32 {
+        self.0
+    }
+
+    fn sample_row_3d(&self, _x: f32, _y: f32, _z: f32, _step: f32, out: &mut [f32]) {
+        out.fill(self.0);
+    }
+}
+
+impl NoiseSource4D for Constant {
+    fn sample_4d(&self, _x: f32, _y: f32, _z: f32, _w: f32) -> f32 {
+        self.0
+    }
+}
+
+impl NoiseGrad2D for Constant {
+    fn sample_grad_2d(&self, _x: f32, _y: f32) -> (f32, Vec2) {
+        (self.0, Vec2::ZERO)
+    }
+}
+
+impl NoiseGrad3D for Constant {
+    fn sample_grad_3d(&self, _x: f32, _y: f32, _z: f32) -> (f32, Vec3) {
+        (self.0, Vec3::ZERO)
+    }
+}
+
+macro_rules! impl_pointer_sources {
+    ($($ptr:ty),*) => {
+        $(
+            impl<S: NoiseSource2D + ?Sized> NoiseSource2D for $ptr {
+                fn sample_2d(&self, x: f32, y: f32) -> f32 {
+                    (**self).sample_2d(x, y)
+                }
+
+                fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+                    (**self).sample_row_2d(x, y, step, out)
+                }
+
+                fn sample_grid_2d(&self, origin: Vec2, step: Vec2, width: usize, out: &mut [f32]) {
+                    (**self).sample_grid_2d(origin, step, width, out)
+                }
+            }
+
+            impl<S: NoiseSource3D + ?Sized> NoiseSource3D for $ptr {
+                fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+                    (**self).sample_3d(x, y, z)
+                }
+
+                fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+                    (**self).sample_row_3d(x, y, z, step, out)
+                }
+
+                fn sample_grid_3d(&self, origin: Vec3, step: Vec3, width: usize, height: usize, out: &mut [f32]) {
+                    (**self).sample_grid_3d(origin, step, width, height, out)
+                }
+            }
+
+            impl<S: NoiseSource4D + ?Sized> NoiseSource4D for $ptr {
+                fn sample_4d(&self, x: f32, y: f32, z: f32, w: f32) -> f32 {
+                    (**self).sample_4d(x, y, z, w)
+                }
+            }
+
+            impl<S: NoiseGrad2D + ?Sized> NoiseGrad2D for $ptr {
+                fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+                    (**self).sample_grad_2d(x, y)
+                }
+            }
+
+            impl<S: NoiseGrad3D + ?Sized> NoiseGrad3D for $ptr {
+                fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+                    (**self).sample_grad_3d(x, y, z)
+                }
+            }
+        )*
+    };
+}
+
+impl_pointer_sources!(&S, Box<S>, Rc<S>, Arc<S>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    // Rows that don't fill a Vec4 or a CHUNK, as well as ones that cross chunk boundaries
+    const WIDTHS: [usize; 5] = [1, 3, 7, 67, 131];
+    // Batched rows step on from the start of each chunk, so positions can differ by rounding, which the
+    // higher octaves of ridged noise magnify
+    const TOLERANCE: f32 = 1e-4;
+
+    fn check_2d(name: &str, noise: &impl NoiseSource2D) {
+        let (origin, step) = (Vec2::new(-13.3, 5.7), Vec2::new(0.37, 0.61));
+        for width in WIDTHS {
+            let height = 3;
+            let mut grid = vec![0.; width * height];
+            noise.sample_grid_2d(origin, step, width, &mut grid);
+            for (i, &value) in grid.iter().enumerate() {
+                let p = origin + Vec2::new((i % width) as f32, (i / width) as f32) * step;
+                let expected = noise.sample_2d(p.x, p.y);
+                assert!((value - expected).abs() < TOLERANCE, "{}: {} at {} of a {}-wide grid, expected {}", name, value, i, width, expected);
+            }
+        }
+    }
+
+    fn check_3d(name: &str, noise: &impl NoiseSource3D) {
+        let (origin, step) = (Vec3::new(-13.3, 5.7, 2.2), Vec3::new(0.37, 0.61, 0.53));
+        for width in WIDTHS {
+            let (height, depth) = (3, 2);
+            let mut grid = vec![0.; width * height * depth];
+            noise.sample_grid_3d(origin, step, width, height, &mut grid);
+            for (i, &value) in grid.iter().enumerate() {
+                let p = origin + Vec3::new((i % width) as f32, (i / width % height) as f32, (i / (width * height)) as f32) * step;
+                let expected = noise.sample_3d(p.x, p.y, p.z);
+                assert!((value - expected).abs() < TOLERANCE, "{}: {} at {} of a {}-wide grid, expected {}", name, value, i, width, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn batched_sampling_matches_single_samples() {
+        let mut rng = ChaCha8Rng::seed_from_u64(6);
+        check_2d("Perlin", &Perlin2D::new(&mut rng));
+        check_2d("tiled Perlin", &Perlin2D::new(&mut rng).period(3));
+        check_2d("fbm", &Fractal::fbm(Perlin2D::new(&mut rng)).octaves(4).frequency(0.3));
+        check_2d("ridged", &Fractal::ridged(Perlin2D::new(&mut rng).period(5)).octaves(3));
+        check_2d("Add", &Add(Perlin2D::new(&mut rng), Fractal::fbm(Perlin2D::new(&mut rng)).octaves(2)));
+        // Narrow enough a falloff that some chunks only need one side
+        check_2d("Select", &Select::new(
+            Fractal::fbm(Perlin2D::new(&mut rng)).frequency(0.2),
+            Perlin2D::new(&mut rng),
+            Fractal::ridged(Perlin2D::new(&mut rng)).octaves(2),
+            0.,
+            0.05
+        ));
+
+        check_3d("Perlin", &Perlin3D::new(&mut rng));
+        check_3d("tiled Perlin", &Perlin3D::new(&mut rng).period(3));
+        check_3d("fbm", &Fractal::fbm(Perlin3D::new(&mut rng)).octaves(4).frequency(0.3));
+        check_3d("ridged", &Fractal::ridged(Perlin3D::new(&mut rng).period(5)).octaves(3));
+        check_3d("Add", &Add(Perlin3D::new(&mut rng), Fractal::fbm(Perlin3D::new(&mut rng)).octaves(2)));
+        check_3d("Select", &Select::new(
+            Fractal::fbm(Perlin3D::new(&mut rng)).frequency(0.2),
+            Perlin3D::new(&mut rng),
+            Fractal::ridged(Perlin3D::new(&mut rng)).octaves(2),
+            0.,
+            0.05
+        ));
+    }
+}
@@ -0,0 +1,226 @@
+use bevy::prelude::*;
+
+use super::{NoiseSource2D, NoiseSource3D, NoiseGrad2D, NoiseGrad3D};
+
+// source * scale + bias
+#[derive(Clone)]
+pub struct ScaleBias<S> {
+    pub source: S,
+    pub scale: f32,
+    pub bias: f32
+}
+
+impl<S> ScaleBias<S> {
+    pub fn new(source: S, scale: f32, bias: f32) -> ScaleBias<S> {
+        ScaleBias { source, scale, bias }
+    }
+}
+
+impl<S: NoiseSource2D> NoiseSource2D for ScaleBias<S> {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        self.source.sample_2d(x, y) * self.scale + self.bias
+    }
+
+    fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+        self.source.sample_row_2d(x, y, step, out);
+        out.iter_mut().for_each(|value| *value = *value * self.scale + self.bias);
+    }
+}
+
+impl<S: NoiseSource3D> NoiseSource3D for ScaleBias<S> {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        self.source.sample_3d(x, y, z) * self.scale + self.bias
+    }
+
+    fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+        self.source.sample_row_3d(x, y, z, step, out);
+        out.iter_mut().for_each(|value| *value = *value * self.scale + self.bias);
+    }
+}
+
+impl<S: NoiseGrad2D> NoiseGrad2D for ScaleBias<S> {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let (value, grad) = self.source.sample_grad_2d(x, y);
+        (value * self.scale + self.bias, grad * self.scale)
+    }
+}
+
+impl<S: NoiseGrad3D> NoiseGrad3D for ScaleBias<S> {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+        let (value, grad) = self.source.sample_grad_3d(x, y, z);
+        (value * self.scale + self.bias, grad * self.scale)
+    }
+}
+
+#[derive(Clone)]
+pub struct Clamp<S> {
+    pub source: S,
+    pub min: f32,
+    pub max: f32
+}
+
+impl<S> Clamp<S> {
+    pub fn new(source: S, min: f32, max: f32) -> Clamp<S> {
+        Clamp { source, min, max }
+    }
+
+    // Flat wherever the value is clamped
+    fn map_grad<G: Default>(&self, value: f32, grad: G) -> (f32, G) {
+        if value < self.min || value > self.max {
+            (value.clamp(self.min, self.max), G::default())
+        } else {
+            (value, grad)
+        }
+    }
+}
+
+impl<S: NoiseSource2D> NoiseSource2D for Clamp<S> {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        self.source.sample_2d(x, y).clamp(self.min, self.max)
+    }
+
+    fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+        self.source.sample_row_2d(x, y, step, out);
+        out.iter_mut().for_each(|value| *value = value.clamp(self.min, self.max));
+    }
+}
+
+impl<S: NoiseSource3D> NoiseSource3D for Clamp<S> {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        self.source.sample_3d(x, y, z).clamp(self.min, self.max)
+    }
+
+    fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+        self.source.sample_row_3d(x, y, z, step, out);
+        out.iter_mut().for_each(|value| *value = value.clamp(self.min, self.max));
+    }
+}
+
+impl<S: NoiseGrad2D> NoiseGrad2D for Clamp<S> {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let (value, grad) = self.source.sample_grad_2d(x, y);
+        self.map_grad(value, grad)
+    }
+}
+
+impl<S: NoiseGrad3D> NoiseGrad3D for Clamp<S> {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+        let (value, grad) = self.source.sample_grad_3d(x, y, z);
+        self.map_grad(value, grad)
+    }
+}
+
+// Flattens the source into steps at the given (strictly increasing) heights, easing into each step from below
+#[derive(Clone)]
+pub struct Terrace<S> {
+    pub source: S,
+    pub points: Vec<f32>
+}
+
+impl<S> Terrace<S> {
+    pub fn new(source: S, points: Vec<f32>) -> Terrace<S> {
+        assert!(points.len() >= 2, "Terrace needs at least two points");
+        // Each step divides by the distance between its points
+        assert!(points.windows(2).all(|pair| pair[0] < pair[1]), "Terrace points must be increasing, got {:?}", points);
+        Terrace { source, points }
+    }
+
+    fn map(&self, value: f32) -> f32 {
+        self.map_with_slope(value).0
+    }
+
+    // Mapped value and its derivative with respect to the input value
+    fn map_with_slope(&self, value: f32) -> (f32, f32) {
+        let upper = self.points.partition_point(|&p| p <= value).clamp(1, self.points.len() - 1);
+        let (p0, p1) = (self.points[upper - 1], self.points[upper]);
+        let t = (value - p0) / (p1 - p0);
+        if !(0. ..=1.).contains(&t) {
+            return (p0 + (p1 - p0) * t.clamp(0., 1.), 0.);
+        }
+        (p0 + (p1 - p0) * t * t, 2. * t)
+    }
+}
+
+impl<S: NoiseSource2D> NoiseSource2D for Terrace<S> {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        self.map(self.source.sample_2d(x, y))
+    }
+
+    fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+        self.source.sample_row_2d(x, y, step, out);
+        out.iter_mut().for_each(|value| *value = self.map(*value));
+    }
+}
+
+impl<S: NoiseSource3D> NoiseSource3D for Terrace<S> {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        self.map(self.source.sample_3d(x, y, z))
+    }
+
+    fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+        self.source.sample_row_3d(x, y, z, step, out);
+        out.iter_mut().for_each(|value| *value = self.map(*value));
+    }
+}
+
+impl<S: NoiseGrad2D> NoiseGrad2D for Terrace<S> {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let (value, grad) = self.source.sample_grad_2d(x, y);
+        let (value, slope) = self.map_with_slope(value);
+        (value, grad * slope)
+    }
+}
+
+impl<S: NoiseGrad3D> NoiseGrad3D for Terrace<S> {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+        let (value, grad) = self.source.sample_grad_3d(x, y, z);
+        let (value, slope) = self.map_with_slope(value);
+        (value, grad * slope)
+    }
+}
+
+// Moves the sampling point, so the same source can be reused without lining up with itself.
+// 2D sources use only the x and y components.
+#[derive(Clone)]
+pub struct Translate<S> {
+    pub source: S,
+    pub offset: Vec3
+}
+
+impl<S> Translate<S> {
+    pub fn new(source: S, offset: Vec3) -> Translate<S> {
+        Translate { source, offset }
+    }
+}
+
+impl<S: NoiseSource2D> NoiseSource2D for Translate<S> {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        self.source.sample_2d(x + self.offset.x, y + self.offset.y)
+    }
+
+    fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+        self.source.sample_row_2d(x + self.offset.x, y + self.offset.y, step, out)
+    }
+}
+
+impl<S: NoiseSource3D> NoiseSource3D for Translate<S> {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        self.source.sample_3d(x + self.offset.x, y + self.offset.y, z + self.offset.z)
+    }
+
+    fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+        self.source.sample_row_3d(x + self.offset.x, y + self.offset.y, z + self.offset.z, step, out)
+    }
+}
+
+impl<S: NoiseGrad2D> NoiseGrad2D for Translate<S> {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        self.source.sample_grad_2d(x + self.offset.x, y + self.offset.y)
+    }
+}
+
+impl<S: NoiseGrad3D> NoiseGrad3D for Translate<S> {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+        self.source.sample_grad_3d(x + self.offset.x, y + self.offset.y, z + self.offset.z)
+    }
+}
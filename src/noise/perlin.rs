@@ -0,0 +1,293 @@
+use bevy::prelude::*;
+use rand::Rng;
+
+use super::{NoiseSource2D, NoiseSource3D, NoiseGrad2D, NoiseGrad3D, hash::{self, PRIME_X, PRIME_Y, PRIME_Z}};
+
+// Gradients are picked by hashing the lattice point, so there's no table to store and the noise never
+// repeats unless given a period
+
+// 16 unit directions, 22.5 degrees apart
+const GRADIENTS_2D: [Vec2; 16] = [
+    Vec2::new(0.98078525, 0.19509032), Vec2::new(0.8314696, 0.55557024),
+    Vec2::new(0.55557024, 0.8314696), Vec2::new(0.19509032, 0.98078525),
+    Vec2::new(-0.19509032, 0.98078525), Vec2::new(-0.55557024, 0.8314696),
+    Vec2::new(-0.8314696, 0.55557024), Vec2::new(-0.98078525, 0.19509032),
+    Vec2::new(-0.98078525, -0.19509032), Vec2::new(-0.8314696, -0.55557024),
+    Vec2::new(-0.55557024, -0.8314696), Vec2::new(-0.19509032, -0.98078525),
+    Vec2::new(0.19509032, -0.98078525), Vec2::new(0.55557024, -0.8314696),
+    Vec2::new(0.8314696, -0.55557024), Vec2::new(0.98078525, -0.19509032),
+];
+
+// Cube edge midpoints, normalized
+const E: f32 = std::f32::consts::FRAC_1_SQRT_2;
+const GRADIENTS_3D: [Vec3; 12] = [
+    Vec3::new(E, E, 0.), Vec3::new(-E, E, 0.), Vec3::new(E, -E, 0.), Vec3::new(-E, -E, 0.),
+    Vec3::new(E, 0., E), Vec3::new(-E, 0., E), Vec3::new(E, 0., -E), Vec3::new(-E, 0., -E),
+    Vec3::new(0., E, E), Vec3::new(0., -E, E), Vec3::new(0., E, -E), Vec3::new(0., -E, -E),
+];
+
+fn smoothstep(x: f32) -> f32 {
+    x * x * x * (x * (x * 6. - 15.) + 10.)
+}
+
+fn smoothstep_derivative(x: f32) -> f32 {
+    30. * x * x * (x - 1.) * (x - 1.)
+}
+
+fn smoothstep_4(x: Vec4) -> Vec4 {
+    x * x * x * (x * (x * 6. - 15.) + 10.)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+// The lattice points on either side of a coordinate (premultiplied by `prime`) and the fraction past the
+// lower one
+fn cell(c: f32, prime: u64, period: Option<u32>) -> ([u64; 2], f32) {
+    let base = c.floor();
+    let i = base as i64;
+    ([hash::lattice(i, prime, period), hash::lattice(i + 1, prime, period)], c - base)
+}
+
+// `cell` for four coordinates at once, with the lattice points of each lane
+fn cell_4(c: Vec4, prime: u64, period: Option<u32>) -> ([[u64; 2]; 4], Vec4) {
+    let base = c.floor();
+    let lattice = base.to_array().map(|b| {
+        let i = b as i64;
+        [hash::lattice(i, prime, period), hash::lattice(i + 1, prime, period)]
+    });
+    (lattice, c - base)
+}
+
+// Classic gradient noise on the integer lattice, roughly within [-0.7, 0.7]
+#[derive(Clone, Copy, Debug)]
+pub struct Perlin2D {
+    seed: u64,
+    // Wraps the lattice so the noise tiles every `period` units
+    pub period: Option<u32>
+}
+
+impl Perlin2D {
+    pub fn new(rng: &mut impl Rng) -> Perlin2D {
+        Perlin2D { seed: rng.gen(), period: None }
+    }
+
+    pub fn period(mut self, period: u32) -> Self {
+        self.period = Some(period);
+        self
+    }
+
+    fn gradient(&self, lx: u64, ly: u64) -> Vec2 {
+        GRADIENTS_2D[hash::index(hash::hash(self.seed, &[lx, ly]), GRADIENTS_2D.len())]
+    }
+}
+
+impl NoiseSource2D for Perlin2D {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        let ([x0, x1], fx) = cell(x, PRIME_X, self.period);
+        let ([y0, y1], fy) = cell(y, PRIME_Y, self.period);
+        let a = self.gradient(x0, y0).dot(Vec2::new(fx, fy));
+        let b = self.gradient(x1, y0).dot(Vec2::new(fx - 1., fy));
+        let c = self.gradient(x0, y1).dot(Vec2::new(fx, fy - 1.));
+        let d = self.gradient(x1, y1).dot(Vec2::new(fx - 1., fy - 1.));
+        let (u, v) = (smoothstep(fx), smoothstep(fy));
+        lerp(lerp(a, b, u), lerp(c, d, u), v)
+    }
+
+    // Four samples at a time: y is shared by the whole row, and the blend runs on 4-wide vectors
+    fn sample_row_2d(&self, x: f32, y: f32, step: f32, out: &mut [f32]) {
+        let ([y0, y1], fy) = cell(y, PRIME_Y, self.period);
+        let v = smoothstep(fy);
+        let lanes = Vec4::new(0., 1., 2., 3.);
+        let done = out.len() / 4 * 4;
+        for (chunk_idx, chunk) in out.chunks_exact_mut(4).enumerate() {
+            let xs = Vec4::splat(x) + (lanes + Vec4::splat((chunk_idx * 4) as f32)) * step;
+            let (lattice, fx) = cell_4(xs, PRIME_X, self.period);
+            let gather = |dx: usize, yi: u64| {
+                let g = lattice.map(|l| self.gradient(l[dx], yi));
+                (Vec4::new(g[0].x, g[1].x, g[2].x, g[3].x), Vec4::new(g[0].y, g[1].y, g[2].y, g[3].y))
+            };
+            let ((ax, ay), (bx, by)) = (gather(0, y0), gather(1, y0));
+            let ((cx, cy), (dx, dy)) = (gather(0, y1), gather(1, y1));
+            let fx1 = fx - Vec4::ONE;
+            let a = ax * fx + ay * fy;
+            let b = bx * fx1 + by * fy;
+            let c = cx * fx + cy * (fy - 1.);
+            let d = dx * fx1 + dy * (fy - 1.);
+            let u = smoothstep_4(fx);
+            let low = a + (b - a) * u;
+            let high = c + (d - c) * u;
+            chunk.copy_from_slice(&(low + (high - low) * v).to_array());
+        }
+        for (i, value) in out.iter_mut().enumerate().skip(done) {
+            *value = self.sample_2d(x + i as f32 * step, y);
+        }
+    }
+}
+
+impl NoiseGrad2D for Perlin2D {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let ([x0, x1], fx) = cell(x, PRIME_X, self.period);
+        let ([y0, y1], fy) = cell(y, PRIME_Y, self.period);
+        let (ga, gb, gc, gd) = (self.gradient(x0, y0), self.gradient(x1, y0), self.gradient(x0, y1), self.gradient(x1, y1));
+        let (a, b) = (ga.dot(Vec2::new(fx, fy)), gb.dot(Vec2::new(fx - 1., fy)));
+        let (c, d) = (gc.dot(Vec2::new(fx, fy - 1.)), gd.dot(Vec2::new(fx - 1., fy - 1.)));
+        let (u, v) = (smoothstep(fx), smoothstep(fy));
+        let (du, dv) = (smoothstep_derivative(fx), smoothstep_derivative(fy));
+
+        // Bilinear blend written as a polynomial in u and v, then differentiated by the chain rule
+        let (k1, k2, k3) = (b - a, c - a, a - b - c + d);
+        let value = a + k1 * u + k2 * v + k3 * u * v;
+        let gradient = ga + (gb - ga) * u + (gc - ga) * v + (ga - gb - gc + gd) * u * v
+            + Vec2::new(du * (k1 + k3 * v), dv * (k2 + k3 * u));
+        (value, gradient)
+    }
+}
+
+// Classic gradient noise on the integer lattice, roughly within [-0.9, 0.9]
+#[derive(Clone, Copy, Debug)]
+pub struct Perlin3D {
+    seed: u64,
+    // Wraps the lattice so the noise tiles every `period` units along each axis
+    pub period: Option<u32>
+}
+
+impl Perlin3D {
+    pub fn new(rng: &mut impl Rng) -> Perlin3D {
+        Perlin3D { seed: rng.gen(), period: None }
+    }
+
+    pub fn period(mut self, period: u32) -> Self {
+        self.period = Some(period);
+        self
+    }
+
+    fn gradient(&self, lx: u64, ly: u64, lz: u64) -> Vec3 {
+        GRADIENTS_3D[hash::index(hash::hash(self.seed, &[lx, ly, lz]), GRADIENTS_3D.len())]
+    }
+
+    // Gradients and offset dot products at the 8 corners of the cell around (x, y, z), indexed 0bxyz
+    fn corners(&self, x: f32, y: f32, z: f32) -> ([(Vec3, f32); 8], Vec3) {
+        let (xl, fx) = cell(x, PRIME_X, self.period);
+        let (yl, fy) = cell(y, PRIME_Y, self.period);
+        let (zl, fz) = cell(z, PRIME_Z, self.period);
+        let f = Vec3::new(fx, fy, fz);
+        let corners = std::array::from_fn(|i| {
+            let (dx, dy, dz) = (i >> 2 & 1, i >> 1 & 1, i & 1);
+            let g = self.gradient(xl[dx], yl[dy], zl[dz]);
+            (g, g.dot(f - Vec3::new(dx as f32, dy as f32, dz as f32)))
+        });
+        (corners, f)
+    }
+}
+
+impl NoiseSource3D for Perlin3D {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        let (c, f) = self.corners(x, y, z);
+        let (u, v, w) = (smoothstep(f.x), smoothstep(f.y), smoothstep(f.z));
+        lerp(
+            lerp(lerp(c[0b000].1, c[0b100].1, u), lerp(c[0b010].1, c[0b110].1, u), v),
+            lerp(lerp(c[0b001].1, c[0b101].1, u), lerp(c[0b011].1, c[0b111].1, u), v),
+            w
+        )
+    }
+
+    // Four samples at a time, as in Perlin2D: only x changes along the row
+    fn sample_row_3d(&self, x: f32, y: f32, z: f32, step: f32, out: &mut [f32]) {
+        let ([y0, y1], fy) = cell(y, PRIME_Y, self.period);
+        let ([z0, z1], fz) = cell(z, PRIME_Z, self.period);
+        let (v, w) = (smoothstep(fy), smoothstep(fz));
+        let lanes = Vec4::new(0., 1., 2., 3.);
+        let done = out.len() / 4 * 4;
+        for (chunk_idx, chunk) in out.chunks_exact_mut(4).enumerate() {
+            let xs = Vec4::splat(x) + (lanes + Vec4::splat((chunk_idx * 4) as f32)) * step;
+            let (lattice, fx) = cell_4(xs, PRIME_X, self.period);
+            // Dot product of each lane's corner gradient with its offset from that corner
+            let corner = |dx: usize, yi: u64, dy: f32, zi: u64, dz: f32| {
+                let g = lattice.map(|l| self.gradient(l[dx], yi, zi));
+                Vec4::new(g[0].x, g[1].x, g[2].x, g[3].x) * (fx - dx as f32)
+                    + Vec4::new(g[0].y, g[1].y, g[2].y, g[3].y) * (fy - dy)
+                    + Vec4::new(g[0].z, g[1].z, g[2].z, g[3].z) * (fz - dz)
+            };
+            let u = smoothstep_4(fx);
+            let lerp_x = |yi, dy, zi, dz| {
+                let a = corner(0, yi, dy, zi, dz);
+                a + (corner(1, yi, dy, zi, dz) - a) * u
+            };
+            let (near_low, near_high) = (lerp_x(y0, 0., z0, 0.), lerp_x(y1, 1., z0, 0.));
+            let (far_low, far_high) = (lerp_x(y0, 0., z1, 1.), lerp_x(y1, 1., z1, 1.));
+            let near = near_low + (near_high - near_low) * v;
+            let far = far_low + (far_high - far_low) * v;
+            chunk.copy_from_slice(&(near + (far - near) * w).to_array());
+        }
+        for (i, value) in out.iter_mut().enumerate().skip(done) {
+            *value = self.sample_3d(x + i as f32 * step, y, z);
+        }
+    }
+}
+
+impl NoiseGrad3D for Perlin3D {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+        let (corners, f) = self.corners(x, y, z);
+        let [(ga, a), (ge, e), (gc, c), (gg, g), (gb, b), (gf, ff), (gd, d), (gh, h)] = corners;
+        let (u, v, w) = (smoothstep(f.x), smoothstep(f.y), smoothstep(f.z));
+        let (du, dv, dw) = (smoothstep_derivative(f.x), smoothstep_derivative(f.y), smoothstep_derivative(f.z));
+
+        // Trilinear blend written as a polynomial in u, v and w, then differentiated by the chain rule
+        let (k1, k2, k3) = (b - a, c - a, e - a);
+        let (k4, k5, k6) = (a - b - c + d, a - c - e + g, a - b - e + ff);
+        let k7 = -a + b + c - d + e - ff - g + h;
+        let value = a + k1 * u + k2 * v + k3 * w + k4 * u * v + k5 * v * w + k6 * w * u + k7 * u * v * w;
+        let gradient = ga + (gb - ga) * u + (gc - ga) * v + (ge - ga) * w
+            + (ga - gb - gc + gd) * u * v + (ga - gc - ge + gg) * v * w + (ga - gb - ge + gf) * w * u
+            + (-ga + gb + gc - gd + ge - gf - gg + gh) * u * v * w
+            + Vec3::new(
+                du * (k1 + k4 * v + k6 * w + k7 * v * w),
+                dv * (k2 + k5 * w + k4 * u + k7 * w * u),
+                dw * (k3 + k6 * u + k5 * v + k7 * u * v)
+            );
+        (value, gradient)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    const PERIOD: u32 = 5;
+
+    // Points on a 1/64 grid, so shifting them by whole periods is exact
+    fn points() -> impl Iterator<Item = Vec3> {
+        (0..500).map(|i| Vec3::new((i * 37 % 641) as f32, (i * 53 % 577) as f32, (i * 71 % 607) as f32) / 64. - 4.)
+    }
+
+    #[test]
+    fn period_tiles() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        let (noise_2d, noise_3d) = (Perlin2D::new(&mut rng).period(PERIOD), Perlin3D::new(&mut rng).period(PERIOD));
+        for p in points() {
+            for shift in [Vec3::X, Vec3::Y, Vec3::Z, Vec3::new(-2., 3., 1.)] {
+                let q = p + shift * PERIOD as f32;
+                assert_eq!(noise_2d.sample_2d(p.x, p.y), noise_2d.sample_2d(q.x, q.y), "2D at {} and {}", p, q);
+                assert_eq!(noise_3d.sample_3d(p.x, p.y, p.z), noise_3d.sample_3d(q.x, q.y, q.z), "3D at {} and {}", p, q);
+            }
+        }
+    }
+
+    #[test]
+    fn untiled_noise_doesnt_repeat() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        let (noise_2d, noise_3d) = (Perlin2D::new(&mut rng), Perlin3D::new(&mut rng));
+        // Points on the lattice are always zero, so only most of them differ
+        let (mut differ_2d, mut differ_3d) = (0, 0);
+        for p in points() {
+            let q = p + Vec3::X * PERIOD as f32;
+            differ_2d += (noise_2d.sample_2d(p.x, p.y) != noise_2d.sample_2d(q.x, q.y)) as usize;
+            differ_3d += (noise_3d.sample_3d(p.x, p.y, p.z) != noise_3d.sample_3d(q.x, q.y, q.z)) as usize;
+        }
+        assert!(differ_2d > 450 && differ_3d > 450, "{} and {} of 500 differ", differ_2d, differ_3d);
+    }
+}
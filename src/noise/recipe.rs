@@ -0,0 +1,298 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Serialize, Deserialize, Serializer, Deserializer, de::{self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, VariantAccess, Visitor}, forward_to_deserialize_any};
+
+use super::{NoiseGrad2D, NoiseKind, FractalKind, Gradient2D, Worley, WorleyReturn, Constant, Fractal, ScaleBias, Clamp, Terrace, Translate, Add, Mul, Select, DomainWarp};
+
+// A noise source built from a recipe, with gradients so it can drive analytic terrain normals
+pub type BoxedNoise2D = Box<dyn NoiseGrad2D + Send + Sync>;
+
+// Serializable description of a noise graph, so worlds can be tuned from data files. Each node maps onto
+// the source or combinator of the same name, with the same defaults. Modules built on top of the noise can
+// add their own nodes with `X`, which are written and nested like any other.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(remote = "Self", bound = "X: NodeExtension")]
+pub enum NoiseNode<X = NoExtension> {
+    // Gradient noise. Without a kind the layer's default is used, so it can still be picked on the command line.
+    // Only Perlin noise can have a period.
+    Gradient {
+        #[serde(default)]
+        kind: Option<NoiseKind>,
+        #[serde(default)]
+        period: Option<u32>
+    },
+    Constant(f32),
+    // Cellular noise, for cracked mesas, crater fields and the like. Distances are in cells, so it's scaled
+    // with a Fractal's frequency and amplitude.
+    Worley {
+        #[serde(default)]
+        return_type: WorleyReturn,
+        #[serde(default = "default_one")]
+        jitter: f32,
+        #[serde(default)]
+        period: Option<u32>
+    },
+    Fractal {
+        source: Box<NoiseNode<X>>,
+        #[serde(default)]
+        kind: FractalKind,
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+        #[serde(default = "default_one")]
+        frequency: f32,
+        #[serde(default = "default_lacunarity")]
+        lacunarity: f32,
+        #[serde(default = "default_gain")]
+        gain: f32,
+        #[serde(default = "default_one")]
+        amplitude: f32
+    },
+    ScaleBias {
+        source: Box<NoiseNode<X>>,
+        #[serde(default = "default_one")]
+        scale: f32,
+        #[serde(default)]
+        bias: f32
+    },
+    Clamp {
+        source: Box<NoiseNode<X>>,
+        min: f32,
+        max: f32
+    },
+    // Points in increasing order, at least two of them
+    Terrace {
+        source: Box<NoiseNode<X>>,
+        points: Vec<f32>
+    },
+    Translate {
+        source: Box<NoiseNode<X>>,
+        offset: [f32; 2]
+    },
+    Add(Box<NoiseNode<X>>, Box<NoiseNode<X>>),
+    Mul(Box<NoiseNode<X>>, Box<NoiseNode<X>>),
+    Select {
+        control: Box<NoiseNode<X>>,
+        lower: Box<NoiseNode<X>>,
+        upper: Box<NoiseNode<X>>,
+        threshold: f32,
+        #[serde(default)]
+        falloff: f32
+    },
+    Warp {
+        source: Box<NoiseNode<X>>,
+        warp: Box<NoiseNode<X>>,
+        strength: f32,
+        #[serde(default = "default_levels")]
+        levels: u32
+    },
+    // One of the nodes added by `X`
+    #[serde(skip)]
+    Extension(X)
+}
+
+fn default_octaves() -> u32 {
+    4
+}
+
+fn default_one() -> f32 {
+    1.
+}
+
+fn default_lacunarity() -> f32 {
+    2.
+}
+
+fn default_gain() -> f32 {
+    0.5
+}
+
+fn default_levels() -> u32 {
+    1
+}
+
+// Nodes another module adds to `NoiseNode`, such as heightmaps read from disk. `VARIANTS` are the names
+// they're written with, which mustn't clash with the noise nodes.
+pub trait NodeExtension: Clone + fmt::Debug + Serialize + DeserializeOwned {
+    const VARIANTS: &'static [&'static str];
+
+    fn build_2d<R: Rng>(&self, default_kind: NoiseKind, rng: &mut R) -> Result<BoxedNoise2D, String>;
+}
+
+// Adds no nodes, for graphs of noise alone
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum NoExtension {}
+
+impl NodeExtension for NoExtension {
+    const VARIANTS: &'static [&'static str] = &[];
+
+    fn build_2d<R: Rng>(&self, _default_kind: NoiseKind, _rng: &mut R) -> Result<BoxedNoise2D, String> {
+        match *self {}
+    }
+}
+
+impl<X: NodeExtension> Serialize for NoiseNode<X> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            NoiseNode::Extension(node) => node.serialize(serializer),
+            node => NoiseNode::serialize(node, serializer)
+        }
+    }
+}
+
+// The variant's name is read first, then the rest goes to whichever of the noise nodes or `X` has it
+impl<'de, X: NodeExtension> Deserialize<'de> for NoiseNode<X> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NodeVisitor<X>(std::marker::PhantomData<X>);
+
+        impl<'de, X: NodeExtension> Visitor<'de> for NodeVisitor<X> {
+            type Value = NoiseNode<X>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a noise node")
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+                let (VariantName(name), variant) = data.variant()?;
+                if X::VARIANTS.contains(&name.as_str()) {
+                    X::deserialize(Replay { name, variant }).map(NoiseNode::Extension)
+                } else {
+                    NoiseNode::deserialize(Replay { name, variant })
+                }
+            }
+        }
+
+        deserializer.deserialize_enum("NoiseNode", &[], NodeVisitor(std::marker::PhantomData))
+    }
+}
+
+// Name of an enum variant, read as an identifier
+struct VariantName(String);
+
+impl<'de> Deserialize<'de> for VariantName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NameVisitor;
+
+        impl Visitor<'_> for NameVisitor {
+            type Value = VariantName;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a node name")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<VariantName, E> {
+                Ok(VariantName(name.to_string()))
+            }
+        }
+
+        deserializer.deserialize_identifier(NameVisitor)
+    }
+}
+
+// An enum whose variant name has already been read, handed on to another enum's deserializer
+struct Replay<A> {
+    name: String,
+    variant: A
+}
+
+impl<'de, A: VariantAccess<'de>> Deserializer<'de> for Replay<A> {
+    type Error = A::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, A::Error> {
+        visitor.visit_enum(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de, A: VariantAccess<'de>> EnumAccess<'de> for Replay<A> {
+    type Error = A::Error;
+    type Variant = A;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, A), A::Error> {
+        Ok((seed.deserialize(self.name.into_deserializer())?, self.variant))
+    }
+}
+
+impl<X: NodeExtension> NoiseNode<X> {
+    // Builds the graph as a 2D heightmap. Seeds are drawn from `rng` depth first, in the order the nodes
+    // are written, so the same recipe and seed always give the same world.
+    pub fn build_2d<R: Rng>(&self, default_kind: NoiseKind, rng: &mut R) -> Result<BoxedNoise2D, String> {
+        let mut build = |node: &NoiseNode<X>| node.build_2d(default_kind, rng);
+        Ok(match self {
+            NoiseNode::Gradient { kind, period } => {
+                let noise = Gradient2D::new(kind.unwrap_or(default_kind), rng);
+                match period {
+                    Some(period) => Box::new(noise.period(*period)?),
+                    None => Box::new(noise)
+                }
+            },
+            NoiseNode::Constant(value) => Box::new(Constant(*value)),
+            NoiseNode::Worley { return_type, jitter, period } => {
+                // Points moving further than their own cell could be missed by the search
+                if !(0. ..=1.).contains(jitter) {
+                    return Err(format!("Worley jitter must be between 0 and 1, got {}", jitter));
+                }
+                let worley = Worley::new(rng).return_type(*return_type).jitter(*jitter);
+                match period {
+                    Some(period) => Box::new(worley.period(*period)),
+                    None => Box::new(worley)
+                }
+            },
+            NoiseNode::Fractal { source, kind, octaves, frequency, lacunarity, gain, amplitude } => Box::new(
+                Fractal::new(*kind, build(source)?)
+                    .octaves(*octaves)
+                    .frequency(*frequency)
+                    .lacunarity(*lacunarity)
+                    .gain(*gain)
+                    .amplitude(*amplitude)
+            ),
+            NoiseNode::ScaleBias { source, scale, bias } => Box::new(ScaleBias::new(build(source)?, *scale, *bias)),
+            NoiseNode::Clamp { source, min, max } => Box::new(Clamp::new(build(source)?, *min, *max)),
+            NoiseNode::Terrace { source, points } => {
+                if points.len() < 2 {
+                    return Err(format!("Terrace needs at least two points, got {}", points.len()));
+                }
+                if !points.windows(2).all(|pair| pair[0] < pair[1]) {
+                    return Err(format!("Terrace points must be increasing, got {:?}", points));
+                }
+                Box::new(Terrace::new(build(source)?, points.clone()))
+            },
+            NoiseNode::Translate { source, offset } => Box::new(Translate::new(build(source)?, Vec3::new(offset[0], offset[1], 0.))),
+            NoiseNode::Add(a, b) => Box::new(Add(build(a)?, build(b)?)),
+            NoiseNode::Mul(a, b) => Box::new(Mul(build(a)?, build(b)?)),
+            NoiseNode::Select { control, lower, upper, threshold, falloff } => {
+                Box::new(Select::new(build(control)?, build(lower)?, build(upper)?, *threshold, *falloff))
+            },
+            NoiseNode::Warp { source, warp, strength, levels } => {
+                Box::new(DomainWarp::new(build(source)?, build(warp)?, *strength).levels(*levels))
+            },
+            NoiseNode::Extension(node) => node.build_2d(default_kind, rng)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    fn terrace(points: &[f32]) -> Result<BoxedNoise2D, String> {
+        let node: NoiseNode = NoiseNode::Terrace { source: Box::new(NoiseNode::Gradient { kind: None, period: None }), points: points.to_vec() };
+        node.build_2d(NoiseKind::Perlin, &mut ChaCha8Rng::seed_from_u64(13))
+    }
+
+    #[test]
+    fn terrace_points_must_increase() {
+        assert!(terrace(&[-1., 0., 0.5, 1.]).is_ok());
+        assert!(terrace(&[0.]).is_err());
+        assert!(terrace(&[-1., 0., 0., 1.]).is_err());
+        assert!(terrace(&[1., 0.]).is_err());
+        assert!(terrace(&[0., f32::NAN]).is_err());
+    }
+}
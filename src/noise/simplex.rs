@@ -0,0 +1,300 @@
+use bevy::prelude::*;
+use rand::Rng;
+
+use super::{NoiseSource2D, NoiseSource3D, NoiseSource4D, NoiseGrad2D, NoiseGrad3D, hash::{self, PRIME_X, PRIME_Y, PRIME_Z, PRIME_W}};
+
+// OpenSimplex2 (the fast variant): gradient noise on a simplex-type lattice, without the axis-aligned
+// artifacts of Perlin noise and with fewer gradient lookups per sample (3 in 2D, 4 in 3D, 5 in 4D).
+// Gradients are chosen by hashing lattice coordinates, so there are no tables to build or store.
+
+// The falloff radii and normalizers are those of the reference implementation, OpenSimplex2.java in
+// https://github.com/KdotJPG/OpenSimplex2, which divides its gradients by the normalizer so the output
+// stays within -1 to 1. The radii are as large as they can be while every vertex in range is one of
+// those visited, which keeps the noise (and its derivative) continuous.
+//
+// The 2D and 3D gradients are the reference ones, but the 4D gradients aren't: they're the 32 permutations
+// of (0, +-1, +-1, +-1) / sqrt(3) rather than the reference 160-entry table. The reference 4D normalizer was
+// derived for that table, so it only keeps this output within -1 to 1 as measured by `output_stays_within_one`
+// (the largest values found are around 0.96), not by construction.
+
+const SKEW_2D: f64 = 0.366025403784439; // (sqrt(3) - 1) / 2
+const UNSKEW_2D: f64 = -0.21132486540518713; // (1 / sqrt(3) - 1) / 2
+const RSQUARED_2D: f64 = 0.5;
+const NORMALIZER_2D: f64 = 1. / 0.01001634121365712;
+
+const ROTATE_3D: f64 = 2. / 3.;
+const RSQUARED_3D: f64 = 0.6;
+const SEED_FLIP_3D: u64 = 0xA8B09A8B1B2E4F4D;
+const NORMALIZER_3D: f64 = 1. / 0.07969837668935331;
+
+const SKEW_4D: f64 = -0.138196601125011; // (1 / sqrt(5) - 1) / 4
+const UNSKEW_4D: f64 = 0.309016994374947; // (sqrt(5) - 1) / 4
+const LATTICE_STEP_4D: f64 = 0.2;
+const RSQUARED_4D: f64 = 0.6;
+const SEED_OFFSET_4D: u64 = 0xE83DC3E0DA7164D;
+const NORMALIZER_4D: f64 = 1. / 0.0220065933241897;
+
+// 24 directions, 15 degrees apart
+const GRADIENTS_2D: [[f64; 2]; 24] = [
+    [0.991444861, 0.130526192], [0.923879533, 0.382683432], [0.793353340, 0.608761429],
+    [0.608761429, 0.793353340], [0.382683432, 0.923879533], [0.130526192, 0.991444861],
+    [-0.130526192, 0.991444861], [-0.382683432, 0.923879533], [-0.608761429, 0.793353340],
+    [-0.793353340, 0.608761429], [-0.923879533, 0.382683432], [-0.991444861, 0.130526192],
+    [-0.991444861, -0.130526192], [-0.923879533, -0.382683432], [-0.793353340, -0.608761429],
+    [-0.608761429, -0.793353340], [-0.382683432, -0.923879533], [-0.130526192, -0.991444861],
+    [0.130526192, -0.991444861], [0.382683432, -0.923879533], [0.608761429, -0.793353340],
+    [0.793353340, -0.608761429], [0.923879533, -0.382683432], [0.991444861, -0.130526192],
+];
+
+// The reference gradients, towards the vertices of a skewed rhombicuboctahedron whose square and
+// triangular faces fit in circles of the same radius. They all have the same length, about 3.3.
+const A: f64 = 2.22474487139;
+const B: f64 = 3.0862664687972017;
+const D: f64 = 1.1721513422464978;
+const GRADIENTS_3D: [[f64; 3]; 48] = [
+    [A, A, -1.], [A, A, 1.], [B, D, 0.], [D, B, 0.],
+    [-A, A, -1.], [-A, A, 1.], [-D, B, 0.], [-B, D, 0.],
+    [-1., -A, -A], [1., -A, -A], [0., -B, -D], [0., -D, -B],
+    [-1., -A, A], [1., -A, A], [0., -D, B], [0., -B, D],
+    [-A, -A, -1.], [-A, -A, 1.], [-B, -D, 0.], [-D, -B, 0.],
+    [A, -A, -1.], [A, -A, 1.], [D, -B, 0.], [B, -D, 0.],
+    [-1., A, -A], [1., A, -A], [0., D, -B], [0., B, -D],
+    [-1., A, A], [1., A, A], [0., B, D], [0., D, B],
+    [A, -1., -A], [A, 1., -A], [D, 0., -B], [B, 0., -D],
+    [-A, -1., -A], [-A, 1., -A], [-B, 0., -D], [-D, 0., -B],
+    [-A, -1., A], [-A, 1., A], [-D, 0., B], [-B, 0., D],
+    [A, -1., A], [A, 1., A], [B, 0., D], [D, 0., B],
+];
+
+// Unit length, for the 4D gradients
+const C: f64 = 0.577350269;
+
+fn gradient_4d(hash: u64) -> [f64; 4] {
+    // Permutations of (0, +-1, +-1, +-1) / sqrt(3), not the reference table (see the top of the file)
+    let i = hash::index(hash, 32);
+    let (zero_axis, signs) = (i / 8, i % 8);
+    let mut g = [0.; 4];
+    let mut bit = 0;
+    for (axis, component) in g.iter_mut().enumerate() {
+        if axis != zero_axis {
+            *component = if signs & (1 << bit) == 0 { C } else { -C };
+            bit += 1;
+        }
+    }
+    g
+}
+
+fn lattice(coord: f64, prime: u64) -> u64 {
+    (coord as i64 as u64).wrapping_mul(prime)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct OpenSimplex2 {
+    seed: u64
+}
+
+impl OpenSimplex2 {
+    pub fn new(rng: &mut impl Rng) -> OpenSimplex2 {
+        OpenSimplex2 { seed: rng.gen() }
+    }
+
+    // Value and derivative of one vertex's contribution, falloff^4 * dot(gradient, offset)
+    fn contribution_2d(&self, xp: u64, yp: u64, dx: f64, dy: f64) -> (f64, [f64; 2]) {
+        let a = RSQUARED_2D - dx * dx - dy * dy;
+        if a <= 0. {
+            return (0., [0.; 2]);
+        }
+        let g = GRADIENTS_2D[hash::index(hash::hash(self.seed, &[xp, yp]), GRADIENTS_2D.len())];
+        let dot = g[0] * dx + g[1] * dy;
+        let (a2, a3) = (a * a, a * a * a);
+        (a2 * a2 * dot, [a2 * a2 * g[0] - 8. * a3 * dot * dx, a2 * a2 * g[1] - 8. * a3 * dot * dy])
+    }
+
+    fn contribution_3d(&self, seed: u64, p: [u64; 3], d: [f64; 3], a: f64) -> (f64, [f64; 3]) {
+        let g = GRADIENTS_3D[hash::index(hash::hash(seed, &p), GRADIENTS_3D.len())];
+        let dot = g[0] * d[0] + g[1] * d[1] + g[2] * d[2];
+        let (a2, a3) = (a * a, a * a * a);
+        (a2 * a2 * dot, [0, 1, 2].map(|i| a2 * a2 * g[i] - 8. * a3 * dot * d[i]))
+    }
+
+    fn grad_4d(&self, seed: u64, p: [u64; 4], d: [f64; 4]) -> f64 {
+        let g = gradient_4d(hash::hash(seed, &p));
+        g[0] * d[0] + g[1] * d[1] + g[2] * d[2] + g[3] * d[3]
+    }
+
+    fn noise_2d(&self, x: f64, y: f64) -> (f64, [f64; 2]) {
+        // Skew onto the square lattice, where each square is split into two triangles
+        let s = SKEW_2D * (x + y);
+        let (xs, ys) = (x + s, y + s);
+        let (xsb, ysb) = (xs.floor(), ys.floor());
+        let (xi, yi) = (xs - xsb, ys - ysb);
+        let (xp, yp) = (lattice(xsb, PRIME_X), lattice(ysb, PRIME_Y));
+
+        // Unskew the offset back to the base vertex. Offsets move one-to-one with the input point, so
+        // each contribution's derivative is also the derivative with respect to the input.
+        let t = (xi + yi) * UNSKEW_2D;
+        let (dx0, dy0) = (xi + t, yi + t);
+        let contributions = [
+            self.contribution_2d(xp, yp, dx0, dy0),
+            self.contribution_2d(xp.wrapping_add(PRIME_X), yp.wrapping_add(PRIME_Y), dx0 - (1. + 2. * UNSKEW_2D), dy0 - (1. + 2. * UNSKEW_2D)),
+            // The third vertex depends on which triangle of the square we're in
+            if dy0 > dx0 {
+                self.contribution_2d(xp, yp.wrapping_add(PRIME_Y), dx0 - UNSKEW_2D, dy0 - (UNSKEW_2D + 1.))
+            } else {
+                self.contribution_2d(xp.wrapping_add(PRIME_X), yp, dx0 - (UNSKEW_2D + 1.), dy0 - UNSKEW_2D)
+            }
+        ];
+        let (value, gradient) = contributions.iter().fold((0., [0.; 2]), |(v, g), (cv, cg)| (v + cv, [g[0] + cg[0], g[1] + cg[1]]));
+        (value * NORMALIZER_2D, gradient.map(|g| g * NORMALIZER_2D))
+    }
+
+    fn noise_3d(&self, x: f64, y: f64, z: f64) -> (f64, [f64; 3]) {
+        // Rotate so the main diagonal points up, then sample two interleaved cubic lattices (together a
+        // body-centered cubic lattice). Each lattice contributes its closest vertex and the neighbour
+        // along the largest offset axis.
+        let r = ROTATE_3D * (x + y + z);
+        let p = [r - x, r - y, r - z];
+        let mut value = 0.;
+        let mut gradient = [0.; 3];
+        let mut add = |(v, g): (f64, [f64; 3])| {
+            value += v;
+            gradient = [0, 1, 2].map(|i| gradient[i] + g[i]);
+        };
+        for (lattice_offset, seed) in [(0., self.seed), (0.5, self.seed ^ SEED_FLIP_3D)] {
+            let base = p.map(|c| (c - lattice_offset).round());
+            let d = [0, 1, 2].map(|i| p[i] - lattice_offset - base[i]);
+            let bp = [lattice(base[0], PRIME_X), lattice(base[1], PRIME_Y), lattice(base[2], PRIME_Z)];
+
+            let a = RSQUARED_3D - d[0] * d[0] - d[1] * d[1] - d[2] * d[2];
+            if a > 0. {
+                add(self.contribution_3d(seed, bp, d, a));
+            }
+
+            let abs = d.map(f64::abs);
+            let axis = if abs[0] >= abs[1] && abs[0] >= abs[2] { 0 } else if abs[1] >= abs[2] { 1 } else { 2 };
+            // Moving one unit towards the sample along `axis` changes the falloff by 2|d| - 1
+            let b = a + 2. * abs[axis] - 1.;
+            if b > 0. {
+                let step = d[axis].signum();
+                let mut np = bp;
+                let mut nd = d;
+                np[axis] = np[axis].wrapping_add([PRIME_X, PRIME_Y, PRIME_Z][axis].wrapping_mul(step as i64 as u64));
+                nd[axis] -= step;
+                add(self.contribution_3d(seed, np, nd, b));
+            }
+        }
+        // The rotation is symmetric, so its transpose maps the gradient back the same way
+        let r = ROTATE_3D * (gradient[0] + gradient[1] + gradient[2]);
+        (value * NORMALIZER_3D, gradient.map(|g| (r - g) * NORMALIZER_3D))
+    }
+
+    fn noise_4d(&self, x: f64, y: f64, z: f64, w: f64) -> f64 {
+        // Skew onto the hypercubic lattice, then walk five copies of it offset along the main diagonal,
+        // each contributing its closest vertex
+        let s = SKEW_4D * (x + y + z + w);
+        let (xs, ys, zs, ws) = (x + s, y + s, z + s, w + s);
+        let (xsb, ysb, zsb, wsb) = (xs.floor(), ys.floor(), zs.floor(), ws.floor());
+        let mut si = [xs - xsb, ys - ysb, zs - zsb, ws - wsb];
+
+        let si_sum = si.iter().sum::<f64>();
+        let starting_lattice = (si_sum * 1.25) as u64;
+        let mut seed = self.seed.wrapping_add(starting_lattice.wrapping_mul(SEED_OFFSET_4D));
+        let starting_lattice_offset = starting_lattice as f64 * -LATTICE_STEP_4D;
+        si = si.map(|c| c + starting_lattice_offset);
+        let mut ssi = (si_sum + starting_lattice_offset * 4.) * UNSKEW_4D;
+        let mut vp = [lattice(xsb, PRIME_X), lattice(ysb, PRIME_Y), lattice(zsb, PRIME_Z), lattice(wsb, PRIME_W)];
+        let primes = [PRIME_X, PRIME_Y, PRIME_Z, PRIME_W];
+
+        let mut value = 0.;
+        for i in 0..5 {
+            // Move to the neighbouring vertex if it's closer than the base vertex of this lattice
+            let score0 = 1. + ssi * (-1. / UNSKEW_4D);
+            let max_axis = (0..4).fold(0, |best, axis| if si[axis] > si[best] { axis } else { best });
+            if si[max_axis] >= score0 {
+                vp[max_axis] = vp[max_axis].wrapping_add(primes[max_axis]);
+                si[max_axis] -= 1.;
+                ssi -= UNSKEW_4D;
+            }
+
+            let d = si.map(|c| c + ssi);
+            let a = d.iter().map(|c| c * c).sum::<f64>();
+            if a < RSQUARED_4D {
+                let a = (RSQUARED_4D - a) * (RSQUARED_4D - a);
+                value += a * a * self.grad_4d(seed, vp, d);
+            }
+            if i == 4 {
+                break;
+            }
+
+            si = si.map(|c| c + LATTICE_STEP_4D);
+            ssi += LATTICE_STEP_4D * 4. * UNSKEW_4D;
+            seed = seed.wrapping_sub(SEED_OFFSET_4D);
+
+            // Wrap around from the last lattice copy back to the first, one cell lower
+            if i == starting_lattice {
+                for (v, prime) in vp.iter_mut().zip(primes) {
+                    *v = v.wrapping_sub(prime);
+                }
+                seed = seed.wrapping_add(SEED_OFFSET_4D.wrapping_mul(5));
+            }
+        }
+        value * NORMALIZER_4D
+    }
+}
+
+impl NoiseSource2D for OpenSimplex2 {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        self.noise_2d(x as f64, y as f64).0 as f32
+    }
+}
+
+impl NoiseGrad2D for OpenSimplex2 {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let (value, gradient) = self.noise_2d(x as f64, y as f64);
+        (value as f32, Vec2::new(gradient[0] as f32, gradient[1] as f32))
+    }
+}
+
+impl NoiseSource3D for OpenSimplex2 {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        self.noise_3d(x as f64, y as f64, z as f64).0 as f32
+    }
+}
+
+impl NoiseGrad3D for OpenSimplex2 {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+        let (value, gradient) = self.noise_3d(x as f64, y as f64, z as f64);
+        (value as f32, Vec3::new(gradient[0] as f32, gradient[1] as f32, gradient[2] as f32))
+    }
+}
+
+impl NoiseSource4D for OpenSimplex2 {
+    fn sample_4d(&self, x: f32, y: f32, z: f32, w: f32) -> f32 {
+        self.noise_4d(x as f64, y as f64, z as f64, w as f64) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn output_stays_within_one() {
+        let mut rng = ChaCha8Rng::seed_from_u64(3);
+        let mut largest = [0f32; 3];
+        for _ in 0..8 {
+            let noise = OpenSimplex2::new(&mut rng);
+            for _ in 0..20000 {
+                let [x, y, z, w] = [(); 4].map(|_| rng.gen_range(-100f32..100.));
+                for (largest, value) in largest.iter_mut().zip([noise.sample_2d(x, y), noise.sample_3d(x, y, z), noise.sample_4d(x, y, z, w)]) {
+                    assert!((-1. ..=1.).contains(&value), "{} at ({}, {}, {}, {})", value, x, y, z, w);
+                    *largest = largest.max(value.abs());
+                }
+            }
+        }
+        // And the normalizers don't squash it into a much smaller range
+        assert!(largest.iter().all(|&largest| largest > 0.7), "{:?}", largest);
+    }
+}
@@ -0,0 +1,112 @@
+use bevy::prelude::*;
+
+use super::{NoiseSource2D, NoiseSource3D, NoiseGrad2D, NoiseGrad3D};
+
+// Shifts the warp noise for each axis so the axes don't move in lockstep. Large, since warps are usually
+// low frequency.
+const AXIS_OFFSET: [Vec3; 3] = [Vec3::new(0., 0., 0.), Vec3::new(520., 130., 710.), Vec3::new(170., 920., 340.)];
+// Extra shift per warp level, so each level warps differently
+const LEVEL_OFFSET: Vec3 = Vec3::new(830., 280., 460.);
+
+fn offset(level: u32, axis: usize) -> Vec3 {
+    AXIS_OFFSET[axis] + LEVEL_OFFSET * level as f32
+}
+
+// Samples `source` at p + strength * q, where q is `warp` sampled once per axis. With more than one level
+// the warp is itself warped by the previous level's q (Quilez's f(p + 4 g(p + 4 h(p)))), which twists
+// features into swirls and stretched ridges.
+#[derive(Clone)]
+pub struct DomainWarp<S, W> {
+    pub source: S,
+    pub warp: W,
+    // Distance the sample point moves per unit of warp noise
+    pub strength: f32,
+    pub levels: u32
+}
+
+impl<S, W> DomainWarp<S, W> {
+    pub fn new(source: S, warp: W, strength: f32) -> DomainWarp<S, W> {
+        DomainWarp { source, warp, strength, levels: 1 }
+    }
+
+    pub fn levels(mut self, levels: u32) -> Self {
+        self.levels = levels;
+        self
+    }
+}
+
+impl<S: NoiseSource2D, W: NoiseSource2D> DomainWarp<S, W> {
+    fn warped_2d(&self, p: Vec2) -> Vec2 {
+        (0..self.levels).fold(p, |warped, level| {
+            let sample = |axis| {
+                let o = offset(level, axis);
+                self.warp.sample_2d(warped.x + o.x, warped.y + o.y)
+            };
+            p + Vec2::new(sample(0), sample(1)) * self.strength
+        })
+    }
+}
+
+impl<S: NoiseSource3D, W: NoiseSource3D> DomainWarp<S, W> {
+    fn warped_3d(&self, p: Vec3) -> Vec3 {
+        (0..self.levels).fold(p, |warped, level| {
+            let sample = |axis| {
+                let o = warped + offset(level, axis);
+                self.warp.sample_3d(o.x, o.y, o.z)
+            };
+            p + Vec3::new(sample(0), sample(1), sample(2)) * self.strength
+        })
+    }
+}
+
+impl<S: NoiseSource2D, W: NoiseSource2D> NoiseSource2D for DomainWarp<S, W> {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        let warped = self.warped_2d(Vec2::new(x, y));
+        self.source.sample_2d(warped.x, warped.y)
+    }
+}
+
+impl<S: NoiseSource3D, W: NoiseSource3D> NoiseSource3D for DomainWarp<S, W> {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        let warped = self.warped_3d(Vec3::new(x, y, z));
+        self.source.sample_3d(warped.x, warped.y, warped.z)
+    }
+}
+
+// The gradients carry the Jacobian of the warped point through every level by the chain rule
+impl<S: NoiseGrad2D, W: NoiseGrad2D> NoiseGrad2D for DomainWarp<S, W> {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let p = Vec2::new(x, y);
+        let (warped, jacobian) = (0..self.levels).fold((p, Mat2::IDENTITY), |(warped, jacobian), level| {
+            let sample = |axis| {
+                let o = offset(level, axis);
+                let (value, grad) = self.warp.sample_grad_2d(warped.x + o.x, warped.y + o.y);
+                // Row of the Jacobian of this axis' warp with respect to p
+                (value, jacobian.transpose() * grad)
+            };
+            let ((qx, dqx), (qy, dqy)) = (sample(0), sample(1));
+            let q_jacobian = Mat2::from_cols(dqx, dqy).transpose();
+            (p + Vec2::new(qx, qy) * self.strength, Mat2::IDENTITY + q_jacobian * self.strength)
+        });
+        let (value, grad) = self.source.sample_grad_2d(warped.x, warped.y);
+        (value, jacobian.transpose() * grad)
+    }
+}
+
+impl<S: NoiseGrad3D, W: NoiseGrad3D> NoiseGrad3D for DomainWarp<S, W> {
+    fn sample_grad_3d(&self, x: f32, y: f32, z: f32) -> (f32, Vec3) {
+        let p = Vec3::new(x, y, z);
+        let (warped, jacobian) = (0..self.levels).fold((p, Mat3::IDENTITY), |(warped, jacobian), level| {
+            let sample = |axis| {
+                let o = warped + offset(level, axis);
+                let (value, grad) = self.warp.sample_grad_3d(o.x, o.y, o.z);
+                (value, jacobian.transpose() * grad)
+            };
+            let ((qx, dqx), (qy, dqy), (qz, dqz)) = (sample(0), sample(1), sample(2));
+            let q_jacobian = Mat3::from_cols(dqx, dqy, dqz).transpose();
+            (p + Vec3::new(qx, qy, qz) * self.strength, Mat3::IDENTITY + q_jacobian * self.strength)
+        });
+        let (value, grad) = self.source.sample_grad_3d(warped.x, warped.y, warped.z);
+        (value, jacobian.transpose() * grad)
+    }
+}
@@ -0,0 +1,234 @@
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+use super::{NoiseSource2D, NoiseSource3D, NoiseGrad2D, hash::{self, PRIME_X, PRIME_Y, PRIME_Z}};
+
+// Which distance(s) to the nearest feature points a Worley sample returns
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WorleyReturn {
+    // Distance to the closest point: round cells, dark at their centers
+    #[default]
+    F1,
+    // Distance to the second closest point
+    F2,
+    // Difference of the two: thin ridges along the cell borders, like cracked mud or crater rims
+    F2MinusF1
+}
+
+// Cellular noise: one randomly placed feature point per unit cell, sampled as the distance to the
+// nearest point(s). Distances are in cell units, so F1 stays within about [0, 1].
+#[derive(Clone, Copy, Debug)]
+pub struct Worley {
+    seed: u64,
+    pub return_type: WorleyReturn,
+    // Fraction of the cell the feature point can move within, 0 gives a regular grid
+    pub jitter: f32,
+    // Wraps the cell coordinates so the noise tiles every `period` cells
+    pub period: Option<u32>
+}
+
+impl Worley {
+    pub fn new(rng: &mut impl Rng) -> Worley {
+        Worley { seed: rng.gen(), return_type: WorleyReturn::F1, jitter: 1., period: None }
+    }
+
+    pub fn return_type(mut self, return_type: WorleyReturn) -> Self {
+        self.return_type = return_type;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: f32) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn period(mut self, period: u32) -> Self {
+        self.period = Some(period);
+        self
+    }
+
+    fn cell(&self, c: i64, prime: u64) -> u64 {
+        hash::lattice(c, prime, self.period)
+    }
+
+    // Feature point offset inside a cell, from successive bytes of the cell hash
+    fn feature<const N: usize>(&self, hash: u64) -> [f32; N] {
+        std::array::from_fn(|i| {
+            let byte = (hash >> (8 * i + 8)) as u8 as f32 / 255.;
+            0.5 + (byte - 0.5) * self.jitter
+        })
+    }
+
+    fn distances(&self, f1: f32, f2: f32) -> f32 {
+        match self.return_type {
+            WorleyReturn::F1 => f1,
+            WorleyReturn::F2 => f2,
+            WorleyReturn::F2MinusF1 => f2 - f1
+        }
+    }
+}
+
+// Cells searched out from the one being sampled along each axis. Feature points sitting near their cell
+// edges can put the second closest point two cells away, so one ring of neighbours isn't enough.
+const SEARCH_RADIUS: i64 = 2;
+
+// Smallest distance along one axis from a point at `f` in its cell to any point in the cell `d` away
+fn axis_gap(d: i64, f: f32) -> f32 {
+    match d {
+        0 => 0.,
+        d if d > 0 => d as f32 - f,
+        d => f - (d + 1) as f32
+    }
+}
+
+// The two closest feature points so far, as squared distance and offset from the sample
+#[derive(Clone, Copy)]
+struct Nearest<V> {
+    first: (f32, V),
+    second: (f32, V)
+}
+
+impl<V: Copy + Default> Nearest<V> {
+    fn new() -> Self {
+        Nearest { first: (f32::MAX, V::default()), second: (f32::MAX, V::default()) }
+    }
+
+    fn insert(&mut self, dist_sq: f32, offset: V) {
+        if dist_sq < self.first.0 {
+            self.second = self.first;
+            self.first = (dist_sq, offset);
+        } else if dist_sq < self.second.0 {
+            self.second = (dist_sq, offset);
+        }
+    }
+}
+
+impl Worley {
+    fn nearest_2d(&self, x: f32, y: f32) -> Nearest<Vec2> {
+        let (xb, yb) = (x.floor(), y.floor());
+        let (xf, yf) = (x - xb, y - yb);
+        let (xb, yb) = (xb as i64, yb as i64);
+        let mut nearest = Nearest::new();
+        for dx in -SEARCH_RADIUS..=SEARCH_RADIUS {
+            for dy in -SEARCH_RADIUS..=SEARCH_RADIUS {
+                // Cells that can't hold anything closer than the second point aren't hashed
+                let (gx, gy) = (axis_gap(dx, xf), axis_gap(dy, yf));
+                if gx * gx + gy * gy >= nearest.second.0 {
+                    continue;
+                }
+                let h = hash::hash(self.seed, &[self.cell(xb + dx, PRIME_X), self.cell(yb + dy, PRIME_Y)]);
+                let [fx, fy] = self.feature::<2>(h);
+                let offset = Vec2::new(dx as f32 + fx - xf, dy as f32 + fy - yf);
+                nearest.insert(offset.length_squared(), offset);
+            }
+        }
+        nearest
+    }
+}
+
+impl NoiseSource2D for Worley {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        let nearest = self.nearest_2d(x, y);
+        self.distances(nearest.first.0.sqrt(), nearest.second.0.sqrt())
+    }
+}
+
+// The distance to a point grows straight away from it, so each distance's gradient is the unit vector from
+// its feature point to the sample. It's discontinuous where the closest points swap, along cell borders.
+impl NoiseGrad2D for Worley {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let nearest = self.nearest_2d(x, y);
+        let (f1, f2) = (nearest.first.0.sqrt(), nearest.second.0.sqrt());
+        let (g1, g2) = (-nearest.first.1.normalize_or_zero(), -nearest.second.1.normalize_or_zero());
+        let gradient = match self.return_type {
+            WorleyReturn::F1 => g1,
+            WorleyReturn::F2 => g2,
+            WorleyReturn::F2MinusF1 => g2 - g1
+        };
+        (self.distances(f1, f2), gradient)
+    }
+}
+
+impl NoiseSource3D for Worley {
+    fn sample_3d(&self, x: f32, y: f32, z: f32) -> f32 {
+        let (xb, yb, zb) = (x.floor(), y.floor(), z.floor());
+        let (xf, yf, zf) = (x - xb, y - yb, z - zb);
+        let (xb, yb, zb) = (xb as i64, yb as i64, zb as i64);
+        let mut nearest = Nearest::<Vec3>::new();
+        for dx in -SEARCH_RADIUS..=SEARCH_RADIUS {
+            for dy in -SEARCH_RADIUS..=SEARCH_RADIUS {
+                for dz in -SEARCH_RADIUS..=SEARCH_RADIUS {
+                    let (gx, gy, gz) = (axis_gap(dx, xf), axis_gap(dy, yf), axis_gap(dz, zf));
+                    if gx * gx + gy * gy + gz * gz >= nearest.second.0 {
+                        continue;
+                    }
+                    let cell = [self.cell(xb + dx, PRIME_X), self.cell(yb + dy, PRIME_Y), self.cell(zb + dz, PRIME_Z)];
+                    let [fx, fy, fz] = self.feature::<3>(hash::hash(self.seed, &cell));
+                    let offset = Vec3::new(dx as f32 + fx - xf, dy as f32 + fy - yf, dz as f32 + fz - zf);
+                    nearest.insert(offset.length_squared(), offset);
+                }
+            }
+        }
+        self.distances(nearest.first.0.sqrt(), nearest.second.0.sqrt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    // Distances to every feature point in a wide block of cells, closest first
+    fn brute_force_2d(worley: &Worley, x: f32, y: f32) -> Vec<f32> {
+        let (xf, yf) = (x - x.floor(), y - y.floor());
+        let (xb, yb) = (x.floor() as i64, y.floor() as i64);
+        let mut distances: Vec<f32> = (-4..=4).flat_map(|dx| (-4..=4).map(move |dy| (dx, dy))).map(|(dx, dy)| {
+            let h = hash::hash(worley.seed, &[worley.cell(xb + dx, PRIME_X), worley.cell(yb + dy, PRIME_Y)]);
+            let [fx, fy] = worley.feature::<2>(h);
+            Vec2::new(dx as f32 + fx - xf, dy as f32 + fy - yf).length()
+        }).collect();
+        distances.sort_by(f32::total_cmp);
+        distances
+    }
+
+    #[test]
+    fn finds_the_two_closest_points() {
+        let mut rng = ChaCha8Rng::seed_from_u64(4);
+        let f1 = Worley::new(&mut rng);
+        let f2 = f1.return_type(WorleyReturn::F2);
+        for i in 0..4000 {
+            let (x, y) = (i as f32 * 0.173 - 300., (i % 97) as f32 * 0.311 - 15.);
+            let distances = brute_force_2d(&f1, x, y);
+            assert!((f1.sample_2d(x, y) - distances[0]).abs() < 1e-5, "F1 at ({}, {})", x, y);
+            assert!((f2.sample_2d(x, y) - distances[1]).abs() < 1e-5, "F2 at ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        let mut rng = ChaCha8Rng::seed_from_u64(5);
+        for return_type in [WorleyReturn::F1, WorleyReturn::F2, WorleyReturn::F2MinusF1] {
+            let worley = Worley::new(&mut rng).return_type(return_type);
+            let h = 1e-3;
+            let mut checked = 0;
+            for i in 0..500 {
+                let (x, y) = (i as f32 * 0.0917 + 0.03, (i % 23) as f32 * 0.213 + 0.05);
+                // Skip samples where the closest points swap between the differences
+                let nearest = [worley.nearest_2d(x - h, y), worley.nearest_2d(x + h, y), worley.nearest_2d(x, y - h), worley.nearest_2d(x, y + h)];
+                let center = worley.nearest_2d(x, y);
+                let same = |n: &Nearest<Vec2>| (n.first.1 - center.first.1).length() < 0.01 && (n.second.1 - center.second.1).length() < 0.01;
+                if !nearest.iter().all(same) {
+                    continue;
+                }
+                let (_, gradient) = worley.sample_grad_2d(x, y);
+                let dx = (worley.sample_2d(x + h, y) - worley.sample_2d(x - h, y)) / (2. * h);
+                let dy = (worley.sample_2d(x, y + h) - worley.sample_2d(x, y - h)) / (2. * h);
+                assert!((gradient - Vec2::new(dx, dy)).length() < 1e-2, "{:?} at ({}, {}): {} vs {}", return_type, x, y, gradient, Vec2::new(dx, dy));
+                checked += 1;
+            }
+            assert!(checked > 400);
+        }
+    }
+}
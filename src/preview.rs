@@ -0,0 +1,71 @@
+use std::path::{Path, PathBuf};
+
+use bevy::{prelude::*, asset::FileAssetIo, tasks::{AsyncComputeTaskPool, TaskPool}};
+
+use astral::clouds::CLOUD_VOLUME_SIZE;
+use astral::noise::NoiseSource2D;
+use astral::raster::{rasterize_2d, value_range, save_grayscale, save_slices};
+
+use crate::{recipe::WorldRecipe, sky_plane::SkyPlane, terrain_plane::TerrainPlaneDescriptor};
+use crate::world::{WorldSeed, NoiseSettings, WorldRecipePath, arg_value};
+
+/// Images to write instead of launching the scene, set with `--preview-terrain <path>`,
+/// `--preview-water <path>` and `--preview-clouds <path>`. The extension picks the format:
+/// `.png` or `.raw` for 16-bit grayscale, or `.exr` for raw float values.
+#[derive(Clone, Debug, Default)]
+pub struct PreviewRequest {
+    pub terrain: Option<PathBuf>,
+    pub water: Option<PathBuf>,
+    /// Grid the heightmaps are sampled on, centered on the origin. `--preview-size <cells>` and
+    /// `--preview-spacing <units>` default to the water planes' grid.
+    pub grid: TerrainPlaneDescriptor,
+    /// Each slice of the cloud density volume is written next to this path, numbered from the bottom up
+    pub clouds: Option<PathBuf>
+}
+
+impl PreviewRequest {
+    /// Returns `None` when no preview was asked for
+    pub fn from_args() -> Option<PreviewRequest> {
+        let path = |layer: &str| arg_value(&format!("preview-{}", layer)).map(PathBuf::from);
+        let grid = TerrainPlaneDescriptor::from_args("preview");
+        let request = PreviewRequest { terrain: path("terrain"), water: path("water"), grid, clouds: path("clouds") };
+        (request.terrain.is_some() || request.water.is_some() || request.clouds.is_some()).then_some(request)
+    }
+
+    /// Generates each requested layer with the same seeds the scene would use and writes it out
+    pub fn run(&self, seed: WorldSeed, noise: NoiseSettings, recipe_path: &WorldRecipePath) -> Result<(), String> {
+        // Generation is spread over the same pool as in the app, which isn't running to set it up
+        AsyncComputeTaskPool::init(TaskPool::default);
+        if self.terrain.is_some() || self.water.is_some() {
+            let recipe = WorldRecipe::read(&FileAssetIo::get_base_path().join("assets").join(&recipe_path.0))?;
+            if let Some(path) = &self.terrain {
+                let heightmap = recipe.terrain.build_2d(noise.terrain, &mut seed.rng("terrain"))?;
+                save_heightmap(path, &self.grid, &heightmap)?;
+            }
+            // The water layers are all shifted copies of one heightmap, so only that is written
+            if let Some(path) = &self.water {
+                let heightmap = recipe.water.heightmap.build_2d(noise.water, &mut seed.rng("water"))?;
+                save_heightmap(path, &self.grid, &heightmap)?;
+            }
+        }
+
+        if let Some(path) = &self.clouds {
+            let density = SkyPlane::density_volume(&mut seed.rng("sky"), noise.clouds)?;
+            let range = value_range(&density);
+            let size = CLOUD_VOLUME_SIZE;
+            let paths = save_slices(path, &density, size, size, size, range).map_err(|err| format!("Couldn't write {}: {}", path.display(), err))?;
+            println!("Wrote {} cloud slices next to {} (range {:.3} to {:.3})", paths.len(), path.display(), range.0, range.1);
+        }
+        Ok(())
+    }
+}
+
+// Rasterizes a heightmap at the vertices of `grid`
+pub fn save_heightmap(path: &Path, grid: &TerrainPlaneDescriptor, heightmap: &impl NoiseSource2D) -> Result<(), String> {
+    let (width, height) = ((grid.size.x + 1) as usize, (grid.size.y + 1) as usize);
+    let heights = rasterize_2d(heightmap, grid.origin, Vec2::splat(grid.spacing), width, height);
+    let range = value_range(&heights);
+    save_grayscale(path, &heights, width, height, range).map_err(|err| format!("Couldn't write {}: {}", path.display(), err))?;
+    println!("Wrote {} (range {:.3} to {:.3})", path.display(), range.0, range.1);
+    Ok(())
+}
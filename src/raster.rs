@@ -0,0 +1,70 @@
+// Rasterizes noise into images, so layers can be looked at and diffed without launching the scene
+
+use std::{fs, path::{Path, PathBuf}};
+
+use bevy::prelude::*;
+use image::{ImageBuffer, ImageError, ImageFormat, ImageResult, Luma, Rgb, error::{UnsupportedError, UnsupportedErrorKind}};
+
+use crate::noise::NoiseSource2D;
+
+// Samples `noise` on a width x height grid starting at `origin`, row by row
+pub fn rasterize_2d(noise: &impl NoiseSource2D, origin: Vec2, step: Vec2, width: usize, height: usize) -> Vec<f32> {
+    let mut data = vec![0.; width * height];
+    noise.sample_grid_2d(origin, step, width, &mut data);
+    data
+}
+
+// Smallest and largest finite values, for mapping the data onto the image's range
+pub fn value_range(data: &[f32]) -> (f32, f32) {
+    data.iter().filter(|v| v.is_finite()).fold((f32::MAX, f32::MIN), |(min, max), &v| (min.min(v), max.max(v)))
+}
+
+// Maps `range` onto the full range of 16-bit values
+fn quantize_16(data: &[f32], range: (f32, f32)) -> Vec<u16> {
+    let (min, max) = range;
+    let scale = if max > min { u16::MAX as f32 / (max - min) } else { 0. };
+    data.iter().map(|v| ((v - min) * scale).clamp(0., u16::MAX as f32).round() as u16).collect()
+}
+
+// Writes `data` (row by row) as a grayscale image in the format given by the path's extension: a 16-bit
+// PNG or headerless little-endian `.raw` file with `range` mapped onto black to white, or a float EXR
+// holding the raw values
+pub fn save_grayscale(path: &Path, data: &[f32], width: usize, height: usize, range: (f32, f32)) -> ImageResult<()> {
+    assert_eq!(data.len(), width * height, "Image data doesn't match its size");
+    if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("raw")) {
+        let bytes: Vec<u8> = quantize_16(data, range).into_iter().flat_map(u16::to_le_bytes).collect();
+        return Ok(fs::write(path, bytes)?);
+    }
+    match ImageFormat::from_path(path)? {
+        ImageFormat::Png => {
+            let pixels = quantize_16(data, range);
+            let image: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::from_raw(width as u32, height as u32, pixels).unwrap();
+            image.save(path)
+        },
+        // The EXR encoder only takes RGB(A), so the value goes in every channel
+        ImageFormat::OpenExr => {
+            let pixels = data.iter().flat_map(|&v| [v; 3]).collect();
+            let image: ImageBuffer<Rgb<f32>, Vec<f32>> = ImageBuffer::from_raw(width as u32, height as u32, pixels).unwrap();
+            image.save(path)
+        },
+        format => Err(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+            format.into(),
+            UnsupportedErrorKind::Format(format.into())
+        )))
+    }
+}
+
+// Writes each z slice of a width x height x depth volume (x fastest, then y) as its own image, numbered
+// after the file stem: `clouds.png` becomes `clouds_000.png`, `clouds_001.png`, ... All slices share
+// `range`, so they can be compared with each other.
+pub fn save_slices(path: &Path, data: &[f32], width: usize, height: usize, depth: usize, range: (f32, f32)) -> ImageResult<Vec<PathBuf>> {
+    assert_eq!(data.len(), width * height * depth, "Volume data doesn't match its size");
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let digits = (depth.max(2) - 1).to_string().len();
+    data.chunks(width * height).enumerate().map(|(z, slice)| {
+        let slice_path = path.with_file_name(format!("{}_{:0digits$}.{}", stem, z, extension, digits = digits));
+        save_grayscale(&slice_path, slice, width, height, range)?;
+        Ok(slice_path)
+    }).collect()
+}
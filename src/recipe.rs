@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use bevy::{prelude::*, asset::{AssetLoader, LoadContext, LoadedAsset}, reflect::{TypeUuid, TypePath}, utils::BoxedFuture};
+use serde::Deserialize;
+
+use astral::biome::ClimateRecipe;
+use astral::terrain_recipe::TerrainRecipe;
+
+#[derive(Default)]
+pub struct WorldRecipePlugin {}
+
+impl Plugin for WorldRecipePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<WorldRecipe>();
+        app.init_asset_loader::<WorldRecipeLoader>();
+    }
+}
+
+/// Noise graphs a world is generated from, loaded from `assets/worlds/*.terrain.ron`
+#[derive(Deserialize, TypeUuid, TypePath, Clone, Debug)]
+#[uuid = "8a4c7d52-3f1e-4b9a-a6d0-5e2b71c93f08"]
+pub struct WorldRecipe {
+    pub terrain: TerrainRecipe,
+    pub water: WaterRecipe,
+    /// Temperature and moisture the biomes are picked from. Without it the world has the same climate
+    /// everywhere, apart from latitude and altitude.
+    #[serde(default)]
+    pub climate: ClimateRecipe
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct WaterRecipe {
+    pub heightmap: TerrainRecipe,
+    /// One plane per layer, each with the heightmap shifted so the layers don't line up
+    pub layers: Vec<WaterLayer>
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct WaterLayer {
+    #[serde(default)]
+    pub offset: [f32; 2],
+    pub height: f32
+}
+
+impl WorldRecipe {
+    /// Reads a recipe straight from disk, for tools that run without the asset server
+    pub fn read(path: &Path) -> Result<WorldRecipe, String> {
+        let bytes = std::fs::read(path).map_err(|err| format!("Couldn't read {}: {}", path.display(), err))?;
+        ron::de::from_bytes(&bytes).map_err(|err| format!("Invalid recipe {}: {}", path.display(), err))
+    }
+}
+
+/// The recipe the current world is generated from
+#[derive(Resource)]
+pub struct WorldRecipeHandle(pub Handle<WorldRecipe>);
+
+#[derive(Default)]
+pub struct WorldRecipeLoader;
+
+impl AssetLoader for WorldRecipeLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let recipe: WorldRecipe = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(recipe));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["terrain.ron"]
+    }
+}
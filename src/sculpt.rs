@@ -0,0 +1,229 @@
+// Hand edits to a heightmap, kept as offsets on top of it so they survive regenerating the terrain
+
+use std::{collections::HashMap, sync::Arc};
+
+use bevy::prelude::*;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::{Serialize, Deserialize};
+
+use crate::erosion::{HeightGrid, ThermalErosion};
+use crate::noise::{NoiseSource2D, NoiseGrad2D, NoiseKind, Gradient2D};
+
+// Lattice points along each side of a tile of offsets
+const TILE_SIZE: i32 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BrushKind {
+    Raise,
+    Lower,
+    // Pulls heights towards the average of their neighbours
+    Smooth,
+    // Pulls heights towards `height`
+    Flatten { height: f32 },
+    // Adds gradient noise drawn from `seed`
+    Noise { frequency: f32, seed: u64 },
+    // Slumps slopes steeper than `talus_angle`, like `ThermalErosion`
+    Erode { talus_angle: f32 }
+}
+
+// What a dab does to the points within `radius` of it. Raising, lowering and noise move points up to
+// `strength` units per dab, the others move them `strength` of the way to their target. The full effect
+// fades out to nothing over the outer `falloff` fraction of the radius.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Brush {
+    pub kind: BrushKind,
+    pub radius: f32,
+    pub strength: f32,
+    pub falloff: f32
+}
+
+impl Brush {
+    // How much of the brush's strength reaches `distance` from its center
+    fn weight(&self, distance: f32) -> f32 {
+        let d = distance / self.radius;
+        let inner = 1. - self.falloff.clamp(0., 1.);
+        if d >= 1. {
+            0.
+        } else if d <= inner {
+            1.
+        } else {
+            let t = (d - inner) / (1. - inner);
+            1. - t * t * (3. - 2. * t)
+        }
+    }
+}
+
+// Dabs of one brush, in the order they were applied
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Stroke {
+    pub brush: Brush,
+    pub dabs: Vec<[f32; 2]>
+}
+
+impl Stroke {
+    // Area the stroke can have changed, which is empty without any dabs
+    pub fn bounds(&self) -> Rect {
+        // Built directly, since `Rect::new` would swap the corners into an infinite rect
+        self.dabs.iter().fold(Rect { min: Vec2::MAX, max: Vec2::MIN }, |bounds, &dab| {
+            bounds.union(Rect::from_center_half_size(Vec2::from(dab), Vec2::splat(self.brush.radius)))
+        })
+    }
+}
+
+// Height offsets at lattice points `spacing` apart, stored in tiles that are only allocated once edited.
+// Between lattice points the offsets are interpolated bilinearly.
+#[derive(Clone, Debug, Default)]
+pub struct SculptLayer {
+    spacing: f32,
+    tiles: HashMap<IVec2, Vec<f32>>
+}
+
+impl SculptLayer {
+    pub fn new(spacing: f32) -> SculptLayer {
+        SculptLayer { spacing, tiles: HashMap::new() }
+    }
+
+    // Clears the layer and applies `strokes` to `source` again in order, which is how strokes are undone
+    pub fn replay(&mut self, source: &impl NoiseSource2D, strokes: &[Stroke]) {
+        self.tiles.clear();
+        for stroke in strokes {
+            for &dab in stroke.dabs.iter() {
+                self.apply(source, &stroke.brush, Vec2::from(dab));
+            }
+        }
+    }
+
+    pub fn offset(&self, lattice: IVec2) -> f32 {
+        let tile = lattice.div_euclid(IVec2::splat(TILE_SIZE));
+        let local = lattice.rem_euclid(IVec2::splat(TILE_SIZE));
+        self.tiles.get(&tile).map_or(0., |offsets| offsets[(local.y * TILE_SIZE + local.x) as usize])
+    }
+
+    fn offset_mut(&mut self, lattice: IVec2) -> &mut f32 {
+        let tile = lattice.div_euclid(IVec2::splat(TILE_SIZE));
+        let local = lattice.rem_euclid(IVec2::splat(TILE_SIZE));
+        let offsets = self.tiles.entry(tile).or_insert_with(|| vec![0.; (TILE_SIZE * TILE_SIZE) as usize]);
+        &mut offsets[(local.y * TILE_SIZE + local.x) as usize]
+    }
+
+    // Slope of the offsets at a lattice point, by central differences
+    fn lattice_gradient(&self, lattice: IVec2) -> Vec2 {
+        let x = self.offset(lattice + IVec2::X) - self.offset(lattice - IVec2::X);
+        let y = self.offset(lattice + IVec2::Y) - self.offset(lattice - IVec2::Y);
+        Vec2::new(x, y) / (2. * self.spacing)
+    }
+
+    // Offset and its gradient at `p`. The gradient is interpolated from the lattice points' own, so
+    // normals don't crease along the lattice lines.
+    pub fn sample(&self, p: Vec2) -> (f32, Vec2) {
+        if self.tiles.is_empty() {
+            return (0., Vec2::ZERO);
+        }
+        let p = p / self.spacing;
+        let cell = p.floor();
+        let (t, lattice) = (p - cell, cell.as_ivec2());
+        let corners = [IVec2::ZERO, IVec2::X, IVec2::Y, IVec2::ONE].map(|corner| lattice + corner);
+        let weights = [(1. - t.x) * (1. - t.y), t.x * (1. - t.y), (1. - t.x) * t.y, t.x * t.y];
+        corners.iter().zip(weights).fold((0., Vec2::ZERO), |(offset, gradient), (&corner, weight)| {
+            (offset + self.offset(corner) * weight, gradient + self.lattice_gradient(corner) * weight)
+        })
+    }
+
+    // Applies one dab of `brush` at `center` to the heights of `source` with this layer on top, and
+    // returns the area it changed
+    pub fn apply(&mut self, source: &impl NoiseSource2D, brush: &Brush, center: Vec2) -> Rect {
+        let min = ((center - brush.radius) / self.spacing).floor().as_ivec2() - 1;
+        let max = ((center + brush.radius) / self.spacing).ceil().as_ivec2() + 1;
+        let size = (max - min + 1).as_uvec2();
+        // Heights as they are before this dab, which every point reads
+        let heights: Vec<f32> = (0..size.x * size.y).map(|i| {
+            let lattice = min + IVec2::new((i % size.x) as i32, (i / size.x) as i32);
+            let p = lattice.as_vec2() * self.spacing;
+            source.sample_2d(p.x, p.y) + self.offset(lattice)
+        }).collect();
+        let index = |local: IVec2| (local.y as u32 * size.x + local.x as u32) as usize;
+
+        // Where the heights are heading, for the brushes that pull towards something
+        let targets: Option<Vec<f32>> = match brush.kind {
+            BrushKind::Smooth => Some((0..heights.len()).map(|i| {
+                let local = IVec2::new((i as u32 % size.x) as i32, (i as u32 / size.x) as i32);
+                let neighbours = [IVec2::ZERO, IVec2::X, IVec2::NEG_X, IVec2::Y, IVec2::NEG_Y]
+                    .map(|offset| (local + offset).clamp(IVec2::ZERO, size.as_ivec2() - 1));
+                neighbours.iter().map(|&n| heights[index(n)]).sum::<f32>() / neighbours.len() as f32
+            }).collect()),
+            BrushKind::Flatten { height } => Some(vec![height; heights.len()]),
+            BrushKind::Erode { talus_angle } => {
+                // The erosion works in cells, like the recipe's erosion passes
+                let scaled = heights.iter().map(|height| height / self.spacing).collect();
+                let mut grid = HeightGrid::new(size.x as usize, size.y as usize, scaled);
+                ThermalErosion { iterations: 4, talus_angle, rate: 0.5, tolerance: 0. }.relax(&mut grid);
+                Some(grid.heights.iter().map(|height| height * self.spacing).collect())
+            },
+            _ => None
+        };
+        let noise = match brush.kind {
+            BrushKind::Noise { seed, .. } => Some(Gradient2D::new(NoiseKind::default(), &mut ChaCha8Rng::seed_from_u64(seed))),
+            _ => None
+        };
+
+        for y in 0..size.y as i32 {
+            for x in 0..size.x as i32 {
+                let lattice = min + IVec2::new(x, y);
+                let p = lattice.as_vec2() * self.spacing;
+                let weight = brush.weight(p.distance(center));
+                if weight <= 0. {
+                    continue;
+                }
+                let i = index(IVec2::new(x, y));
+                let change = match (brush.kind, &targets, &noise) {
+                    (BrushKind::Raise, _, _) => brush.strength * weight,
+                    (BrushKind::Lower, _, _) => -brush.strength * weight,
+                    (BrushKind::Noise { frequency, .. }, _, Some(noise)) => {
+                        brush.strength * weight * noise.sample_2d(p.x * frequency, p.y * frequency)
+                    },
+                    (_, Some(targets), _) => (targets[i] - heights[i]) * (brush.strength * weight).min(1.),
+                    _ => 0.
+                };
+                *self.offset_mut(lattice) += change;
+            }
+        }
+        Rect::from_center_half_size(center, Vec2::splat(brush.radius + self.spacing))
+    }
+}
+
+// `source` with a sculpt layer's offsets added on
+#[derive(Clone)]
+pub struct Sculpted<S> {
+    pub source: S,
+    pub layer: Arc<SculptLayer>
+}
+
+impl<S: NoiseSource2D> NoiseSource2D for Sculpted<S> {
+    fn sample_2d(&self, x: f32, y: f32) -> f32 {
+        self.source.sample_2d(x, y) + self.layer.sample(Vec2::new(x, y)).0
+    }
+}
+
+impl<S: NoiseGrad2D> NoiseGrad2D for Sculpted<S> {
+    fn sample_grad_2d(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let (height, gradient) = self.source.sample_grad_2d(x, y);
+        let (offset, offset_gradient) = self.layer.sample(Vec2::new(x, y));
+        (height + offset, gradient + offset_gradient)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stroke_bounds_cover_only_its_dabs() {
+        let brush = Brush { kind: BrushKind::Raise, radius: 8., strength: 1., falloff: 0.5 };
+        let stroke = Stroke { brush, dabs: vec![[10., -4.]] };
+        assert_eq!(stroke.bounds(), Rect::new(2., -12., 18., 4.));
+        let stroke = Stroke { brush, dabs: vec![[10., -4.], [30., 0.]] };
+        assert_eq!(stroke.bounds(), Rect::new(2., -12., 38., 8.));
+        assert!(Stroke { brush, dabs: Vec::new() }.bounds().is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+use std::fs;
+
+use bevy::prelude::*;
+
+use astral::sculpt::{Brush, BrushKind, Stroke};
+
+use crate::terrain_chunks::TerrainChunks;
+
+// Turns sculpting on and off. While it's on, holding the left mouse button with the cursor released
+// sculpts the terrain under it.
+const SCULPT_KEY: KeyCode = KeyCode::B;
+const UNDO_KEY: KeyCode = KeyCode::Z;
+const SAVE_KEY: KeyCode = KeyCode::F5;
+const LOAD_KEY: KeyCode = KeyCode::F9;
+// Where strokes are saved to and loaded from, relative to the working directory
+const STROKES_FILE: &str = "sculpt.ron";
+// Seconds between dabs while the mouse button is held
+const DAB_INTERVAL: f32 = 1. / 30.;
+// Farthest the cursor can sculpt from the camera
+const SCULPT_DISTANCE: f32 = 10_000.;
+// Frequency of the noise brush, in cycles per unit
+const NOISE_FREQUENCY: f32 = 0.05;
+
+#[derive(Default)]
+pub struct SculptingPlugin {}
+
+impl Plugin for SculptingPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Sculpting {
+            enabled: false,
+            brush: Brush { kind: BrushKind::Raise, radius: 16., strength: 0.5, falloff: 0.5 },
+            strokes: Vec::new(),
+            current: None,
+            last_dab: 0.
+        });
+        app.add_systems(Update, (choose_brush, sculpt, edit_strokes).chain());
+    }
+}
+
+// Brush in hand and every stroke made with it so far, oldest first, which are replayed to undo one
+#[derive(Resource)]
+pub struct Sculpting {
+    enabled: bool,
+    brush: Brush,
+    strokes: Vec<Stroke>,
+    // Stroke being drawn while the mouse button is held
+    current: Option<Stroke>,
+    last_dab: f32
+}
+
+impl Sculpting {
+    // While sculpting is on the left mouse button belongs to the brush
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn report(&self) {
+        let Brush { kind, radius, strength, .. } = self.brush;
+        println!("Sculpting {}: {:?}, radius {:.0}, strength {:.2}", if self.enabled { "on" } else { "off" }, kind, radius, strength);
+    }
+
+    fn finish_stroke(&mut self) {
+        self.strokes.extend(self.current.take().filter(|stroke| !stroke.dabs.is_empty()));
+    }
+}
+
+// 1 to 6 pick the brush, [ and ] change its radius and - and = its strength
+fn choose_brush(keys: Res<Input<KeyCode>>, mut sculpting: ResMut<Sculpting>) {
+    let brush = &mut sculpting.brush;
+    let kinds = [
+        (KeyCode::Key1, BrushKind::Raise),
+        (KeyCode::Key2, BrushKind::Lower),
+        (KeyCode::Key3, BrushKind::Smooth),
+        // Heights and seeds are picked when a stroke starts
+        (KeyCode::Key4, BrushKind::Flatten { height: 0. }),
+        (KeyCode::Key5, BrushKind::Noise { frequency: NOISE_FREQUENCY, seed: 0 }),
+        (KeyCode::Key6, BrushKind::Erode { talus_angle: 30. })
+    ];
+    let mut changed = keys.just_pressed(SCULPT_KEY);
+    for (key, kind) in kinds {
+        if keys.just_pressed(key) {
+            brush.kind = kind;
+            changed = true;
+        }
+    }
+    if keys.just_pressed(KeyCode::BracketLeft) {
+        brush.radius = (brush.radius / 1.25).max(1.);
+        changed = true;
+    }
+    if keys.just_pressed(KeyCode::BracketRight) {
+        brush.radius *= 1.25;
+        changed = true;
+    }
+    if keys.just_pressed(KeyCode::Minus) {
+        brush.strength = (brush.strength / 1.25).max(0.01);
+        changed = true;
+    }
+    if keys.just_pressed(KeyCode::Equals) {
+        brush.strength *= 1.25;
+        changed = true;
+    }
+    if keys.just_pressed(SCULPT_KEY) {
+        sculpting.enabled = !sculpting.enabled;
+    }
+    if changed {
+        sculpting.report();
+    }
+}
+
+// Dabs the brush on the terrain under the cursor while the left mouse button is held
+fn sculpt(
+    mouse: Res<Input<MouseButton>>,
+    window: Query<&Window>,
+    camera: Query<(&Camera, &GlobalTransform), With<Camera3d>>,
+    chunks: Option<ResMut<TerrainChunks>>,
+    time: Res<Time>,
+    mut sculpting: ResMut<Sculpting>
+) {
+    let (Ok(window), Ok((camera, transform)), Some(mut chunks)) = (window.get_single(), camera.get_single(), chunks) else {
+        return;
+    };
+    if !sculpting.enabled || !window.cursor.visible || !mouse.pressed(MouseButton::Left) {
+        sculpting.finish_stroke();
+        return;
+    }
+    if sculpting.current.is_some() && time.elapsed_seconds() - sculpting.last_dab < DAB_INTERVAL {
+        return;
+    }
+    let Some(ray) = window.cursor_position().and_then(|cursor| camera.viewport_to_world(transform, cursor)) else {
+        return;
+    };
+    // Only the ground is sculpted, so the ray goes through water
+    let Some(hit) = chunks.cast_ray(ray, SCULPT_DISTANCE) else {
+        return;
+    };
+    if sculpting.current.is_none() {
+        let mut brush = sculpting.brush;
+        match &mut brush.kind {
+            BrushKind::Flatten { height } => *height = hit.position.y,
+            // Every stroke gets its own pattern, the same one each time the strokes are replayed
+            BrushKind::Noise { seed, .. } => *seed = sculpting.strokes.len() as u64,
+            _ => {}
+        }
+        sculpting.current = Some(Stroke { brush, dabs: Vec::new() });
+    }
+    sculpting.last_dab = time.elapsed_seconds();
+    let stroke = sculpting.current.as_mut().unwrap();
+    let center = Vec2::new(hit.position.x, hit.position.z);
+    chunks.sculpt(|source, layer| layer.apply(source, &stroke.brush, center));
+    stroke.dabs.push(center.to_array());
+}
+
+// Undoes the last stroke and saves or loads every stroke
+fn edit_strokes(keys: Res<Input<KeyCode>>, chunks: Option<ResMut<TerrainChunks>>, mut sculpting: ResMut<Sculpting>) {
+    let Some(mut chunks) = chunks else {
+        return;
+    };
+    if keys.just_pressed(UNDO_KEY) {
+        sculpting.finish_stroke();
+        match sculpting.strokes.pop() {
+            Some(undone) => {
+                chunks.sculpt(|source, layer| {
+                    layer.replay(source, &sculpting.strokes);
+                    undone.bounds()
+                });
+                println!("Undid a stroke, {} left", sculpting.strokes.len());
+            },
+            None => println!("Nothing to undo")
+        }
+    }
+    if keys.just_pressed(SAVE_KEY) {
+        sculpting.finish_stroke();
+        let saved = ron::ser::to_string_pretty(&sculpting.strokes, ron::ser::PrettyConfig::default())
+            .map_err(|err| err.to_string())
+            .and_then(|text| fs::write(STROKES_FILE, text).map_err(|err| err.to_string()));
+        match saved {
+            Ok(()) => println!("Saved {} strokes to {}", sculpting.strokes.len(), STROKES_FILE),
+            Err(err) => eprintln!("Couldn't save {}: {}", STROKES_FILE, err)
+        }
+    }
+    if keys.just_pressed(LOAD_KEY) {
+        sculpting.finish_stroke();
+        let loaded = fs::read(STROKES_FILE)
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| ron::de::from_bytes::<Vec<Stroke>>(&bytes).map_err(|err| err.to_string()));
+        match loaded {
+            Ok(strokes) => {
+                // Both the strokes being replaced and the loaded ones change the terrain
+                let area = sculpting.strokes.iter().chain(strokes.iter())
+                    .fold(Rect { min: Vec2::MAX, max: Vec2::MIN }, |area, stroke| area.union(stroke.bounds()));
+                chunks.sculpt(|source, layer| {
+                    layer.replay(source, &strokes);
+                    area
+                });
+                println!("Loaded {} strokes from {}", strokes.len(), STROKES_FILE);
+                sculpting.strokes = strokes;
+            },
+            Err(err) => eprintln!("Couldn't load {}: {}", STROKES_FILE, err)
+        }
+    }
+}
@@ -1,7 +1,14 @@
-use bevy::{prelude::*, render::{render_resource::{ShaderRef, AsBindGroup, TextureDescriptor, Extent3d, TextureDimension, TextureFormat, TextureUsages, SamplerDescriptor, AddressMode, FilterMode, TextureViewDescriptor, TextureViewDimension, TextureAspect}, texture::ImageSampler}, reflect::TypeUuid};
+use bevy::{prelude::*, render::{render_resource::{ShaderRef, AsBindGroup, TextureDescriptor, Extent3d, TextureDimension, TextureFormat, TextureUsages, SamplerDescriptor, AddressMode, FilterMode, TextureViewDescriptor, TextureViewDimension, TextureAspect}, texture::ImageSampler}, reflect::TypeUuid, utils::Instant};
 use bevy_inspector_egui::{quick::AssetInspectorPlugin, InspectorOptions, prelude::ReflectInspectorOptions};
 
-use crate::perlin_3d;
+use std::sync::Arc;
+
+use rand::Rng;
+
+use astral::clouds::{self, CLOUD_VOLUME_SIZE, CLOUD_DETAIL};
+use astral::noise::{Perlin3D, Fractal, NoiseSource3D, NoiseKind, Curl3D};
+
+use crate::background::{Background, par_chunks_mut};
 
 #[derive(Default)]
 pub struct SkyPlanePlugin {}
@@ -12,93 +19,66 @@ impl Plugin for SkyPlanePlugin {
         app.add_asset::<SkyPlaneMaterial>();
         app.add_plugins(MaterialPlugin::<SkyPlaneMaterial>::default());
         app.add_plugins(AssetInspectorPlugin::<SkyPlaneMaterial>::default());
-        app.add_systems(Update, update);
+        app.add_systems(Update, (update, finish_textures));
     }
 }
 
+// Side of the cloud box, which the cloud texture spans once at noise_size 1
+const SKY_WIDTH: f32 = 2048.;
+// Texels along each side of the flow texture, which spans FLOW_PERIOD lattice cells of its potential
+const FLOW_VOLUME_SIZE: usize = 64;
+const FLOW_PERIOD: u32 = 4;
+
+// Curl noise the clouds are advected along
+pub type FlowField = Curl3D<Fractal<Perlin3D>>;
+
+// The flow field baked into the sky's flow texture, kept so CPU systems can follow the same wind as the
+// clouds. It's sampled in lattice units of its potential: the texture spans FLOW_PERIOD of them along each
+// axis, once per repeat of the cloud texture.
+#[derive(Resource, Clone)]
+pub struct Wind(pub Arc<FlowField>);
+
 #[derive(Component)]
 pub struct SkyPlane {
     pub mesh: Handle<Mesh>,
-    pub material: Handle<SkyPlaneMaterial>
+    pub material: Handle<SkyPlaneMaterial>,
+    // Density and flow textures, until they have been swapped into the material
+    generating: Option<Background<(Image, Image)>>
 }
 
 impl SkyPlane {
-    pub fn new(meshes: &mut Assets<Mesh>, materials: &mut Assets<SkyPlaneMaterial>, images: &mut Assets<Image>) -> SkyPlane {
-        let width = 2048.0;
-        let corner = Vec3::new(width/2.0, 200.0, width/2.0);
+    pub fn new(meshes: &mut Assets<Mesh>, materials: &mut Assets<SkyPlaneMaterial>, images: &mut Assets<Image>, rng: &mut impl Rng, noise: NoiseKind) -> (SkyPlane, Wind) {
+        let corner = Vec3::new(SKY_WIDTH/2.0, 200.0, SKY_WIDTH/2.0);
         let mesh = shape::Box::from_corners(corner, -corner).into();
 
-        let perlin_size = 256;
-        let perlin_detail = 2.;
-        let perlin_func = perlin_3d((perlin_size as f32 * perlin_detail) as usize);
-        let layered_perlin_func = |x: f32, y: f32, z: f32| {
-            let a = perlin_func(x / 3.0, y / 3.0, z / 3.0) * 1.0;
-            let b = perlin_func(x / 13.0, y / 13.0, z / 13.0) * 4.0;
-            let c = perlin_func(x / 43.0, y / 43.0, z / 43.0) * 16.0;
-            let d = perlin_func(x / 197.0, y / 197.0, z / 197.0) * 64.0;
-            let e = -(y - 128.0).powi(2) / 16384.0;
-            a + b + c + d + e
-        };
+        // Noise textures are sampled in the background. Until they are ready the material reads from
+        // single texel placeholders, which leave the sky clear.
+        // `NoiseSettings::from_args` only lets through noise that tiles
+        let density = clouds::density(rng, noise).unwrap_or_else(|err| panic!("{}", err));
+        let wind = Wind(Arc::new(Self::flow(rng)));
+        let flow = wind.clone();
+        let img_handle = images.add(volume_texture("3D Perlin Noise", vec![0; 4], 1, TextureFormat::R32Float));
+        let flow_handle = images.add(volume_texture("3D Curl Noise", vec![0; 16], 1, TextureFormat::Rgba32Float));
+        let generating = Background::spawn(move || {
+            let start = Instant::now();
+            let density_data = Self::sample_density(&density);
+            let perlin_data = density_data.iter().flat_map(|density| density.to_ne_bytes()).collect();
+            let density_image = volume_texture("3D Perlin Noise", perlin_data, CLOUD_VOLUME_SIZE, TextureFormat::R32Float);
+
+            let mut flow_data = vec![Vec3::ZERO; FLOW_VOLUME_SIZE * FLOW_VOLUME_SIZE * FLOW_VOLUME_SIZE];
+            let step = Vec3::splat(FLOW_PERIOD as f32 / FLOW_VOLUME_SIZE as f32);
+            par_chunks_mut(&mut flow_data, FLOW_VOLUME_SIZE * FLOW_VOLUME_SIZE, |z, slice| {
+                flow.0.sample_curl_grid_3d(Vec3::Z * z as f32 * step.z, step, FLOW_VOLUME_SIZE, FLOW_VOLUME_SIZE, slice);
+            });
+            let flow_data = flow_data.iter().flat_map(|velocity| velocity.extend(0.).to_array()).flat_map(f32::to_ne_bytes).collect();
+            let flow_image = volume_texture("3D Curl Noise", flow_data, FLOW_VOLUME_SIZE, TextureFormat::Rgba32Float);
+            println!("Clouds ({}) generated in {:?}", noise, start.elapsed());
+            (density_image, flow_image)
+        });
 
-        // Generate noise texture with fixed detail
-        let mut perlin_data = vec![0; perlin_size * perlin_size * perlin_size * 4]; // 4 bytes per float
-        for z in 0..perlin_size {
-            for y in 0..perlin_size {
-                for x in 0..perlin_size {
-                    let cell_idx = x + y * perlin_size + z * perlin_size * perlin_size;
-                    let perlin = layered_perlin_func(x as f32 * perlin_detail, y as f32 * perlin_detail, z as f32 * perlin_detail);
-                    let data = perlin.to_ne_bytes();
-                    perlin_data[4 * cell_idx + 0] = data[0];
-                    perlin_data[4 * cell_idx + 1] = data[1];
-                    perlin_data[4 * cell_idx + 2] = data[2];
-                    perlin_data[4 * cell_idx + 3] = data[3];
-                }
-            }
-        }
-        let image = Image {
-            data: perlin_data,
-            texture_descriptor: TextureDescriptor {
-                label: "3D Perlin Noise Texture".into(),
-                size: Extent3d {
-                    width: perlin_size as u32,
-                    height: perlin_size as u32,
-                    depth_or_array_layers: perlin_size as u32
-                },
-                mip_level_count: 1,
-                sample_count: 1,
-                dimension: TextureDimension::D3,
-                format: TextureFormat::R32Float,
-                usage: TextureUsages::TEXTURE_BINDING,
-                view_formats: &[],
-            },
-            sampler_descriptor: ImageSampler::Descriptor(SamplerDescriptor {
-                label: "3D Perlin Noise Sampler".into(),
-                address_mode_u: AddressMode::Repeat,
-                address_mode_v: AddressMode::Repeat,
-                address_mode_w: AddressMode::Repeat,
-                mag_filter: FilterMode::Linear,
-                min_filter: FilterMode::Linear,
-                mipmap_filter: FilterMode::Linear,
-                lod_min_clamp: 1.0,
-                lod_max_clamp: 1.0,
-                compare: None,
-                anisotropy_clamp: 1,
-                border_color: None
-            }),
-            texture_view_descriptor: Some(TextureViewDescriptor {
-                label: "3D Perlin Noise View".into(),
-                format: Some(TextureFormat::R32Float),
-                dimension: Some(TextureViewDimension::D3),
-                aspect: TextureAspect::All,
-                base_mip_level: 0,
-                mip_level_count: None,
-                base_array_layer: 0,
-                array_layer_count: None,
-            }),
-        };
-        let img_handle = images.add(image);
         let mat: Handle<SkyPlaneMaterial> = materials.add(SkyPlaneMaterial {
             noise_3d: img_handle,
+            flow_3d: flow_handle,
             step_size: 1.0,
             noise_size: 1.0,
             noise_scale: 0.03,
@@ -106,10 +86,35 @@ impl SkyPlane {
             noise_bias: 0.0,
             noise_thresh: 10.0,
             step_count: 200,
-            camera_pos: Vec3::ZERO
+            camera_pos: Vec3::ZERO,
+            time: 0.0,
+            flow_strength: 0.02,
+            flow_speed: 0.05,
+            wind_speed: 4.0
+        });
+
+        (SkyPlane { mesh: meshes.add(mesh), material: mat, generating: Some(generating) }, wind)
+    }
+
+    // Cloud density sampled on a CLOUD_VOLUME_SIZE^3 grid, x fastest, then y, then z. Tiles along every axis
+    // except the fade towards the top and bottom.
+    pub fn density_volume(rng: &mut impl Rng, noise: NoiseKind) -> Result<Vec<f32>, String> {
+        Ok(Self::sample_density(&clouds::density(rng, noise)?))
+    }
+
+    fn sample_density(density: &(impl NoiseSource3D + Sync)) -> Vec<f32> {
+        let size = CLOUD_VOLUME_SIZE;
+        let mut density_data = vec![0.; size * size * size];
+        par_chunks_mut(&mut density_data, size * size, |z, slice| {
+            density.sample_grid_3d(Vec3::new(0., 0., z as f32 * CLOUD_DETAIL), Vec3::splat(CLOUD_DETAIL), size, size, slice);
         });
+        density_data
+    }
 
-        SkyPlane { mesh: meshes.add(mesh), material: mat }
+    // Wind that advects the clouds, sampled in lattice units of its potential, so velocities are around 1
+    // and the shader scales them by `flow_strength`
+    fn flow(rng: &mut impl Rng) -> FlowField {
+        Curl3D::new(Fractal::fbm(Perlin3D::new(rng).period(FLOW_PERIOD)).octaves(2).lacunarity(2.))
     }
 }
 
@@ -120,6 +125,9 @@ pub struct SkyPlaneMaterial {
     #[texture(0, dimension = "3d")]
     #[sampler(1)]
     noise_3d: Handle<Image>,
+    #[texture(3, dimension = "3d")]
+    #[sampler(4)]
+    flow_3d: Handle<Image>,
 
     #[uniform(2)]
     #[inspector(speed = 0.01)]
@@ -142,7 +150,20 @@ pub struct SkyPlaneMaterial {
     #[uniform(2)]
     step_count: u32,
     #[uniform(2)]
-    camera_pos: Vec3
+    camera_pos: Vec3,
+    #[uniform(2)]
+    time: f32,
+    // How far (in texture space) the flow field drags the clouds over one advection cycle
+    #[uniform(2)]
+    #[inspector(speed = 0.001)]
+    flow_strength: f32,
+    // Advection cycles per second
+    #[uniform(2)]
+    #[inspector(speed = 0.01)]
+    flow_speed: f32,
+    // Steady drift along x, in world units per second
+    #[inspector(speed = 0.1)]
+    wind_speed: f32
 }
 
 impl Material for SkyPlaneMaterial {
@@ -170,9 +191,71 @@ impl Material for SkyPlaneMaterial {
     }
 }
 
-fn update(mut assets: ResMut<Assets<SkyPlaneMaterial>>, camera: Query<&Transform, With<Camera3d>>) {
+fn update(mut assets: ResMut<Assets<SkyPlaneMaterial>>, camera: Query<&Transform, With<Camera3d>>, time: Res<Time>) {
     let camera_pos = camera.single().translation;
     assets.iter_mut().for_each(|(_, mat)| {
         mat.camera_pos = camera_pos;
+        mat.time = time.elapsed_seconds_wrapped();
+        // Wrap once the texture has scrolled a whole repeat, so the offset stays small
+        mat.noise_scroll = (mat.noise_scroll + mat.wind_speed * time.delta_seconds()).rem_euclid(SKY_WIDTH / mat.noise_size.max(1e-3));
     });
 }
+
+fn finish_textures(mut skies: Query<&mut SkyPlane>, mut materials: ResMut<Assets<SkyPlaneMaterial>>, mut images: ResMut<Assets<Image>>) {
+    for mut sky in skies.iter_mut() {
+        let Some((density, flow)) = sky.generating.as_ref().and_then(Background::poll) else {
+            continue;
+        };
+        // New handles rather than replacing the placeholders in place, so the material is bound again
+        if let Some(material) = materials.get_mut(&sky.material) {
+            material.noise_3d = images.add(density);
+            material.flow_3d = images.add(flow);
+        }
+        sky.generating = None;
+    }
+}
+
+// Repeating 3D texture of `size`^3 texels, linearly filtered
+fn volume_texture(label: &'static str, data: Vec<u8>, size: usize, format: TextureFormat) -> Image {
+    Image {
+        data,
+        texture_descriptor: TextureDescriptor {
+            label: Some(label),
+            size: Extent3d {
+                width: size as u32,
+                height: size as u32,
+                depth_or_array_layers: size as u32
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D3,
+            format,
+            usage: TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        sampler_descriptor: ImageSampler::Descriptor(SamplerDescriptor {
+            label: Some(label),
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            address_mode_w: AddressMode::Repeat,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Linear,
+            lod_min_clamp: 1.0,
+            lod_max_clamp: 1.0,
+            compare: None,
+            anisotropy_clamp: 1,
+            border_color: None
+        }),
+        texture_view_descriptor: Some(TextureViewDescriptor {
+            label: Some(label),
+            format: Some(format),
+            dimension: Some(TextureViewDimension::D3),
+            aspect: TextureAspect::All,
+            base_mip_level: 0,
+            mip_level_count: None,
+            base_array_layer: 0,
+            array_layer_count: None,
+        }),
+    }
+}
@@ -0,0 +1,415 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+
+use bevy::{prelude::*, render::{render_resource::PrimitiveTopology, mesh::{Indices, VertexAttributeValues}, primitives::Aabb}};
+use astral::biome::{BiomeWeights, ClimateMap};
+use astral::noise::{BoxedNoise2D, NoiseGrad2D};
+use astral::sculpt::{SculptLayer, Sculpted};
+
+use crate::{background::Background, terrain_plane::{TerrainPlaneMaterial, TerrainPlaneDescriptor, TerrainHeights, TerrainHit, set_biome_weights}};
+
+#[derive(Default)]
+pub struct TerrainChunksPlugin {}
+
+impl Plugin for TerrainChunksPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, stream_chunks);
+    }
+}
+
+// Distance between neighbouring vertices at the finest level of detail
+pub const UNIT: f32 = 1.;
+// Quads along each side of a chunk, at every level of detail
+pub const CHUNK_QUADS: u32 = 64;
+// Coarsest level. Chunks at level `l` have vertices 2^l units apart, so a root chunk covers
+// CHUNK_QUADS * 2^MAX_LEVEL units.
+pub const MAX_LEVEL: u32 = 5;
+// How far each chunk's skirt hangs below its edges, in vertex spacings at its level
+const SKIRT_DEPTH: f32 = 4.;
+
+/// A square tile of terrain, `CHUNK_QUADS` quads across
+#[derive(Component)]
+pub struct TerrainChunk;
+
+/// Node of the terrain quadtree: the chunk at `coord`, in chunk units of its `level`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkKey {
+    pub level: u32,
+    pub coord: IVec2
+}
+
+impl ChunkKey {
+    // Corner and side of the chunk, in lattice points from the world origin
+    fn lattice_rect(&self) -> (IVec2, i32) {
+        let side = (CHUNK_QUADS << self.level) as i32;
+        (self.coord * side, side)
+    }
+
+    fn overlaps(&self, other: &ChunkKey) -> bool {
+        let ((a, a_side), (b, b_side)) = (self.lattice_rect(), other.lattice_rect());
+        (a.cmplt(b + b_side) & b.cmplt(a + a_side)).all()
+    }
+
+    fn children(&self) -> [ChunkKey; 4] {
+        [IVec2::new(0, 0), IVec2::new(1, 0), IVec2::new(0, 1), IVec2::new(1, 1)]
+            .map(|offset| ChunkKey { level: self.level - 1, coord: self.coord * 2 + offset })
+    }
+
+    // Places the chunk's corner vertex
+    fn transform(&self) -> Transform {
+        let (corner, _) = self.lattice_rect();
+        let corner = corner.as_vec2() * UNIT;
+        Transform::from_xyz(corner.x, 0., corner.y)
+    }
+
+    // Distance from `eye` to the closest point of the chunk, taken at sea level
+    fn distance(&self, eye: Vec3) -> f32 {
+        let (corner, side) = self.lattice_rect();
+        let (min, max) = (corner.as_vec2() * UNIT, (corner + side).as_vec2() * UNIT);
+        let closest = Vec2::new(eye.x, eye.z).clamp(min, max);
+        eye.distance(Vec3::new(closest.x, 0., closest.y))
+    }
+}
+
+struct LoadedChunk {
+    entity: Entity,
+    // The chunk's own mesh, once its vertices have been generated. Until then it's drawn as a flat quad.
+    mesh: Option<Handle<Mesh>>,
+    generating: Option<Background<ChunkVertices>>,
+    // What the generated mesh was built from, for looking up heights
+    heights: Option<TerrainHeights>,
+    shown: bool
+}
+
+/// Terrain generated around the camera as it moves, as a quadtree of equally sized meshes. Chunks are
+/// split until their vertex spacing, projected onto the screen, is under `max_pixel_error`, so the
+/// triangle count follows what can be seen rather than how much of the world is covered. Every chunk
+/// samples the same heightmap, and the meshes of chunks that are no longer needed are reused. Vertices
+/// are generated on the async compute threads.
+#[derive(Resource)]
+pub struct TerrainChunks {
+    heightmap: Arc<BoxedNoise2D>,
+    // Hand edits on top of the heightmap, kept when it changes
+    sculpt: Arc<SculptLayer>,
+    // Decides the biomes each vertex is colored with
+    climate: Arc<ClimateMap>,
+    material: Handle<TerrainPlaneMaterial>,
+    // Flat quad from (0, 0) to (1, 1), scaled to stand in for chunks that are still being generated. It's
+    // colored as the biome at the origin.
+    placeholder: Handle<Mesh>,
+    /// Root chunks kept loaded in every direction from the one under the camera
+    pub view_radius: i32,
+    /// Largest distance between vertices, in pixels, before a chunk is split into four
+    pub max_pixel_error: f32,
+    /// Chunks being generated at once
+    pub max_generating: usize,
+    loaded: HashMap<ChunkKey, LoadedChunk>,
+    // Meshes of unloaded chunks, rewritten in place for the next ones
+    spare_meshes: Vec<Handle<Mesh>>
+}
+
+impl TerrainChunks {
+    pub fn new(meshes: &mut Assets<Mesh>, heightmap: BoxedNoise2D, climate: ClimateMap, material: Handle<TerrainPlaneMaterial>) -> TerrainChunks {
+        let placeholder = meshes.add(placeholder_mesh(&climate));
+        TerrainChunks {
+            heightmap: Arc::new(heightmap),
+            sculpt: Arc::new(SculptLayer::new(UNIT)),
+            climate: Arc::new(climate),
+            material,
+            placeholder,
+            view_radius: 1,
+            max_pixel_error: 4.,
+            max_generating: 16,
+            loaded: HashMap::new(),
+            spare_meshes: Vec::new()
+        }
+    }
+
+    // Switches to a new heightmap and climate, unloading every chunk so they are generated again from them
+    pub fn reset(&mut self, commands: &mut Commands, meshes: &mut Assets<Mesh>, heightmap: BoxedNoise2D, climate: ClimateMap, material: Handle<TerrainPlaneMaterial>) {
+        self.heightmap = Arc::new(heightmap);
+        *meshes.get_mut(&self.placeholder).unwrap() = placeholder_mesh(&climate);
+        self.climate = Arc::new(climate);
+        self.material = material;
+        for (_, chunk) in self.loaded.drain() {
+            commands.entity(chunk.entity).despawn();
+            self.spare_meshes.extend(chunk.mesh);
+        }
+    }
+
+    // The heightmap with the sculpted edits on top, as the chunks are generated from it
+    pub fn sculpted(&self) -> Sculpted<Arc<BoxedNoise2D>> {
+        Sculpted { source: self.heightmap.clone(), layer: self.sculpt.clone() }
+    }
+
+    // Edits the sculpt layer, given the heightmap under it, and generates the chunks over the area `edit`
+    // returns again. Chunks keep their current mesh until the new one is ready, and every other chunk is
+    // left as it is.
+    pub fn sculpt(&mut self, edit: impl FnOnce(&BoxedNoise2D, &mut SculptLayer) -> Rect) {
+        // Only copied if chunks are still being generated from the previous edit
+        let area = edit(&self.heightmap, Arc::make_mut(&mut self.sculpt));
+        let heightmap = self.sculpted();
+        for (&key, chunk) in self.loaded.iter_mut() {
+            let (corner, side) = key.lattice_rect();
+            let bounds = Rect::from_corners(corner.as_vec2() * UNIT, (corner + side).as_vec2() * UNIT);
+            if !bounds.intersect(area).is_empty() {
+                let (heightmap, climate) = (heightmap.clone(), self.climate.clone());
+                chunk.generating = Some(Background::spawn(move || chunk_vertices(&heightmap, &climate, key)));
+            }
+        }
+    }
+
+    pub fn material(&self) -> &Handle<TerrainPlaneMaterial> {
+        &self.material
+    }
+
+    pub fn climate(&self) -> &Arc<ClimateMap> {
+        &self.climate
+    }
+
+    // Height of the terrain drawn at (x, z), or `None` where the chunk there is still a placeholder
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        self.drawn_at(x, z)?.height_at(x, z)
+    }
+
+    pub fn normal_at(&self, x: f32, z: f32) -> Option<Vec3> {
+        self.drawn_at(x, z)?.normal_at(x, z)
+    }
+
+    // First hit of `ray` on the generated chunks being shown, within `max_distance`
+    pub fn cast_ray(&self, ray: Ray, max_distance: f32) -> Option<TerrainHit> {
+        self.loaded.values()
+            .filter(|chunk| chunk.shown)
+            .filter_map(|chunk| chunk.heights.as_ref()?.cast_ray(ray, max_distance))
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
+    // Heights of the generated chunk shown over (x, z). Shown chunks never overlap, so at most one level
+    // has one there.
+    fn drawn_at(&self, x: f32, z: f32) -> Option<&TerrainHeights> {
+        let lattice = (Vec2::new(x, z) / UNIT).floor().as_ivec2();
+        (0..=MAX_LEVEL).find_map(|level| {
+            let key = ChunkKey { level, coord: lattice.div_euclid(IVec2::splat((CHUNK_QUADS << level) as i32)) };
+            self.loaded.get(&key).filter(|chunk| chunk.shown)?.heights.as_ref()
+        })
+    }
+
+    // Leaves of the quadtree around `eye`. `pixels_per_unit` is the size on screen of one unit seen from
+    // a distance of one unit.
+    fn select(&self, eye: Vec3, pixels_per_unit: f32) -> Vec<ChunkKey> {
+        let root_side = (CHUNK_QUADS << MAX_LEVEL) as f32 * UNIT;
+        let center = (Vec2::new(eye.x, eye.z) / root_side).floor().as_ivec2();
+        let radius = self.view_radius;
+        let mut stack: Vec<ChunkKey> = (-radius..=radius)
+            .flat_map(|y| (-radius..=radius).map(move |x| ChunkKey { level: MAX_LEVEL, coord: center + IVec2::new(x, y) }))
+            .collect();
+        let mut leaves = Vec::new();
+        while let Some(key) = stack.pop() {
+            let spacing = (1 << key.level) as f32 * UNIT;
+            let pixel_error = spacing * pixels_per_unit / key.distance(eye).max(f32::EPSILON);
+            if key.level > 0 && pixel_error > self.max_pixel_error {
+                stack.extend(key.children());
+            } else {
+                leaves.push(key);
+            }
+        }
+        leaves
+    }
+}
+
+fn stream_chunks(
+    mut commands: Commands,
+    chunks: Option<ResMut<TerrainChunks>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    camera: Query<(&Transform, &Camera, &Projection), With<Camera3d>>
+) {
+    let (Some(mut chunks), Ok((transform, camera, projection))) = (chunks, camera.get_single()) else {
+        return;
+    };
+    let chunks = &mut *chunks;
+    let eye = transform.translation;
+    let fov = match projection {
+        Projection::Perspective(perspective) => perspective.fov,
+        Projection::Orthographic(_) => std::f32::consts::FRAC_PI_4
+    };
+    let viewport_height = camera.logical_viewport_size().map_or(720., |size| size.y);
+    let pixels_per_unit = viewport_height / (2. * (fov / 2.).tan());
+
+    let mut wanted = chunks.select(eye, pixels_per_unit);
+    let wanted_set: HashSet<ChunkKey> = wanted.iter().copied().collect();
+
+    // Move finished chunks into meshes of their own, or back into theirs once they've been sculpted
+    for (key, chunk) in chunks.loaded.iter_mut() {
+        let Some(vertices) = chunk.generating.as_ref().and_then(Background::poll) else {
+            continue;
+        };
+        let mesh = match chunk.mesh.take().or_else(|| chunks.spare_meshes.pop()) {
+            Some(handle) => {
+                write_chunk(meshes.get_mut(&handle).unwrap(), &vertices);
+                handle
+            },
+            None => {
+                let mut mesh = chunk_mesh();
+                write_chunk(&mut mesh, &vertices);
+                meshes.add(mesh)
+            }
+        };
+        // Dropping the bounds makes Bevy compute them again from the new mesh
+        commands.entity(chunk.entity).insert((mesh.clone(), key.transform())).remove::<Aabb>();
+        chunk.mesh = Some(mesh);
+        chunk.generating = None;
+        chunk.heights = Some(vertices.heights);
+    }
+
+    // Start generating missing chunks nearest first. They stay hidden until the chunks they replace can
+    // go, so no holes open up while a region is being refined.
+    let generating = chunks.loaded.values().filter(|chunk| chunk.generating.is_some()).count();
+    wanted.retain(|key| !chunks.loaded.contains_key(key));
+    wanted.sort_by(|a, b| a.distance(eye).total_cmp(&b.distance(eye)));
+    for key in wanted.into_iter().take(chunks.max_generating.saturating_sub(generating)) {
+        let (heightmap, climate) = (chunks.sculpted(), chunks.climate.clone());
+        let (_, side) = key.lattice_rect();
+        let side = side as f32 * UNIT;
+        let entity = commands.spawn((TerrainChunk, MaterialMeshBundle {
+            mesh: chunks.placeholder.clone(),
+            material: chunks.material.clone(),
+            transform: key.transform().with_scale(Vec3::new(side, 1., side)),
+            visibility: Visibility::Hidden,
+            ..default()
+        })).id();
+        chunks.loaded.insert(key, LoadedChunk {
+            entity,
+            mesh: None,
+            generating: Some(Background::spawn(move || chunk_vertices(&heightmap, &climate, key))),
+            heights: None,
+            shown: false
+        });
+    }
+
+    // Unload chunks that are no longer wanted once everything covering them has loaded
+    let stale: Vec<ChunkKey> = chunks.loaded.keys().copied()
+        .filter(|key| !wanted_set.contains(key))
+        .filter(|key| wanted_set.iter().filter(|wanted| wanted.overlaps(key)).all(|wanted| {
+            chunks.loaded.get(wanted).is_some_and(|chunk| chunk.mesh.is_some())
+        }))
+        .collect();
+    for key in stale {
+        let chunk = chunks.loaded.remove(&key).unwrap();
+        commands.entity(chunk.entity).despawn();
+        chunks.spare_meshes.extend(chunk.mesh);
+    }
+    let pending: Vec<ChunkKey> = chunks.loaded.keys().copied().filter(|key| !wanted_set.contains(key)).collect();
+    for (key, chunk) in chunks.loaded.iter_mut().filter(|(_, chunk)| !chunk.shown) {
+        if !pending.iter().any(|stale| stale.overlaps(key)) {
+            commands.entity(chunk.entity).insert(Visibility::Visible);
+            chunk.shown = true;
+        }
+    }
+}
+
+// Lattice position of the `i`th vertex around the edge of a chunk, going round from the (0, 0) corner
+fn perimeter(i: u32) -> UVec2 {
+    let (side, along) = (i / CHUNK_QUADS, i % CHUNK_QUADS);
+    match side {
+        0 => UVec2::new(along, 0),
+        1 => UVec2::new(CHUNK_QUADS, along),
+        2 => UVec2::new(CHUNK_QUADS - along, CHUNK_QUADS),
+        _ => UVec2::new(0, CHUNK_QUADS - along)
+    }
+}
+
+// Mesh with the triangles of a chunk and room for its vertices, which `write_chunk` fills in. The grid is
+// followed by a skirt: a copy of the edge vertices lowered below the terrain, hiding the cracks where a
+// chunk meets a neighbour at another level of detail. Skirt triangles face both ways, so they show from
+// either side.
+fn chunk_mesh() -> Mesh {
+    let side = CHUNK_QUADS + 1;
+    let idx = |x, y| y * side + x;
+    let mut indices = Vec::with_capacity((CHUNK_QUADS * CHUNK_QUADS * 6 + CHUNK_QUADS * 4 * 12) as usize);
+    for xi in 1..=CHUNK_QUADS {
+        for yi in 1..=CHUNK_QUADS {
+            indices.extend_from_slice(&[idx(xi-1, yi-1), idx(xi-1, yi), idx(xi, yi), idx(xi, yi), idx(xi, yi-1), idx(xi-1, yi-1)]);
+        }
+    }
+    let skirt = side * side;
+    let perimeter_len = CHUNK_QUADS * 4;
+    for i in 0..perimeter_len {
+        let j = (i + 1) % perimeter_len;
+        let (a, b) = (perimeter(i), perimeter(j));
+        let (a, b, a_low, b_low) = (idx(a.x, a.y), idx(b.x, b.y), skirt + i, skirt + j);
+        indices.extend_from_slice(&[a, b, b_low, b_low, a_low, a, a, a_low, b_low, b_low, b, a]);
+    }
+
+    let vertex_count = (side * side + perimeter_len) as usize;
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vec![[0.; 3]; vertex_count]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.; 3]; vertex_count]);
+    set_biome_weights(&mut mesh, &vec![BiomeWeights::default(); vertex_count]);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
+
+fn placeholder_mesh(climate: &ClimateMap) -> Mesh {
+    let unit_quad = TerrainPlaneDescriptor { origin: Vec2::ZERO, ..TerrainPlaneDescriptor::centered(UVec2::ONE, 1.) };
+    let mut mesh = unit_quad.placeholder_mesh();
+    set_biome_weights(&mut mesh, &[climate.biome_weights_at(0., 0., 0.); 4]);
+    mesh
+}
+
+// Vertices of a chunk, grid first and then skirt, and the grid's heights in world space
+struct ChunkVertices {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    biomes: Vec<BiomeWeights>,
+    heights: TerrainHeights
+}
+
+// Vertices of the chunk at `key`, relative to the chunk's corner. Heights are sampled at whole lattice
+// points counted from the world origin, so neighbouring chunks sample their shared edge at exactly the same
+// positions, and a coarser chunk's edge vertices land on its finer neighbour's. Normals come from the
+// heightmap's gradient, which needs no neighbouring vertices either.
+fn chunk_vertices(heightmap: &impl NoiseGrad2D, climate: &ClimateMap, key: ChunkKey) -> ChunkVertices {
+    let side = CHUNK_QUADS + 1;
+    let (corner, _) = key.lattice_rect();
+    let spacing = 1 << key.level;
+    let skirt_depth = SKIRT_DEPTH * spacing as f32 * UNIT;
+
+    let (mut positions, mut normals): (Vec<[f32; 3]>, Vec<[f32; 3]>) = (0..side * side).map(|i| {
+        let local = IVec2::new((i % side) as i32, (i / side) as i32) * spacing;
+        let world = (corner + local).as_vec2() * UNIT;
+        let (height, gradient) = heightmap.sample_grad_2d(world.x, world.y);
+        let local = local.as_vec2() * UNIT;
+        ([local.x, height, local.y], Vec3::new(-gradient.x, 1., -gradient.y).normalize().to_array())
+    }).unzip();
+    let heights = TerrainHeights {
+        origin: corner.as_vec2() * UNIT,
+        spacing: spacing as f32 * UNIT,
+        size: UVec2::splat(CHUNK_QUADS),
+        heights: positions.iter().map(|&[_, height, _]| height).collect()
+    };
+    let origin = heights.origin;
+    let mut biomes: Vec<BiomeWeights> = positions.iter()
+        .map(|&[x, height, z]| climate.biome_weights_at(origin.x + x, origin.y + z, height))
+        .collect();
+    for i in 0..CHUNK_QUADS * 4 {
+        let edge = perimeter(i);
+        let edge = (edge.y * side + edge.x) as usize;
+        let [x, y, z] = positions[edge];
+        positions.push([x, y - skirt_depth, z]);
+        normals.push(normals[edge]);
+        biomes.push(biomes[edge]);
+    }
+    ChunkVertices { positions, normals, biomes, heights }
+}
+
+// Copies `vertices` over the ones already in `mesh`, keeping its buffers
+fn write_chunk(mesh: &mut Mesh, vertices: &ChunkVertices) {
+    let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION) else {
+        panic!("Chunk mesh has no positions");
+    };
+    positions.copy_from_slice(&vertices.positions);
+    let Some(VertexAttributeValues::Float32x3(normals)) = mesh.attribute_mut(Mesh::ATTRIBUTE_NORMAL) else {
+        panic!("Chunk mesh has no normals");
+    };
+    normals.copy_from_slice(&vertices.normals);
+    set_biome_weights(mesh, &vertices.biomes);
+}
@@ -1,7 +1,14 @@
-use std::f32::consts::{PI, E};
+use std::sync::{Arc, OnceLock};
 
-use bevy::{prelude::*, render::{render_resource::{PrimitiveTopology, ShaderRef, AsBindGroup, TextureDescriptor, Extent3d, TextureDimension, TextureFormat, TextureUsages, SamplerDescriptor, AddressMode, FilterMode, TextureViewDescriptor, TextureViewDimension, TextureAspect}, mesh::Indices, texture::ImageSampler}, reflect::TypeUuid, math::Vec3Swizzles};
+use bevy::{prelude::*, render::{render_resource::{PrimitiveTopology, IndexFormat, ShaderRef, AsBindGroup, TextureDescriptor, Extent3d, TextureDimension, TextureFormat, TextureUsages, SamplerDescriptor, AddressMode, FilterMode, TextureViewDescriptor, TextureViewDimension, TextureAspect, VertexFormat}, mesh::{Indices, MeshVertexAttribute, VertexAttributeValues}, texture::ImageSampler}, reflect::TypeUuid, math::Vec3Swizzles};
 use bevy_inspector_egui::{quick::AssetInspectorPlugin, InspectorOptions, prelude::ReflectInspectorOptions};
+use astral::biome::{Biome, BiomeWeights, BIOME_COUNT};
+use astral::noise::NoiseSource2D;
+use rand::Rng;
+
+use crate::background::{Background, PendingMesh, par_chunks_mut};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::world::arg_value;
 
 #[derive(Default)]
 pub struct TerrainPlanePlugin {}
@@ -18,37 +25,343 @@ impl Plugin for TerrainPlanePlugin {
 #[derive(Component)]
 pub struct TerrainPlane {
     pub mesh: Handle<Mesh>,
-    pub material: Handle<TerrainPlaneMaterial>
+    // Filled in once the mesh has been generated
+    heights: Arc<OnceLock<TerrainHeights>>
+}
+
+// Rows of vertices generated by each parallel task
+const ROWS_PER_TASK: usize = 32;
+
+/// Grid a `TerrainPlane` is built on
+#[derive(Clone, Copy, Debug)]
+pub struct TerrainPlaneDescriptor {
+    /// Cells along x and z. There is one more vertex than cells along each side.
+    pub size: UVec2,
+    /// Distance between neighbouring vertices
+    pub spacing: f32,
+    /// Position of the (0, 0) vertex
+    pub origin: Vec2,
+    /// `Uint16` halves the index buffer, but only fits grids of up to 65536 vertices
+    pub index_format: IndexFormat,
+    /// Blur applied to the normals accumulated from the faces. Without it they are used as they are.
+    pub smoothing: Option<NormalSmoothing>
+}
+
+impl Default for TerrainPlaneDescriptor {
+    fn default() -> Self {
+        TerrainPlaneDescriptor::centered(UVec2::splat(1000), 1.)
+    }
+}
+
+impl TerrainPlaneDescriptor {
+    // Grid of `size` cells centered on the origin, with 32-bit indices
+    pub fn centered(size: UVec2, spacing: f32) -> TerrainPlaneDescriptor {
+        TerrainPlaneDescriptor {
+            size,
+            spacing,
+            origin: -size.as_vec2() * spacing / 2.,
+            index_format: IndexFormat::Uint32,
+            smoothing: Some(NormalSmoothing::default())
+        }
+    }
+
+    // Grid centered on the origin from `--<prefix>-size <cells>` and `--<prefix>-spacing <units>`,
+    // falling back to the default grid
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_args(prefix: &str) -> TerrainPlaneDescriptor {
+        let default_grid = TerrainPlaneDescriptor::default();
+        let size = match arg_value(&format!("{}-size", prefix)).map(|v| v.trim().parse::<u32>()) {
+            Some(Ok(size)) => UVec2::splat(size),
+            Some(Err(err)) => panic!("Invalid {} size: {}", prefix, err),
+            None => default_grid.size
+        };
+        let spacing = match arg_value(&format!("{}-spacing", prefix)).map(|v| v.trim().parse::<f32>()) {
+            Some(Ok(spacing)) => spacing,
+            Some(Err(err)) => panic!("Invalid {} spacing: {}", prefix, err),
+            None => default_grid.spacing
+        };
+        TerrainPlaneDescriptor::centered(size, spacing)
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        ((self.size.x + 1) * (self.size.y + 1)) as usize
+    }
+
+    // Flat quad over the grid's area, facing up
+    pub fn placeholder_mesh(&self) -> Mesh {
+        let (min, max) = (self.origin, self.origin + self.size.as_vec2() * self.spacing);
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vec![[min.x, 0., min.y], [min.x, 0., max.y], [max.x, 0., max.y], [max.x, 0., min.y]]);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0., 1., 0.]; 4]);
+        mesh.set_indices(Some(Indices::U16(vec![0, 1, 2, 2, 3, 0])));
+        mesh
+    }
+}
+
+/// Heights a terrain mesh was built from, one per vertex, looked up over the same triangles the mesh draws.
+/// Each quad is split along the diagonal from its (0, 0) to its (1, 1) corner, as in `TerrainPlane` and
+/// terrain chunk meshes, so heights between vertices match the rendered surface exactly.
+#[derive(Clone, Debug)]
+pub struct TerrainHeights {
+    /// Position of the (0, 0) vertex
+    pub origin: Vec2,
+    /// Distance between neighbouring vertices
+    pub spacing: f32,
+    /// Quads along x and z
+    pub size: UVec2,
+    /// One per vertex, row by row along x
+    pub heights: Vec<f32>
+}
+
+impl TerrainHeights {
+    // Height and slope of the triangle under (x, z), or `None` off the grid
+    fn sample(&self, x: f32, z: f32) -> Option<(f32, Vec2)> {
+        let p = (Vec2::new(x, z) - self.origin) / self.spacing;
+        if p.cmplt(Vec2::ZERO).any() || p.cmpgt(self.size.as_vec2()).any() {
+            return None;
+        }
+        // Points on the far edges belong to the last quad
+        let cell = p.floor().min(self.size.as_vec2() - 1.);
+        let t = p - cell;
+        let row = self.size.x as usize + 1;
+        let i = cell.y as usize * row + cell.x as usize;
+        let h = &self.heights;
+        let slope = if t.y > t.x {
+            Vec2::new(h[i + row + 1] - h[i + row], h[i + row] - h[i])
+        } else {
+            Vec2::new(h[i + 1] - h[i], h[i + row + 1] - h[i + 1])
+        };
+        Some((h[i] + slope.dot(t), slope / self.spacing))
+    }
+
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        Some(self.sample(x, z)?.0)
+    }
+
+    // Normal of the triangle under (x, z), rather than the smoothed normals the mesh is shaded with
+    pub fn normal_at(&self, x: f32, z: f32) -> Option<Vec3> {
+        let (_, slope) = self.sample(x, z)?;
+        Some(Vec3::new(-slope.x, 1., -slope.y).normalize())
+    }
+}
+
+/// Where a ray met the terrain
+#[derive(Clone, Copy, Debug)]
+pub struct TerrainHit {
+    pub position: Vec3,
+    /// Normal of the triangle that was hit, facing up
+    pub normal: Vec3,
+    /// From the ray's origin
+    pub distance: f32
+}
+
+impl TerrainHeights {
+    // First hit of `ray` within `max_distance`. The ray is walked across the grid one quad at a time with
+    // a 2D DDA (Amanatides and Woo), testing the two triangles of each quad it passes over, so the cost
+    // follows the distance covered rather than the size of the grid. Quads are visited in order along the
+    // ray, so the first hit is the closest.
+    pub fn cast_ray(&self, ray: Ray, max_distance: f32) -> Option<TerrainHit> {
+        let direction = ray.direction.normalize();
+        // In quads from the (0, 0) vertex, per unit travelled along the ray
+        let start = (ray.origin.xz() - self.origin) / self.spacing;
+        let step = direction.xz() / self.spacing;
+        let size = self.size.as_vec2();
+
+        // Clip the ray to the grid's footprint
+        let (mut enter, mut exit) = (0f32, max_distance);
+        for axis in 0..2 {
+            if step[axis] == 0. {
+                if start[axis] < 0. || start[axis] > size[axis] {
+                    return None;
+                }
+            } else {
+                let (a, b) = (-start[axis] / step[axis], (size[axis] - start[axis]) / step[axis]);
+                enter = enter.max(a.min(b));
+                exit = exit.min(a.max(b));
+            }
+        }
+        if enter > exit {
+            return None;
+        }
+
+        let last = self.size.as_ivec2() - 1;
+        let mut quad = (start + step * enter).floor().as_ivec2().clamp(IVec2::ZERO, last);
+        let towards = IVec2::new((step.x > 0.) as i32 - (step.x < 0.) as i32, (step.y > 0.) as i32 - (step.y < 0.) as i32);
+        // Distance along the ray to cross one quad along each axis, and to the next crossing
+        let across = Vec2::ONE / step.abs();
+        let crossing = |axis: usize| match towards[axis] {
+            0 => f32::INFINITY,
+            1 => (quad[axis] as f32 + 1. - start[axis]) / step[axis],
+            _ => (quad[axis] as f32 - start[axis]) / step[axis]
+        };
+        let mut next = Vec2::new(crossing(0), crossing(1));
+        loop {
+            if let Some(hit) = self.hit_quad(quad, ray.origin, direction).filter(|hit| hit.distance <= max_distance) {
+                return Some(hit);
+            }
+            let axis = if next.x < next.y { 0 } else { 1 };
+            if next[axis] > exit {
+                return None;
+            }
+            quad[axis] += towards[axis];
+            next[axis] += across[axis];
+            if quad.cmplt(IVec2::ZERO).any() || quad.cmpgt(last).any() {
+                return None;
+            }
+        }
+    }
+
+    // Closer hit on the two triangles of `quad`, if any
+    fn hit_quad(&self, quad: IVec2, origin: Vec3, direction: Vec3) -> Option<TerrainHit> {
+        let row = self.size.x as i32 + 1;
+        let vertex = |offset: IVec2| {
+            let lattice = quad + offset;
+            let xz = self.origin + lattice.as_vec2() * self.spacing;
+            Vec3::new(xz.x, self.heights[(lattice.y * row + lattice.x) as usize], xz.y)
+        };
+        let (v00, v10, v01, v11) = (vertex(IVec2::new(0, 0)), vertex(IVec2::new(1, 0)), vertex(IVec2::new(0, 1)), vertex(IVec2::new(1, 1)));
+        [[v00, v01, v11], [v00, v11, v10]].into_iter()
+            .filter_map(|[a, b, c]| {
+                let distance = hit_triangle(origin, direction, a, b, c)?;
+                let normal = (b - a).cross(c - a).normalize();
+                Some(TerrainHit { position: origin + direction * distance, normal: normal * normal.y.signum(), distance })
+            })
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+}
+
+// Distance along the ray to triangle abc, from either side (Möller and Trumbore)
+fn hit_triangle(origin: Vec3, direction: Vec3, a: Vec3, b: Vec3, c: Vec3) -> Option<f32> {
+    let (ab, ac) = (b - a, c - a);
+    let p = direction.cross(ac);
+    let determinant = ab.dot(p);
+    if determinant.abs() < f32::EPSILON {
+        return None;
+    }
+    let to_origin = origin - a;
+    let u = to_origin.dot(p) / determinant;
+    let q = to_origin.cross(ab);
+    let v = direction.dot(q) / determinant;
+    let distance = ac.dot(q) / determinant;
+    (u >= 0. && v >= 0. && u + v <= 1. && distance >= 0.).then_some(distance)
+}
+
+/// Gaussian blur over a grid of normals, `radius` vertices out in each direction
+#[derive(Clone, Copy, Debug)]
+pub struct NormalSmoothing {
+    pub radius: u32,
+    pub sigma: f32
+}
+
+impl Default for NormalSmoothing {
+    fn default() -> Self {
+        NormalSmoothing { radius: 4, sigma: 3. }
+    }
+}
+
+impl NormalSmoothing {
+    // Blurs `normals` (row by row along x) in two passes, along x and then along y, so each vertex reads
+    // 2 * (2 * radius + 1) neighbours instead of the whole square. Vertices past the edges are clamped.
+    pub fn apply(&self, normals: &[Vec3], width: usize, height: usize) -> Vec<Vec3> {
+        assert_eq!(normals.len(), width * height, "Normals don't match the grid");
+        let radius = self.radius as isize;
+        // Left unnormalized, since the sums are normalized anyway
+        let kernel: Vec<(isize, f32)> = (-radius..=radius)
+            .map(|d| (d, (-(d * d) as f32 / (2. * self.sigma * self.sigma)).exp()))
+            .collect();
+        let offset = |i: usize, d: isize, len: usize| (i as isize + d).clamp(0, len as isize - 1) as usize;
+
+        let mut rows = vec![Vec3::ZERO; normals.len()];
+        par_chunks_mut(&mut rows, width * ROWS_PER_TASK, |band, out| {
+            for (j, normal) in out.iter_mut().enumerate() {
+                let i = band * width * ROWS_PER_TASK + j;
+                let (x, y) = (i % width, i / width);
+                *normal = kernel.iter().fold(Vec3::ZERO, |acc, &(d, weight)| acc + weight * normals[y * width + offset(x, d, width)]);
+            }
+        });
+        let mut smoothed = vec![Vec3::ZERO; normals.len()];
+        par_chunks_mut(&mut smoothed, width * ROWS_PER_TASK, |band, out| {
+            for (j, normal) in out.iter_mut().enumerate() {
+                let i = band * width * ROWS_PER_TASK + j;
+                let (x, y) = (i % width, i / width);
+                *normal = kernel.iter().fold(Vec3::ZERO, |acc, &(d, weight)| acc + weight * rows[offset(y, d, height) * width + x]).normalize();
+            }
+        });
+        smoothed
+    }
 }
 
 impl TerrainPlane {
-    pub fn new(meshes: &mut Assets<Mesh>, materials: &mut Assets<TerrainPlaneMaterial>, images: &mut Assets<Image>, heightmap: impl Fn(f32, f32) -> f32) -> TerrainPlane {
+    // Starts generating the plane in the background. `mesh` is a flat quad over the same area until the
+    // returned `PendingMesh` finishes, and the plane has no heights to look up until then.
+    pub fn new(meshes: &mut Assets<Mesh>, descriptor: &TerrainPlaneDescriptor, heightmap: impl NoiseSource2D + Send + Sync + 'static) -> (TerrainPlane, PendingMesh) {
+        let descriptor = *descriptor;
+        let heights = Arc::new(OnceLock::new());
+        let slot = heights.clone();
+        let generating = Background::spawn(move || {
+            let heights = Self::heights(&descriptor, heightmap);
+            let mesh = Self::build(&descriptor, &heights);
+            let _ = slot.set(TerrainHeights { origin: descriptor.origin, spacing: descriptor.spacing, size: descriptor.size, heights });
+            mesh
+        });
+        (TerrainPlane { mesh: meshes.add(descriptor.placeholder_mesh()), heights }, PendingMesh(generating))
+    }
+
+    // Normals are accumulated from the triangle faces, then smoothed if the descriptor asks for it
+    pub fn mesh(descriptor: &TerrainPlaneDescriptor, heightmap: impl NoiseSource2D + Sync) -> Mesh {
+        Self::build(descriptor, &Self::heights(descriptor, heightmap))
+    }
+
+    // Height of the mesh at (x, z) in its own space, or `None` off the grid or before it's been generated
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        self.heights.get()?.height_at(x, z)
+    }
+
+    pub fn normal_at(&self, x: f32, z: f32) -> Option<Vec3> {
+        self.heights.get()?.normal_at(x, z)
+    }
+
+    // First hit of `ray`, in the plane's own space, within `max_distance`
+    pub fn cast_ray(&self, ray: Ray, max_distance: f32) -> Option<TerrainHit> {
+        self.heights.get()?.cast_ray(ray, max_distance)
+    }
+
+    // One height per vertex, row by row along x
+    fn heights(descriptor: &TerrainPlaneDescriptor, heightmap: impl NoiseSource2D + Sync) -> Vec<f32> {
+        let row_len = (descriptor.size.x + 1) as usize;
+        let mut heights = vec![0.; descriptor.vertex_count()];
+        par_chunks_mut(&mut heights, row_len * ROWS_PER_TASK, |band, rows| {
+            let origin = descriptor.origin + Vec2::new(0., (band * ROWS_PER_TASK) as f32 * descriptor.spacing);
+            heightmap.sample_grid_2d(origin, Vec2::splat(descriptor.spacing), row_len, rows);
+        });
+        heights
+    }
+
+    fn build(descriptor: &TerrainPlaneDescriptor, heights: &[f32]) -> Mesh {
+        assert!(descriptor.index_format == IndexFormat::Uint32 || descriptor.vertex_count() <= 1 << 16, "Too many vertices for 16-bit indices");
         let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
 
-        let (width, height) = (1000u32, 1000u32);
-        let unit = 1.;
+        let (width, height) = (descriptor.size.x, descriptor.size.y);
+        let (origin, spacing) = (descriptor.origin, descriptor.spacing);
 
-        let idx = |x, y| x * (height + 1) + y;
+        let idx = |x, y| y * (width + 1) + x;
 
-        let mut positions = vec![Vec3::ZERO; ((width+1) * (height+1)) as usize];
         let mut normals = vec![Vec3::ZERO; ((width+1) * (height+1)) as usize];
         let mut indices = Vec::with_capacity((width * height * 6) as usize);
 
         // Compute vertex positions & indices
-        for xi in 0..=width {
-            for yi in 0..=height {
-                let (x, y) = ((xi as f32 - (width as f32/2.)) * unit, (yi as f32 - (height as f32/2.)) * unit);
-                let attr_idx = idx(xi, yi) as usize;
-                positions[attr_idx] = Vec3::new(x, heightmap(x, y), y);
-                normals[attr_idx] = Vec3::Y; // TODO recompute
-                if xi > 0 && yi > 0 {
-                    // Draw quad between (xi-1, yi-1) and (xi, yi)
-                    let xi_1_yi_1 = idx(xi-1, yi-1);
-                    let xi_1_yi = idx(xi-1, yi);
-                    let xi_yi_1 = idx(xi, yi-1);
-                    let xi_yi = idx(xi, yi);
-                    indices.append(&mut vec![xi_1_yi_1, xi_1_yi, xi_yi, xi_yi, xi_yi_1, xi_1_yi_1]);
-                }
+        let positions: Vec<Vec3> = heights.iter().enumerate().map(|(i, &h)| {
+            let (xi, yi) = (i as u32 % (width + 1), i as u32 / (width + 1));
+            Vec3::new(origin.x + xi as f32 * spacing, h, origin.y + yi as f32 * spacing)
+        }).collect();
+        for xi in 1..=width {
+            for yi in 1..=height {
+                // Draw quad between (xi-1, yi-1) and (xi, yi)
+                let xi_1_yi_1 = idx(xi-1, yi-1);
+                let xi_1_yi = idx(xi-1, yi);
+                let xi_yi_1 = idx(xi, yi-1);
+                let xi_yi = idx(xi, yi);
+                indices.extend_from_slice(&[xi_1_yi_1, xi_1_yi, xi_yi, xi_yi, xi_yi_1, xi_1_yi_1]);
             }
         }
 
@@ -62,38 +375,12 @@ impl TerrainPlane {
             normals[ci] += normal;
         }
         // Renormalize
-        for i in 0..normals.len() {
-            normals[i] = normals[i].normalize();
-        }
-        // Gaussian smooth normals
-        // Make kernel
-        let gauss_radius = 4;
-        let sigma = 3.;
-        let gaussian = |x: f32, y: f32| {
-            let coeff = 1. / (2. * PI * sigma * sigma);
-            let exp = -(x * x + y * y) / (2. * sigma * sigma);
-            coeff * E.powf(exp)
-        };
-        let mut gaussian_kernel = Vec::new();
-        for dx in -gauss_radius..=gauss_radius {
-            for dy in -gauss_radius..=gauss_radius {
-                gaussian_kernel.push((dx, dy, gaussian(dx as f32, dy as f32)));
-            }
+        for normal in normals.iter_mut() {
+            *normal = normal.normalize();
         }
-        // Convolution
-        let mut new_normals = vec![Vec3::ZERO; ((width+1) * (height+1)) as usize];
-        for xi in 0..=width as i32 {
-            for yi in 0..=height as i32 {
-                new_normals[idx(xi as u32, yi as u32) as usize] = gaussian_kernel.iter()
-                    .fold(Vec3::ZERO, |normal_acc, &(x, y, weight)| {
-                        let x = (x + xi).clamp(0, width as i32);
-                        let y = (y + yi).clamp(0, height as i32);
-                        normal_acc + weight * normals[idx(x as u32, y as u32) as usize]
-                    }
-                ).normalize();
-            }
+        if let Some(smoothing) = descriptor.smoothing {
+            normals = smoothing.apply(&normals, (width + 1) as usize, (height + 1) as usize);
         }
-        normals = new_normals;
 
         assert!(positions.len() == ((width+1) * (height+1)) as usize);
         assert!(normals.len() == ((width+1) * (height+1)) as usize);
@@ -101,12 +388,19 @@ impl TerrainPlane {
 
         mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
         mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
-        mesh.set_indices(Some(Indices::U32(indices)));
+        mesh.set_indices(Some(match descriptor.index_format {
+            IndexFormat::Uint16 => Indices::U16(indices.into_iter().map(|i| i as u16).collect()),
+            IndexFormat::Uint32 => Indices::U32(indices)
+        }));
+        mesh
+    }
 
+    // Terrain coloring and lighting, with a random noise texture drawn from `rng`
+    pub fn material(materials: &mut Assets<TerrainPlaneMaterial>, images: &mut Assets<Image>, rng: &mut impl Rng) -> Handle<TerrainPlaneMaterial> {
         let perlin_size = 64;
         let mut perlin_data = vec![0; perlin_size * perlin_size * perlin_size * 4 * 4]; // 4 floats per Vec4, 4 bytes per float
         for _ in 0..perlin_size {
-            let (dx, dy, dz) = rand::random();
+            let (dx, dy, dz): (f32, f32, f32) = rng.gen();
             let vec = Vec3::new(dx, dy, dz).normalize().xyzz();
             perlin_data.append(&mut vec.to_array().iter().flat_map(|x| x.to_ne_bytes()).collect::<Vec<u8>>());
         }
@@ -153,27 +447,54 @@ impl TerrainPlane {
         };
         let img_handle = images.add(image);
 
-        let mat: Handle<TerrainPlaneMaterial> = materials.add(TerrainPlaneMaterial {
-            peak_color: Color::WHITE,
-            flat_color: Color::rgb(0.0, 1.0, 0.0),
-            steep_color: Color::rgb(0.06666667, 0.6666667, 0.18431373),
-            cliff_color: Color::rgb(0.0, 0.22745098, 0.015686275),
-            sea_color: Color::rgb(0.18, 0.55, 0.34),
-            peak_thresh: 24.0,
-            cliff_thresh: 0.92,
-            steep_thresh: 0.6,
-            sea_thresh: -14.5,
-            steep_interp: 3.1,
-            cliff_interp: 2.3,
-            light_direction: Vec3::new(-5.0, -3.0, -8.0).normalize(),
-            diffuse_color: Color::rgb(0.9098039, 0.77254903, 0.3137255),
-            diffuse_strength: 1.0,
-            ambient_color: Color::WHITE,
-            ambient_strength: 0.1,
-            noise_3d: img_handle
-        });
+        materials.add(TerrainPlaneMaterial::new(img_handle))
+    }
+}
 
-        TerrainPlane { mesh: meshes.add(mesh), material: mat }
+// Biome weights of each vertex, indexed like `Biome::ALL`, the first four biomes' in one attribute and the
+// rest in the other. Meshes drawn with `TerrainPlaneMaterial` need both.
+pub const ATTRIBUTE_BIOME_WEIGHTS: [MeshVertexAttribute; 2] = [
+    MeshVertexAttribute::new("Vertex_BiomeWeights0", 0x7e5a_b10e_0000, VertexFormat::Float32x4),
+    MeshVertexAttribute::new("Vertex_BiomeWeights1", 0x7e5a_b10e_0001, VertexFormat::Float32x4)
+];
+
+// Sets the biome weights of every vertex in `mesh`, reusing its buffers when it already has them
+pub fn set_biome_weights(mesh: &mut Mesh, weights: &[BiomeWeights]) {
+    for (half, attribute) in ATTRIBUTE_BIOME_WEIGHTS.into_iter().enumerate() {
+        let values = weights.iter().map(|weights| [0, 1, 2, 3].map(|i| weights[half * 4 + i]));
+        match mesh.attribute_mut(attribute.id) {
+            Some(VertexAttributeValues::Float32x4(existing)) if existing.len() == weights.len() => {
+                existing.iter_mut().zip(values).for_each(|(existing, value)| *existing = value);
+            },
+            _ => mesh.insert_attribute(attribute, values.collect::<Vec<[f32; 4]>>())
+        }
+    }
+}
+
+// Colors the terrain takes in one biome
+#[derive(Clone, Copy, Debug)]
+struct TerrainPalette {
+    peak_color: Color,
+    flat_color: Color,
+    steep_color: Color,
+    cliff_color: Color,
+    sea_color: Color
+}
+
+impl TerrainPalette {
+    fn of(biome: Biome) -> TerrainPalette {
+        let palette = |peak_color, flat_color, steep_color, cliff_color, sea_color| TerrainPalette { peak_color, flat_color, steep_color, cliff_color, sea_color };
+        match biome {
+            Biome::Tundra => palette(Color::WHITE, Color::rgb(0.62, 0.66, 0.55), Color::rgb(0.45, 0.48, 0.42), Color::rgb(0.33, 0.33, 0.35), Color::rgb(0.5, 0.55, 0.55)),
+            Biome::BorealForest => palette(Color::WHITE, Color::rgb(0.12, 0.45, 0.25), Color::rgb(0.08, 0.35, 0.2), Color::rgb(0.05, 0.18, 0.1), Color::rgb(0.2, 0.45, 0.35)),
+            Biome::Grassland => palette(Color::WHITE, Color::rgb(0.6, 0.75, 0.3), Color::rgb(0.5, 0.6, 0.25), Color::rgb(0.35, 0.3, 0.2), Color::rgb(0.45, 0.55, 0.35)),
+            // The coloring the whole world used to have
+            Biome::TemperateForest => palette(Color::WHITE, Color::rgb(0.0, 1.0, 0.0), Color::rgb(0.06666667, 0.6666667, 0.18431373), Color::rgb(0.0, 0.22745098, 0.015686275), Color::rgb(0.18, 0.55, 0.34)),
+            Biome::TemperateRainforest => palette(Color::WHITE, Color::rgb(0.0, 0.6, 0.2), Color::rgb(0.0, 0.45, 0.15), Color::rgb(0.0, 0.2, 0.05), Color::rgb(0.1, 0.45, 0.3)),
+            Biome::Desert => palette(Color::rgb(0.95, 0.88, 0.72), Color::rgb(0.93, 0.79, 0.5), Color::rgb(0.85, 0.62, 0.38), Color::rgb(0.6, 0.35, 0.2), Color::rgb(0.8, 0.72, 0.5)),
+            Biome::Savanna => palette(Color::WHITE, Color::rgb(0.8, 0.75, 0.35), Color::rgb(0.6, 0.6, 0.25), Color::rgb(0.45, 0.3, 0.15), Color::rgb(0.55, 0.6, 0.35)),
+            Biome::TropicalRainforest => palette(Color::WHITE, Color::rgb(0.05, 0.55, 0.05), Color::rgb(0.02, 0.4, 0.05), Color::rgb(0.1, 0.25, 0.08), Color::rgb(0.15, 0.5, 0.4))
+        }
     }
 }
 
@@ -181,16 +502,17 @@ impl TerrainPlane {
 #[reflect(InspectorOptions, Resource)]
 #[uuid="c2ad0a24-0ccd-498e-9162-8d5854e51d8a"]
 pub struct TerrainPlaneMaterial {
+    // Each biome's palette, indexed like `Biome::ALL` and blended by the vertices' biome weights
     #[uniform(0)]
-    peak_color: Color,
+    peak_colors: [Color; BIOME_COUNT],
     #[uniform(0)]
-    flat_color: Color,
+    flat_colors: [Color; BIOME_COUNT],
     #[uniform(0)]
-    steep_color: Color,
+    steep_colors: [Color; BIOME_COUNT],
     #[uniform(0)]
-    cliff_color: Color,
+    cliff_colors: [Color; BIOME_COUNT],
     #[uniform(0)]
-    sea_color: Color,
+    sea_colors: [Color; BIOME_COUNT],
     #[uniform(0)]
     #[inspector(min = -64.0, max = 64.0)]
     peak_thresh: f32,
@@ -228,6 +550,55 @@ pub struct TerrainPlaneMaterial {
     noise_3d: Handle<Image>
 }
 
+impl TerrainPlaneMaterial {
+    // Default coloring and lighting, sampling `noise_3d`
+    pub fn new(noise_3d: Handle<Image>) -> TerrainPlaneMaterial {
+        let palettes = Biome::ALL.map(TerrainPalette::of);
+        TerrainPlaneMaterial {
+            peak_colors: palettes.map(|palette| palette.peak_color),
+            flat_colors: palettes.map(|palette| palette.flat_color),
+            steep_colors: palettes.map(|palette| palette.steep_color),
+            cliff_colors: palettes.map(|palette| palette.cliff_color),
+            sea_colors: palettes.map(|palette| palette.sea_color),
+            peak_thresh: 24.0,
+            cliff_thresh: 0.92,
+            steep_thresh: 0.6,
+            sea_thresh: -14.5,
+            steep_interp: 3.1,
+            cliff_interp: 2.3,
+            light_direction: Vec3::new(-5.0, -3.0, -8.0).normalize(),
+            diffuse_color: Color::rgb(0.9098039, 0.77254903, 0.3137255),
+            diffuse_strength: 1.0,
+            ambient_color: Color::WHITE,
+            ambient_strength: 0.1,
+            noise_3d
+        }
+    }
+
+    // Unlit color of the terrain at `height` facing `normal` in a blend of `biomes`, by the same rules as
+    // terrain_plane.frag. Mixed in linear space like on the GPU.
+    pub fn color_at(&self, height: f32, normal: Vec3, biomes: &BiomeWeights) -> Color {
+        let blend = |colors: &[Color; BIOME_COUNT]| {
+            colors.iter().zip(biomes).map(|(color, &weight)| Vec4::from(color.as_linear_rgba_f32()) * weight).sum::<Vec4>()
+        };
+        let color = if height < self.sea_thresh {
+            blend(&self.sea_colors)
+        } else if height >= self.peak_thresh {
+            blend(&self.peak_colors)
+        } else if normal.y < self.steep_thresh {
+            blend(&self.flat_colors)
+        } else if normal.y < self.cliff_thresh {
+            let steepness = (normal.y - self.steep_thresh) / (self.cliff_thresh - self.steep_thresh);
+            blend(&self.steep_colors).lerp(blend(&self.cliff_colors), 1. - steepness.powf(self.steep_interp))
+        } else {
+            let steepness = (normal.y - self.cliff_thresh) / (1. - self.cliff_thresh);
+            blend(&self.flat_colors).lerp(blend(&self.steep_colors), 1. - steepness.powf(self.cliff_interp))
+        };
+        let [r, g, b, a] = color.to_array();
+        Color::rgba_linear(r, g, b, a)
+    }
+}
+
 impl Material for TerrainPlaneMaterial {
     fn vertex_shader() -> bevy::render::render_resource::ShaderRef {
         ShaderRef::Path("shaders/terrain_plane.vert".into())
@@ -244,11 +615,225 @@ impl Material for TerrainPlaneMaterial {
     fn specialize(
         _pipeline: &bevy::pbr::MaterialPipeline<Self>,
         descriptor: &mut bevy::render::render_resource::RenderPipelineDescriptor,
-        _layout: &bevy::render::mesh::MeshVertexBufferLayout,
+        layout: &bevy::render::mesh::MeshVertexBufferLayout,
         _key: bevy::pbr::MaterialPipelineKey<Self>,
     ) -> Result<(), bevy::render::render_resource::SpecializedMeshPipelineError> {
+        descriptor.vertex.buffers = vec![layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            ATTRIBUTE_BIOME_WEIGHTS[0].at_shader_location(2),
+            ATTRIBUTE_BIOME_WEIGHTS[1].at_shader_location(3)
+        ])?];
         *descriptor.vertex.entry_point.to_mut() = "main".to_string();
         *descriptor.fragment.as_mut().unwrap().entry_point.to_mut() = "main".to_string();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    // Face normals summed at each vertex and normalized, as the mesh did before smoothing was optional
+    fn face_normals(size: UVec2, positions: &[Vec3]) -> Vec<Vec3> {
+        let idx = |x: u32, y: u32| (y * (size.x + 1) + x) as usize;
+        let mut normals = vec![Vec3::ZERO; positions.len()];
+        for xi in 1..=size.x {
+            for yi in 1..=size.y {
+                let quad = [idx(xi-1, yi-1), idx(xi-1, yi), idx(xi, yi), idx(xi, yi), idx(xi, yi-1), idx(xi-1, yi-1)];
+                for [ai, bi, ci] in [[quad[0], quad[1], quad[2]], [quad[3], quad[4], quad[5]]] {
+                    let (a, b, c) = (positions[ai], positions[bi], positions[ci]);
+                    let normal = (c - a).cross(a - b).normalize();
+                    normals[ai] += normal;
+                    normals[bi] += normal;
+                    normals[ci] += normal;
+                }
+            }
+        }
+        normals.iter().map(|normal| normal.normalize()).collect()
+    }
+
+    #[test]
+    fn unsmoothed_normals_are_the_face_normals() {
+        let descriptor = TerrainPlaneDescriptor { smoothing: None, ..TerrainPlaneDescriptor::centered(UVec2::new(7, 5), 1.5) };
+        let heights: Vec<f32> = (0..descriptor.vertex_count()).map(|i| (i as f32 * 0.7).sin() * 3. + (i % 3) as f32).collect();
+        let mesh = TerrainPlane::build(&descriptor, &heights);
+        let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {
+            panic!("No positions");
+        };
+        let Some(VertexAttributeValues::Float32x3(normals)) = mesh.attribute(Mesh::ATTRIBUTE_NORMAL) else {
+            panic!("No normals");
+        };
+        let positions: Vec<Vec3> = positions.iter().map(|&p| Vec3::from(p)).collect();
+        let expected = face_normals(descriptor.size, &positions);
+        for (i, (normal, expected)) in normals.iter().zip(expected).enumerate() {
+            assert_eq!(normal.map(f32::to_bits), expected.to_array().map(f32::to_bits), "Normal {}", i);
+        }
+    }
+
+    // Small terrain with a different slope on every triangle, and the triangles its mesh draws
+    fn bumpy_terrain() -> (TerrainHeights, Vec<[Vec3; 3]>) {
+        let descriptor = TerrainPlaneDescriptor { origin: Vec2::new(-7., 3.), smoothing: None, ..TerrainPlaneDescriptor::centered(UVec2::new(12, 9), 1.5) };
+        let heights: Vec<f32> = (0..descriptor.vertex_count()).map(|i| (i as f32 * 0.7).sin() * 3. + (i % 5) as f32 * 0.4).collect();
+        let mesh = TerrainPlane::build(&descriptor, &heights);
+        let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {
+            panic!("No positions");
+        };
+        let Some(Indices::U32(indices)) = mesh.indices() else {
+            panic!("No indices");
+        };
+        let triangles = indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]].map(|i| Vec3::from(positions[i as usize]))).collect();
+        (TerrainHeights { origin: descriptor.origin, spacing: descriptor.spacing, size: descriptor.size, heights }, triangles)
+    }
+
+    #[test]
+    fn heights_follow_the_mesh_triangles() {
+        let (terrain, triangles) = bumpy_terrain();
+        for [a, b, c] in triangles {
+            let normal = (b - a).cross(c - a).normalize();
+            for weights in [Vec3::splat(1. / 3.), Vec3::new(0.6, 0.3, 0.1), Vec3::new(0.1, 0.15, 0.75)] {
+                // On the plane through the triangle's corners
+                let p = a * weights.x + b * weights.y + c * weights.z;
+                let height = terrain.height_at(p.x, p.z).unwrap();
+                assert!((height - p.y).abs() < 1e-4, "{} at {:?}, expected {}", height, p, p.y);
+                let normal_at = terrain.normal_at(p.x, p.z).unwrap();
+                assert!(normal_at.distance(normal * normal.y.signum()) < 1e-4, "{} at {:?}, expected {}", normal_at, p, normal);
+            }
+        }
+    }
+
+    #[test]
+    fn heights_stop_at_the_grid_edges() {
+        let (terrain, _) = bumpy_terrain();
+        let far = terrain.origin + terrain.size.as_vec2() * terrain.spacing;
+        let row = terrain.size.x as usize + 1;
+        // The far edges belong to the last quads, so their vertices and the lines between them are on the grid
+        let last = *terrain.heights.last().unwrap();
+        assert!((terrain.height_at(far.x, far.y).unwrap() - last).abs() < 1e-4);
+        let (corner_x, corner_z) = (terrain.heights[row - 1], terrain.heights[terrain.heights.len() - row]);
+        assert!((terrain.height_at(far.x, terrain.origin.y).unwrap() - corner_x).abs() < 1e-4);
+        assert!((terrain.height_at(terrain.origin.x, far.y).unwrap() - corner_z).abs() < 1e-4);
+        let midpoint = (terrain.heights[2 * row - 1] + terrain.heights[3 * row - 1]) / 2.;
+        assert!((terrain.height_at(far.x, terrain.origin.y + 1.5 * terrain.spacing).unwrap() - midpoint).abs() < 1e-4);
+
+        let off = 1e-3;
+        for (x, z) in [(far.x + off, far.y - 1.), (far.x - 1., far.y + off), (terrain.origin.x - off, 4.), (-2., terrain.origin.y - off), (100., -100.)] {
+            assert_eq!(terrain.height_at(x, z), None, "({}, {})", x, z);
+            assert_eq!(terrain.normal_at(x, z), None, "({}, {})", x, z);
+        }
+    }
+
+    // Closest hit of every triangle in the mesh
+    fn brute_force(triangles: &[[Vec3; 3]], ray: Ray, max_distance: f32) -> Option<f32> {
+        let direction = ray.direction.normalize();
+        triangles.iter()
+            .filter_map(|&[a, b, c]| hit_triangle(ray.origin, direction, a, b, c))
+            .filter(|&distance| distance <= max_distance)
+            .min_by(f32::total_cmp)
+    }
+
+    fn assert_same_hit(terrain: &TerrainHeights, triangles: &[[Vec3; 3]], ray: Ray, max_distance: f32) {
+        let hit = terrain.cast_ray(ray, max_distance);
+        let expected = brute_force(triangles, ray, max_distance);
+        match (hit, expected) {
+            (Some(hit), Some(expected)) => {
+                assert!((hit.distance - expected).abs() < 1e-3, "{:?} hit at {}, expected {}", ray, hit.distance, expected);
+                assert!(hit.position.distance(ray.origin + ray.direction.normalize() * hit.distance) < 1e-3);
+            },
+            (None, None) => {},
+            _ => panic!("{:?} hit {:?}, expected {:?}", ray, hit, expected)
+        }
+    }
+
+    #[test]
+    fn vertical_rays_hit_the_height_below() {
+        let (terrain, _) = bumpy_terrain();
+        for (x, z) in [(-6.5, 3.2), (0.1, 7.7), (10.2, 16.4), (3.3, 10.)] {
+            let ray = Ray { origin: Vec3::new(x, 20., z), direction: Vec3::NEG_Y };
+            let hit = terrain.cast_ray(ray, 100.).unwrap();
+            let height = terrain.height_at(x, z).unwrap();
+            assert!((hit.position - Vec3::new(x, height, z)).abs().max_element() < 1e-4, "{:?} for height {}", hit, height);
+            assert!((hit.distance - (20. - height)).abs() < 1e-4);
+            assert!(hit.normal.distance(terrain.normal_at(x, z).unwrap()) < 1e-4);
+            // Pointing up from below, the same triangle is hit from underneath
+            let below = terrain.cast_ray(Ray { origin: Vec3::new(x, -20., z), direction: Vec3::Y }, 100.).unwrap();
+            assert!((below.position.y - height).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn rays_hit_the_closest_triangle() {
+        let (terrain, triangles) = bumpy_terrain();
+        let mut rng = ChaCha8Rng::seed_from_u64(9);
+        let footprint = Rect::from_corners(terrain.origin, terrain.origin + terrain.size.as_vec2() * terrain.spacing);
+        let mut hits = 0;
+        for _ in 0..500 {
+            // Grazing rays crossing many quads, starting inside the footprint or up to a few quads outside it
+            let origin = Vec2::new(
+                rng.gen_range(footprint.min.x - 5. ..footprint.max.x + 5.),
+                rng.gen_range(footprint.min.y - 5. ..footprint.max.y + 5.)
+            );
+            let angle = rng.gen_range(0. ..std::f32::consts::TAU);
+            let direction = Vec3::new(angle.cos(), rng.gen_range(-0.3..0.05), angle.sin());
+            let ray = Ray { origin: Vec3::new(origin.x, rng.gen_range(0. ..6.), origin.y), direction };
+            assert_same_hit(&terrain, &triangles, ray, 100.);
+            hits += terrain.cast_ray(ray, 100.).is_some() as u32;
+        }
+        // Enough of them hit to compare where
+        assert!(hits > 100, "{} hits", hits);
+    }
+
+    #[test]
+    fn rays_along_an_axis_and_away_from_the_grid() {
+        let (terrain, triangles) = bumpy_terrain();
+        let far = terrain.origin + terrain.size.as_vec2() * terrain.spacing;
+        let rays = [
+            // Along each axis, across the whole grid from outside it
+            (Vec3::new(terrain.origin.x - 3., 4., 7.3), Vec3::new(1., -0.2, 0.)),
+            (Vec3::new(far.x + 3., 4., 9.1), Vec3::new(-1., -0.2, 0.)),
+            (Vec3::new(-1.2, 4., terrain.origin.y - 3.), Vec3::new(0., -0.2, 1.)),
+            (Vec3::new(2.9, 4., far.y + 3.), Vec3::new(0., -0.2, -1.)),
+            // Along a grid line
+            (Vec3::new(terrain.origin.x + 3. * terrain.spacing, 4., terrain.origin.y - 1.), Vec3::new(0., -0.3, 1.))
+        ];
+        for (origin, direction) in rays {
+            let ray = Ray { origin, direction };
+            assert!(terrain.cast_ray(ray, 100.).is_some(), "{:?}", ray);
+            assert_same_hit(&terrain, &triangles, ray, 100.);
+        }
+
+        let misses = [
+            // Along an axis, beside the grid
+            (Vec3::new(terrain.origin.x - 3., -1., far.y + 0.5), Vec3::new(1., 0., 0.)),
+            (Vec3::new(terrain.origin.x - 0.5, -1., terrain.origin.y - 3.), Vec3::new(0., 0., 1.)),
+            // Pointing away from it
+            (Vec3::new(terrain.origin.x - 3., -1., 7.), Vec3::new(-1., -0.1, 0.3)),
+            (Vec3::new(far.x + 1., -1., far.y + 1.), Vec3::new(1., -0.5, 1.)),
+            // Straight down outside it
+            (Vec3::new(far.x + 1., 10., 5.), Vec3::NEG_Y),
+            // Straight up above it
+            (Vec3::new(0., 10., 5.), Vec3::Y)
+        ];
+        for (origin, direction) in misses {
+            let ray = Ray { origin, direction };
+            assert!(terrain.cast_ray(ray, 100.).is_none(), "{:?}", ray);
+            assert_same_hit(&terrain, &triangles, ray, 100.);
+        }
+    }
+
+    #[test]
+    fn rays_stop_at_the_max_distance() {
+        let (terrain, triangles) = bumpy_terrain();
+        let ray = Ray { origin: Vec3::new(-9., 5., 4.), direction: Vec3::new(1., -0.25, 0.6) };
+        let distance = terrain.cast_ray(ray, 100.).unwrap().distance;
+        assert!((terrain.cast_ray(ray, distance + 1e-3).unwrap().distance - distance).abs() < 1e-4);
+        assert!(terrain.cast_ray(ray, distance - 1e-3).is_none());
+        // Before reaching the grid at all
+        assert!(terrain.cast_ray(ray, 1.).is_none());
+        for max_distance in [0.5, 2., 5., 10., 20.] {
+            assert_same_hit(&terrain, &triangles, ray, max_distance);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use bevy::{prelude::*, ecs::system::SystemParam};
+
+use astral::biome::Climate;
+
+use crate::{sculpting::Sculpting, terrain_chunks::TerrainChunks, terrain_plane::{TerrainPlane, TerrainHit}};
+
+// Farthest the cursor can pick terrain from the camera
+const PICK_DISTANCE: f32 = 10_000.;
+
+#[derive(Default)]
+pub struct TerrainPickingPlugin {}
+
+impl Plugin for TerrainPickingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<TerrainPicked>();
+        app.add_systems(Update, pick_terrain);
+    }
+}
+
+/// Sent when the terrain is clicked while the cursor is released and sculpting is off
+#[derive(Event, Clone, Copy, Debug)]
+pub struct TerrainPicked(pub TerrainHit);
+
+/// Heights and normals of the terrain as it's drawn, for gameplay systems. Covers the streamed terrain
+/// chunks and every `TerrainPlane` entity, and where several overlap the highest one wins, so over water
+/// this is the water's surface. Planes are expected to be moved and scaled, but not rotated.
+#[derive(SystemParam)]
+pub struct TerrainQuery<'w, 's> {
+    chunks: Option<Res<'w, TerrainChunks>>,
+    planes: Query<'w, 's, (&'static TerrainPlane, &'static GlobalTransform)>
+}
+
+impl TerrainQuery<'_, '_> {
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        Some(self.surface_at(x, z)?.0)
+    }
+
+    pub fn normal_at(&self, x: f32, z: f32) -> Option<Vec3> {
+        Some(self.surface_at(x, z)?.1)
+    }
+
+    /// Height of the streamed terrain alone, which is below the water's surface where it's flooded
+    pub fn ground_height_at(&self, x: f32, z: f32) -> Option<f32> {
+        self.chunks.as_ref()?.height_at(x, z)
+    }
+
+    /// Climate of the ground at (x, z), which is colder the higher it is
+    pub fn climate_at(&self, x: f32, z: f32) -> Option<Climate> {
+        let chunks = self.chunks.as_ref()?;
+        Some(chunks.climate().climate_at(x, z, chunks.height_at(x, z)?))
+    }
+
+    /// First hit of `ray` on any terrain within `max_distance`. Line of sight between two points is
+    /// clear when a ray from one towards the other hits nothing before reaching it.
+    pub fn cast_ray(&self, ray: Ray, max_distance: f32) -> Option<TerrainHit> {
+        let ground = self.chunks.as_ref().and_then(|chunks| chunks.cast_ray(ray, max_distance));
+        let planes = self.planes.iter().filter_map(|(plane, transform)| {
+            // Distances shrink or grow with the plane's scale, so the local ray is unit length again
+            let to_local = transform.affine().inverse();
+            let direction = to_local.transform_vector3(ray.direction.normalize());
+            let local = Ray { origin: to_local.transform_point3(ray.origin), direction: direction.normalize() };
+            let hit = plane.cast_ray(local, max_distance * direction.length())?;
+            let position = transform.transform_point(hit.position);
+            let normal = (hit.normal / transform.compute_transform().scale).normalize();
+            Some(TerrainHit { position, normal, distance: position.distance(ray.origin) })
+        });
+        ground.into_iter().chain(planes).min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
+    // Height and normal of the highest surface over (x, z)
+    fn surface_at(&self, x: f32, z: f32) -> Option<(f32, Vec3)> {
+        let ground = self.chunks.as_ref().and_then(|chunks| Some((chunks.height_at(x, z)?, chunks.normal_at(x, z)?)));
+        let planes = self.planes.iter().filter_map(|(plane, transform)| {
+            let local = transform.affine().inverse().transform_point3(Vec3::new(x, 0., z));
+            let (height, normal) = (plane.height_at(local.x, local.z)?, plane.normal_at(local.x, local.z)?);
+            let scale = transform.compute_transform().scale;
+            Some((transform.transform_point(Vec3::new(local.x, height, local.z)).y, (normal / scale).normalize()))
+        });
+        ground.into_iter().chain(planes).max_by(|a, b| a.0.total_cmp(&b.0))
+    }
+}
+
+fn pick_terrain(
+    mouse: Res<Input<MouseButton>>,
+    window: Query<&Window>,
+    camera: Query<(&Camera, &GlobalTransform), With<Camera3d>>,
+    terrain: TerrainQuery,
+    sculpting: Option<Res<Sculpting>>,
+    mut picked: EventWriter<TerrainPicked>
+) {
+    let (Ok(window), Ok((camera, transform))) = (window.get_single(), camera.get_single()) else {
+        return;
+    };
+    // While the cursor is captured, clicks belong to the camera, and while sculpting they belong to the brush
+    if !mouse.just_pressed(MouseButton::Left) || !window.cursor.visible || sculpting.is_some_and(|sculpting| sculpting.enabled()) {
+        return;
+    }
+    let Some(ray) = window.cursor_position().and_then(|cursor| camera.viewport_to_world(transform, cursor)) else {
+        return;
+    };
+    if let Some(hit) = terrain.cast_ray(ray, PICK_DISTANCE) {
+        picked.send(TerrainPicked(hit));
+    }
+}
@@ -0,0 +1,122 @@
+// Terrain recipes: noise graphs that can also read elevation data from disk and erode what they build
+
+use std::path::Path;
+
+use bevy::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Serialize, Deserialize};
+
+use crate::erosion::{ErosionRegion, ErosionStep};
+use crate::heightmap::{Heightmap, HeightmapFormat, HeightmapFilter};
+use crate::noise::{Add, BoxedNoise2D, NodeExtension, NoiseKind, NoiseNode};
+
+// Noise graph with terrain nodes, which can be used anywhere in it
+pub type TerrainRecipe = NoiseNode<TerrainNode>;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum TerrainNode {
+    // Elevation data read from `path` (relative to the working directory) when the graph is built. Without
+    // a format it's guessed from the extension; RAW files need theirs spelled out.
+    Heightmap {
+        path: String,
+        #[serde(default)]
+        format: Option<HeightmapFormat>,
+        #[serde(default = "default_one")]
+        spacing: f32,
+        #[serde(default)]
+        center: [f32; 2],
+        #[serde(default = "default_one")]
+        vertical_scale: f32,
+        #[serde(default)]
+        sea_level: f32,
+        #[serde(default)]
+        filter: HeightmapFilter
+    },
+    // `source` with `passes` run over it in a square of `size` cells around the origin (see `ErosionRegion`)
+    Erode {
+        source: Box<TerrainRecipe>,
+        passes: Vec<ErosionStep>,
+        #[serde(default = "default_erosion_size")]
+        size: u32,
+        #[serde(default = "default_one")]
+        spacing: f32,
+        #[serde(default = "default_erosion_margin")]
+        margin: u32
+    }
+}
+
+fn default_one() -> f32 {
+    1.
+}
+
+fn default_erosion_size() -> u32 {
+    512
+}
+
+fn default_erosion_margin() -> u32 {
+    32
+}
+
+impl NodeExtension for TerrainNode {
+    const VARIANTS: &'static [&'static str] = &["Heightmap", "Erode"];
+
+    fn build_2d<R: Rng>(&self, default_kind: NoiseKind, rng: &mut R) -> Result<BoxedNoise2D, String> {
+        Ok(match self {
+            TerrainNode::Heightmap { path, format, spacing, center, vertical_scale, sea_level, filter } => {
+                let path = Path::new(path);
+                let heightmap = match format {
+                    Some(format) => Heightmap::load_as(path, *format)?,
+                    None => Heightmap::load(path)?
+                };
+                Box::new(heightmap
+                    .spacing(*spacing)
+                    .center(Vec2::from(*center))
+                    .vertical_scale(*vertical_scale)
+                    .sea_level(*sea_level)
+                    .filter(*filter))
+            },
+            TerrainNode::Erode { source, passes, size, spacing, margin } => {
+                let source = source.build_2d(default_kind, rng)?;
+                // The simulation gets its own stream, so the number of droplets doesn't shift later seeds
+                let mut erosion_rng = ChaCha8Rng::seed_from_u64(rng.gen());
+                let region = ErosionRegion { size: *size, spacing: *spacing, margin: *margin };
+                let delta = region.erode(&source, passes, &mut erosion_rng);
+                Box::new(Add(source, delta))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terrain_nodes_nest_with_noise_nodes() {
+        let text = "Add(
+            Erode(source: ScaleBias(source: Gradient(), scale: 8.0), passes: [Thermal((talus_angle: 40.0))], size: 64),
+            Heightmap(path: \"dem.hgt\", vertical_scale: 0.5),
+        )";
+        let recipe: TerrainRecipe = ron::from_str(text).unwrap();
+        let NoiseNode::Add(erode, heightmap) = &recipe else {
+            panic!("Expected Add, got {:?}", recipe);
+        };
+        let NoiseNode::Extension(TerrainNode::Erode { source, size: 64, .. }) = erode.as_ref() else {
+            panic!("Expected Erode, got {:?}", erode);
+        };
+        assert!(matches!(source.as_ref(), NoiseNode::ScaleBias { source, .. } if matches!(source.as_ref(), NoiseNode::Gradient { .. })));
+        assert!(matches!(heightmap.as_ref(), NoiseNode::Extension(TerrainNode::Heightmap { vertical_scale, .. }) if *vertical_scale == 0.5));
+
+        // And they're written back the same way
+        let written = ron::to_string(&recipe).unwrap();
+        assert_eq!(ron::to_string(&ron::from_str::<TerrainRecipe>(&written).unwrap()).unwrap(), written);
+        assert!(written.contains("Erode(") && written.contains("Heightmap("), "{}", written);
+    }
+
+    #[test]
+    fn noise_graphs_reject_terrain_nodes() {
+        assert!(ron::from_str::<NoiseNode>("Fractal(source: Gradient())").is_ok());
+        assert!(ron::from_str::<NoiseNode>("Fractal(source: Heightmap(path: \"dem.hgt\"))").is_err());
+    }
+}